@@ -0,0 +1,22 @@
+-- Users keyed by uuid; the full record lives in `data`, with the
+-- README's uuid | pubKey | hash columns derived from it for querying.
+CREATE TABLE IF NOT EXISTS users (
+    uuid TEXT PRIMARY KEY,
+    data JSONB NOT NULL,
+    pub_key TEXT GENERATED ALWAYS AS (data->>'pub_key') STORED,
+    hash TEXT GENERATED ALWAYS AS (data->>'hash') STORED
+);
+
+-- {pub_key + hash: user_uuid} index used to find existing users on create
+CREATE TABLE IF NOT EXISTS pub_keys (
+    key TEXT PRIMARY KEY,
+    user_uuid TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS pub_keys_user_uuid_idx ON pub_keys (user_uuid);
+
+-- Anything else stored through the StorageClient
+CREATE TABLE IF NOT EXISTS entries (
+    key TEXT PRIMARY KEY,
+    value JSONB NOT NULL
+);
@@ -0,0 +1,18 @@
+-- The pub_keys table now stores one index entry per row under the same
+-- "pub_key:{sha256(pub_key + hash)}" storage key the file backend uses.
+ALTER TABLE pub_keys RENAME TO pub_keys_legacy;
+ALTER INDEX pub_keys_user_uuid_idx RENAME TO pub_keys_legacy_user_uuid_idx;
+
+CREATE TABLE pub_keys (
+    digest TEXT PRIMARY KEY,
+    data JSONB NOT NULL,
+    user_uuid TEXT GENERATED ALWAYS AS (data->>'user_uuid') STORED
+);
+
+CREATE INDEX pub_keys_user_uuid_idx ON pub_keys (user_uuid);
+
+INSERT INTO pub_keys (digest, data)
+SELECT encode(sha256(convert_to(key, 'UTF8')), 'hex'), jsonb_build_object('key', key, 'user_uuid', user_uuid)
+FROM pub_keys_legacy;
+
+DROP TABLE pub_keys_legacy;
@@ -0,0 +1,230 @@
+use axum::http::Uri;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{config::{Clock, SystemClock}, storage::{AuditAction, AuditEntry, AuditLog, OptionalExt, PubKeys, StorageClient, StorageError, StorageResult, User, UserClient, UserStats, AUDIT_HEAD, AUDIT_STRING, PUB_KEY_STRING, STATE_STRING, USER_STRING}};
+
+use super::IndexProblem;
+
+
+// One stored value, a line of an export
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportEntry {
+    pub key: String,
+    pub value: serde_json::Value,
+}
+
+// Maintenance straight on storage, for operators working without the HTTP server
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    user_client: UserClient,
+    audit_log: AuditLog,
+}
+
+impl AdminClient {
+    pub fn new(storage_uri: Uri) -> Self {
+        let user_client = UserClient::new(storage_uri);
+        let audit_log = AuditLog::new(user_client.client.clone());
+        Self { user_client, audit_log }
+    }
+
+    // What an export holds: users, their state, the index and the audit log, users first so an
+    // import never indexes a user it hasn't written yet. Lockouts and the replay cache are left behind
+    fn exported_prefixes() -> [String; 5] {
+        [
+            format!("{}:", USER_STRING),
+            format!("{}:", STATE_STRING),
+            format!("{}:", PUB_KEY_STRING),
+            format!("{}:", AUDIT_STRING),
+            AUDIT_HEAD.to_string(),
+        ]
+    }
+
+    // Every user, by uuid
+    pub async fn users(&self) -> StorageResult<Vec<User>> {
+        let mut users = vec![];
+        for uuid in self.user_client.user_uuids().await? {
+            // a user deleted since it was listed is skipped
+            if let Some(user) = self.user(&uuid).await.optional()? {
+                users.push(user);
+            }
+        }
+        Ok(users)
+    }
+
+    // NotFound if there is no such user
+    pub async fn user(&self, uuid: &str) -> StorageResult<User> {
+        self.user_client.clone().get_user(uuid).await
+    }
+
+    // The users holding pub_key, as their primary key or a device's
+    pub async fn users_with_key(&self, pub_key: &str) -> StorageResult<Vec<User>> {
+        let users = self.users().await?;
+        Ok(users.into_iter().filter(|user| user.has_key(pub_key)).collect())
+    }
+
+    pub async fn stats(&self) -> StorageResult<UserStats> {
+        self.user_client.stats().await
+    }
+
+    // Deletes the user, its state and index entries, and audits it without a key.
+    // NotFound if there is no such user; if auditing fails the user is gone regardless
+    pub async fn delete_user(&self, uuid: &str) -> StorageResult<()> {
+        self.user_client.clone().delete_user(uuid).await?;
+        let entry = AuditEntry { action: AuditAction::Delete, user_uuid: uuid.to_string(), slot: None, pub_key: None };
+        self.audit_log.append(entry, SystemClock.now_millis()).await?;
+        Ok(())
+    }
+
+    // Every user has to be found by each of its keys, device keys included, and its hash, and
+    // every index entry has to point at a user that still has them
+    pub async fn verify_index(&self) -> StorageResult<Vec<IndexProblem>> {
+        let mut problems = vec![];
+        for user in self.users().await? {
+            for pub_key in user.keys() {
+                match self.user_client.check_key(pub_key, &user.hash).await.optional()? {
+                    Some(check) if check.user_uuid == user.uuid => {},
+                    _ => problems.push(IndexProblem::Unindexed { user_uuid: user.uuid.clone(), pub_key: pub_key.to_string() }),
+                }
+            }
+        }
+
+        for entry in self.user_client.key_entries().await? {
+            let user = self.user(&entry.user_uuid).await.optional()?;
+            if user.is_none_or(|user| user.keys().all(|pub_key| PubKeys::key(&user.hash, pub_key) != entry.key)) {
+                problems.push(IndexProblem::Dangling { key: entry.key, user_uuid: entry.user_uuid });
+            }
+        }
+        Ok(problems)
+    }
+
+    // Writes everything worth keeping as JSON lines of ExportEntry; returns how many
+    pub async fn export(&self, out: &mut (impl AsyncWrite + Unpin)) -> StorageResult<u64> {
+        let client = &self.user_client.client;
+        let mut exported = 0;
+        for prefix in AdminClient::exported_prefixes() {
+            for key in client.keys(&prefix).await? {
+                let Some(value) = client.get(&key).await.optional()? else {
+                    continue;
+                };
+                let mut line = serde_json::to_vec(&ExportEntry { key, value }).map_err(std::io::Error::other)?;
+                line.push(b'\n');
+                out.write_all(&line).await?;
+                exported += 1;
+            }
+        }
+        out.flush().await?;
+        Ok(exported)
+    }
+
+    // Stores every line of an export, overwriting what is there; returns how many.
+    // Only the keys an export writes are accepted, so an edited file can't write elsewhere
+    pub async fn import(&self, input: impl AsyncBufRead + Unpin) -> StorageResult<u64> {
+        let prefixes = AdminClient::exported_prefixes();
+        let mut lines = input.lines();
+        let mut imported = 0;
+        let mut line_number = 0;
+        while let Some(line) = lines.next_line().await? {
+            line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let location = format!("line {}", line_number);
+            let entry: ExportEntry = serde_json::from_str(&line).map_err(|e| StorageError::corrupt(&location, e))?;
+            let exported = prefixes.iter().any(|prefix| entry.key.starts_with(prefix.as_str()));
+            if !exported || entry.key.contains('/') {
+                return Err(StorageError::corrupt(&location, format!("{} is not an exported key", entry.key)));
+            }
+            self.user_client.client.set(&entry.key, entry.value).await?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sessionless::Sessionless;
+
+    use super::*;
+    use crate::test_common::{cleanup_test_files, storage_uri};
+
+    #[tokio::test]
+    async fn test_admin_client() {
+        let uri = storage_uri("admin_client");
+        let admin = AdminClient::new(uri.clone());
+        let user_client = UserClient::new(uri.clone());
+
+        let pub_key = Sessionless::new().public_key().to_string();
+        let device_key = Sessionless::new().public_key().to_string();
+        let (first, _) = user_client.create_user(&pub_key, "first").await.expect("Failed to create user");
+        let (second, _) = user_client.create_user(&pub_key, "second").await.expect("Failed to create user");
+        let second = user_client.update_user::<StorageError>(&second.uuid, |user| Ok(user.with_key(&device_key))).await.expect("Failed to add key");
+
+        let mut expected = vec![first.clone(), second.clone()];
+        expected.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+        assert_eq!(admin.users().await.unwrap(), expected);
+        assert_eq!(admin.user(&first.uuid).await.unwrap(), first);
+        assert_eq!(admin.users_with_key(&device_key).await.unwrap(), vec![second.clone()]);
+        assert_eq!(admin.stats().await.unwrap(), UserStats { users: 2, pub_keys: 3 });
+        assert!(admin.verify_index().await.unwrap().is_empty());
+
+        // an entry left behind and a user whose entries went missing, its device key's included
+        user_client.put_key(&PubKeys::key("stale", &pub_key), &first.uuid).await.expect("Failed to put key");
+        user_client.remove_key(&PubKeys::key("second", &pub_key), &second.uuid).await.expect("Failed to remove key");
+        user_client.remove_key(&PubKeys::key("second", &device_key), &second.uuid).await.expect("Failed to remove key");
+        let problems = admin.verify_index().await.unwrap();
+        assert_eq!(problems, vec![
+            IndexProblem::Unindexed { user_uuid: second.uuid.clone(), pub_key: pub_key.clone() },
+            IndexProblem::Unindexed { user_uuid: second.uuid.clone(), pub_key: device_key.clone() },
+            IndexProblem::Dangling { key: PubKeys::key("stale", &pub_key), user_uuid: first.uuid.clone() },
+        ]);
+
+        // deleting is audited
+        admin.delete_user(&second.uuid).await.expect("Failed to delete user");
+        assert!(matches!(admin.user(&second.uuid).await, Err(StorageError::NotFound(_))));
+        assert!(matches!(admin.delete_user(&second.uuid).await, Err(StorageError::NotFound(_))));
+        let records = admin.audit_log.records(1, 10, Some(&second.uuid)).await.unwrap();
+        assert_eq!(records.iter().map(|record| (record.action, record.pub_key.clone())).collect::<Vec<_>>(), vec![(AuditAction::Delete, None)]);
+
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let from_uri = storage_uri("admin_export");
+        let to_uri = storage_uri("admin_import");
+        let from = AdminClient::new(from_uri.clone());
+        let to = AdminClient::new(to_uri.clone());
+
+        let user_client = UserClient::new(from_uri.clone());
+        let (user, _) = user_client.create_user(&Sessionless::new().public_key().to_string(), "hash").await.expect("Failed to create user");
+        user_client.put_state(&user.uuid, "hash", "encrypted").await.expect("Failed to put state");
+        user_client.record_failure(&user.uuid, 0, 5, 1000).await.expect("Failed to record failure");
+        let entry = AuditEntry { action: AuditAction::Create, user_uuid: user.uuid.clone(), slot: None, pub_key: Some(user.pub_key.clone()) };
+        from.audit_log.append(entry, 0).await.expect("Failed to append");
+
+        // user, state, index entry, audit record and head, but not the lockout
+        let mut export = vec![];
+        assert_eq!(from.export(&mut export).await.unwrap(), 5);
+        assert_eq!(to.import(export.as_slice()).await.unwrap(), 5);
+
+        assert_eq!(to.users().await.unwrap(), vec![user.clone()]);
+        let imported = UserClient::new(to_uri.clone());
+        assert_eq!(imported.get_state(&user.uuid).await.unwrap().state, "encrypted");
+        assert!(imported.get_lockout(&user.uuid).await.is_err());
+        assert!(to.verify_index().await.unwrap().is_empty());
+        assert!(to.audit_log.verify().await.unwrap().intact);
+
+        // only exported keys are written
+        let line = serde_json::to_string(&ExportEntry { key: "user:../escape".to_string(), value: serde_json::json!({}) }).unwrap();
+        assert!(matches!(to.import(line.as_bytes()).await, Err(StorageError::Corrupt { .. })));
+        let line = serde_json::to_string(&ExportEntry { key: "lockout:1234".to_string(), value: serde_json::json!({}) }).unwrap();
+        assert!(matches!(to.import(line.as_bytes()).await, Err(StorageError::Corrupt { .. })));
+        assert!(matches!(to.import("not json".as_bytes()).await, Err(StorageError::Corrupt { .. })));
+
+        cleanup_test_files(&from_uri.to_string()).await;
+        cleanup_test_files(&to_uri.to_string()).await;
+    }
+}
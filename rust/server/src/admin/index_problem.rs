@@ -0,0 +1,20 @@
+use std::fmt;
+
+
+// A way the pub_key + hash index and the users it points at disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexProblem {
+    // the user can't be found by one of its keys and its hash: there is no entry, or it points elsewhere
+    Unindexed { user_uuid: String, pub_key: String },
+    // the entry points at a user that is gone or no longer has its pub_key or hash
+    Dangling { key: String, user_uuid: String },
+}
+
+impl fmt::Display for IndexProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexProblem::Unindexed { user_uuid, pub_key } => write!(f, "user {} has no index entry for pubKey {} and its hash", user_uuid, pub_key),
+            IndexProblem::Dangling { key, user_uuid } => write!(f, "index entry {} points at user {}, which doesn't have it", key, user_uuid),
+        }
+    }
+}
@@ -0,0 +1,5 @@
+mod admin_client;
+mod index_problem;
+
+pub use admin_client::*;
+pub use index_problem::*;
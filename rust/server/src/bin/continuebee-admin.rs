@@ -0,0 +1,139 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use axum::http::Uri;
+use clap::{ArgGroup, Parser, Subcommand};
+use tokio::io::{AsyncBufRead, AsyncWrite, BufReader, BufWriter};
+
+use server::{admin::AdminClient, storage::{StorageError, User}};
+
+
+/// Maintenance on continuebee's storage, without the HTTP server
+#[derive(Debug, Parser)]
+#[command(name = "continuebee-admin")]
+struct Cli {
+    /// The storage to open, as the server's STORAGE_URI
+    // hidden from --help, it may hold a database password
+    #[arg(long, env = "STORAGE_URI", hide_env_values = true)]
+    storage_uri: Uri,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Lists every user, one JSON object per line
+    List,
+    /// Shows a user by uuid, or the users holding a pubKey
+    #[command(group(ArgGroup::new("user").required(true).args(["uuid", "pub_key"])))]
+    Show {
+        uuid: Option<String>,
+        #[arg(long)]
+        pub_key: Option<String>,
+    },
+    /// Deletes a user along with its state and index entry
+    Delete { uuid: String },
+    /// Counts users and pubKey index entries
+    Stats,
+    /// Reports users and pubKey index entries that don't agree
+    VerifyIndex,
+    /// Writes users, state, the pubKey index and the audit log as JSON lines
+    Export {
+        /// Written to stdout when not given
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Reads an export into storage
+    Import {
+        /// Read from stdin when not given
+        #[arg(long)]
+        input: Option<PathBuf>,
+        /// Import into storage that already holds users, overwriting any with the same keys
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let admin = AdminClient::new(cli.storage_uri);
+
+    match run(&admin, cli.command).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+async fn run(admin: &AdminClient, command: Command) -> Result<ExitCode, StorageError> {
+    match command {
+        Command::List => {
+            for user in admin.users().await? {
+                println!("{}", to_json(&user, false)?);
+            }
+        },
+        Command::Show { uuid: Some(uuid), .. } => println!("{}", to_json(&admin.user(&uuid).await?, true)?),
+        Command::Show { pub_key, .. } => {
+            let pub_key = pub_key.unwrap_or_default();
+            let users = admin.users_with_key(&pub_key).await?;
+            if users.is_empty() {
+                return Err(StorageError::NotFound(pub_key));
+            }
+            for user in users {
+                println!("{}", to_json(&user, true)?);
+            }
+        },
+        Command::Delete { uuid } => {
+            admin.delete_user(&uuid).await?;
+            println!("Deleted {}", uuid);
+        },
+        Command::Stats => {
+            let stats = admin.stats().await?;
+            println!("users: {}\npubKeys: {}", stats.users, stats.pub_keys);
+        },
+        Command::VerifyIndex => {
+            let problems = admin.verify_index().await?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                eprintln!("Inconsistencies found: {}", problems.len());
+                return Ok(ExitCode::FAILURE);
+            }
+            println!("The pubKey index is consistent");
+        },
+        Command::Export { output } => {
+            let mut out: Box<dyn AsyncWrite + Unpin> = match output {
+                Some(path) => Box::new(BufWriter::new(tokio::fs::File::create(path).await?)),
+                None => Box::new(BufWriter::new(tokio::io::stdout())),
+            };
+            let exported = admin.export(&mut out).await?;
+            eprintln!("Exported {} entries", exported);
+        },
+        Command::Import { input, force } => {
+            let users = admin.stats().await?.users;
+            if users > 0 && !force {
+                eprintln!("error: storage already holds {} users; pass --force to import over them", users);
+                return Ok(ExitCode::FAILURE);
+            }
+            let input: Box<dyn AsyncBufRead + Unpin> = match input {
+                Some(path) => Box::new(BufReader::new(tokio::fs::File::open(path).await?)),
+                None => Box::new(BufReader::new(tokio::io::stdin())),
+            };
+            let imported = admin.import(input).await?;
+            eprintln!("Imported {} entries", imported);
+        },
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn to_json(user: &User, pretty: bool) -> Result<String, StorageError> {
+    let json = match pretty {
+        true => serde_json::to_string_pretty(user),
+        false => serde_json::to_string(user),
+    };
+    json.map_err(|e| StorageError::Io(std::io::Error::other(e)))
+}
@@ -1,8 +1,14 @@
+use std::sync::Arc;
 
-use crate::storage::UserClient;
+use crate::{middleware::RateLimiter, service::UserService};
 
 
 #[derive(Debug, Clone)]
 pub struct AppState {
-    pub user_client: UserClient,
-}
\ No newline at end of file
+    pub user_service: UserService,
+    // The resolver (Fount) whose signature authorizes spells; spells are refused without one
+    pub fount_pub_key: Option<String>,
+    // Operators whose signatures authorize admin requests; none are accepted without any
+    pub operator_pub_keys: Vec<String>,
+    pub rate_limiter: Arc<RateLimiter>,
+}
@@ -0,0 +1,48 @@
+use std::fmt::Debug;
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+
+
+// Source of the current time, so request freshness can be tested without waiting
+pub trait Clock: Debug + Send + Sync {
+    // Milliseconds since the unix epoch, the unit clients sign timestamps in
+    fn now_millis(&self) -> i64;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("System time is before the unix epoch");
+        now.as_millis() as i64
+    }
+}
+
+// A clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FixedClock {
+    now: AtomicI64,
+}
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(now_millis: i64) -> Self {
+        Self { now: AtomicI64::new(now_millis) }
+    }
+
+    pub fn advance(&self, millis: i64) {
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now_millis(&self) -> i64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
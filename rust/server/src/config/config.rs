@@ -1,14 +1,45 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use axum::http::Uri;
 use dotenv::dotenv;
 
+use super::LogFormat;
+
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub subdomain: String,
     pub port: u16,
     pub storage_uri: Uri,
+    // Maximum clock skew accepted on signed request timestamps
+    pub allowed_time_difference: Duration,
+    // Most signatures remembered in memory for replay protection
+    pub replay_cache_capacity: usize,
+    // Also keep seen signatures in storage so replay protection survives a restart
+    pub persist_replay_cache: bool,
+    // Public key of the resolver (Fount) forwarding MAGIC spells
+    pub fount_pub_key: Option<String>,
+    // Public keys of the operators allowed to use the admin endpoints
+    pub operator_pub_keys: Vec<String>,
+    // Largest client state payload stored per user, in bytes
+    pub max_state_size: usize,
+    // Previous hashes kept per user for rollback
+    pub hash_history_size: usize,
+    // Requests allowed per window from one client IP, and for one pub_key or user; 0 for no limit
+    pub rate_limit_window: Duration,
+    pub rate_limit_per_ip: u32,
+    pub rate_limit_per_key: u32,
+    // Take client IPs from the last X-Forwarded-For address, only behind a proxy that appends it
+    pub trust_forwarded_for: bool,
+    // Hash mismatches in a row that lock a user's hash checks out for lockout_duration; 0 for no lockout
+    pub lockout_threshold: u32,
+    pub lockout_duration: Duration,
+    // pretty or json
+    pub log_format: LogFormat,
+    // tracing filter directives, e.g. "info" or "info,sqlx=warn"
+    pub log_level: String,
+    // Address of the separate listener serving /metrics, e.g. 127.0.0.1:9100; no metrics without one
+    pub metrics_address: Option<String>,
 }
 
 impl ServerConfig {
@@ -24,10 +55,76 @@ impl ServerConfig {
         let storage_uri = std::env::var("STORAGE_URI").expect("STORAGE_URI must be set");
         let storage_uri = Uri::from_str(&storage_uri).expect("STORAGE_URI must be a valid URI");
 
+        // milliseconds, same as allowedTimeDifference in the node server
+        let allowed_time_difference = std::env::var("ALLOWED_TIME_DIFFERENCE").unwrap_or("600000".to_string());
+        let allowed_time_difference = allowed_time_difference.parse::<u64>().expect("ALLOWED_TIME_DIFFERENCE must be a number of milliseconds");
+        let allowed_time_difference = Duration::from_millis(allowed_time_difference);
+
+        let replay_cache_capacity = std::env::var("REPLAY_CACHE_CAPACITY").unwrap_or("100000".to_string());
+        let replay_cache_capacity = replay_cache_capacity.parse::<usize>().expect("REPLAY_CACHE_CAPACITY must be a number");
+
+        let persist_replay_cache = std::env::var("PERSIST_REPLAY_CACHE").unwrap_or("false".to_string());
+        let persist_replay_cache = persist_replay_cache.parse::<bool>().expect("PERSIST_REPLAY_CACHE must be true or false");
+
+        let fount_pub_key = std::env::var("FOUNT_PUB_KEY").ok().filter(|key| !key.is_empty());
+
+        // comma separated
+        let operator_pub_keys = std::env::var("OPERATOR_PUB_KEYS").unwrap_or_default();
+        let operator_pub_keys = operator_pub_keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(str::to_string).collect();
+
+        let max_state_size = std::env::var("MAX_STATE_SIZE").unwrap_or("65536".to_string());
+        let max_state_size = max_state_size.parse::<usize>().expect("MAX_STATE_SIZE must be a number of bytes");
+
+        let hash_history_size = std::env::var("HASH_HISTORY_SIZE").unwrap_or("10".to_string());
+        let hash_history_size = hash_history_size.parse::<usize>().expect("HASH_HISTORY_SIZE must be a number");
+
+        let rate_limit_window = std::env::var("RATE_LIMIT_WINDOW").unwrap_or("60000".to_string());
+        let rate_limit_window = rate_limit_window.parse::<u64>().expect("RATE_LIMIT_WINDOW must be a number of milliseconds");
+        let rate_limit_window = Duration::from_millis(rate_limit_window);
+
+        let rate_limit_per_ip = std::env::var("RATE_LIMIT_PER_IP").unwrap_or("300".to_string());
+        let rate_limit_per_ip = rate_limit_per_ip.parse::<u32>().expect("RATE_LIMIT_PER_IP must be a number");
+
+        let rate_limit_per_key = std::env::var("RATE_LIMIT_PER_KEY").unwrap_or("60".to_string());
+        let rate_limit_per_key = rate_limit_per_key.parse::<u32>().expect("RATE_LIMIT_PER_KEY must be a number");
+
+        let trust_forwarded_for = std::env::var("TRUST_FORWARDED_FOR").unwrap_or("false".to_string());
+        let trust_forwarded_for = trust_forwarded_for.parse::<bool>().expect("TRUST_FORWARDED_FOR must be true or false");
+
+        let lockout_threshold = std::env::var("LOCKOUT_THRESHOLD").unwrap_or("5".to_string());
+        let lockout_threshold = lockout_threshold.parse::<u32>().expect("LOCKOUT_THRESHOLD must be a number");
+
+        let lockout_duration = std::env::var("LOCKOUT_DURATION").unwrap_or("900000".to_string());
+        let lockout_duration = lockout_duration.parse::<u64>().expect("LOCKOUT_DURATION must be a number of milliseconds");
+        let lockout_duration = Duration::from_millis(lockout_duration);
+
+        let log_format = std::env::var("LOG_FORMAT").unwrap_or("pretty".to_string());
+        let log_format = log_format.parse::<LogFormat>().expect("LOG_FORMAT must be pretty or json");
+
+        let log_level = std::env::var("LOG_LEVEL").unwrap_or("info".to_string());
+
+        let metrics_address = std::env::var("METRICS_ADDRESS").ok().filter(|address| !address.is_empty());
+
         ServerConfig {
             subdomain,
             port,
             storage_uri,
+            allowed_time_difference,
+            replay_cache_capacity,
+            persist_replay_cache,
+            fount_pub_key,
+            operator_pub_keys,
+            max_state_size,
+            hash_history_size,
+            rate_limit_window,
+            rate_limit_per_ip,
+            rate_limit_per_key,
+            trust_forwarded_for,
+            lockout_threshold,
+            lockout_duration,
+            log_format,
+            log_level,
+            metrics_address,
         }
     }
 
@@ -1,5 +1,10 @@
+#[allow(clippy::module_inception)]
 pub mod config;
 pub mod app_state;
+pub mod clock;
+pub mod logging;
 
 pub use config::*;
-pub use app_state::*;
\ No newline at end of file
+pub use app_state::*;
+pub use clock::*;
+pub use logging::*;
@@ -0,0 +1,161 @@
+// The JS tests run against this server. test_mocha_server and test_mocha_client follow
+// test/mocha/server.js and test/mocha/client.js case by case, checking only what those cases check;
+// test_readme_bodies checks the bodies of the same four calls against the README
+use axum_test::TestServer;
+use chrono::Utc;
+use serde_json::{json, Value};
+use sessionless::Sessionless;
+
+use crate::test_common::{cleanup_test_files, setup_test_server, storage_uri};
+
+
+// new Date().getTime() + ''
+fn timestamp() -> String {
+    Utc::now().timestamp_millis().to_string()
+}
+
+#[tokio::test]
+async fn test_mocha_server() {
+    let storage_uri = storage_uri("test_contract_mocha_server");
+    let test_server = setup_test_server(storage_uri.clone());
+    let keys = Sessionless::new();
+    let pub_key = keys.public_key().to_string();
+
+    // it('should register a user')
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}", timestamp, pub_key, "firstHash"));
+    let payload = json!({"timestamp": timestamp, "pubKey": pub_key, "hash": "firstHash", "signature": signature.to_string()});
+
+    let response = test_server.post("/user/create").json(&payload).await;
+    let saved_user = response.json::<Value>();
+    let uuid = saved_user["userUUID"].as_str().expect("userUUID missing").to_string();
+    assert_eq!(uuid.len(), 36);
+
+    // it('should check hash')
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}", timestamp, uuid, "firstHash"));
+    let response = test_server.get(&format!("/user/{}?timestamp={}&hash={}&signature={}", uuid, timestamp, "firstHash", signature)).await;
+    assert_eq!(response.status_code(), 200);
+
+    // it('should save hash')
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}{}", timestamp, uuid, "firstHash", "secondHash"));
+    let payload = json!({"timestamp": timestamp, "userUUID": uuid, "hash": "firstHash", "newHash": "secondHash", "signature": signature.to_string()});
+
+    let response = test_server.put("/user/update-hash").json(&payload).await;
+    assert_eq!(response.status_code(), 202);
+
+    // it('should delete a user')
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}", timestamp, uuid, "secondHash"));
+    let payload = json!({"timestamp": timestamp, "userUUID": uuid, "hash": "secondHash", "signature": signature.to_string()});
+
+    let response = test_server.delete("/user/delete").json(&payload).await;
+    assert_eq!(response.status_code(), 200);
+
+    cleanup_test_files(&storage_uri.to_string()).await;
+}
+
+// src/client/javascript/continuebee.js, sending what it sends and reading what it reads
+struct Continuebee<'a> {
+    test_server: &'a TestServer,
+    keys: Sessionless,
+}
+
+impl Continuebee<'_> {
+    async fn create_user(&self, hash: &str) -> Option<String> {
+        let timestamp = self::timestamp();
+        let pub_key = self.keys.public_key().to_string();
+        let signature = self.keys.sign(format!("{}{}{}", timestamp, pub_key, hash));
+        let payload = json!({"timestamp": timestamp, "pubKey": pub_key, "hash": hash, "signature": signature.to_string()});
+
+        let user = self.test_server.post("/user/create").json(&payload).await.json::<Value>();
+        user["userUUID"].as_str().map(str::to_string)
+    }
+
+    async fn update_hash(&self, uuid: &str, hash: &str, new_hash: &str) -> bool {
+        let timestamp = self::timestamp();
+        let signature = self.keys.sign(format!("{}{}{}{}", timestamp, uuid, hash, new_hash));
+        let payload = json!({"timestamp": timestamp, "userUUID": uuid, "hash": hash, "newHash": new_hash, "signature": signature.to_string()});
+
+        self.test_server.put("/user/update-hash").json(&payload).await.status_code() == 202
+    }
+
+    async fn check_hash(&self, uuid: &str, hash: &str) -> bool {
+        let timestamp = self::timestamp();
+        let signature = self.keys.sign(format!("{}{}{}", timestamp, uuid, hash));
+
+        self.test_server.get(&format!("/user/{}?timestamp={}&hash={}&signature={}", uuid, timestamp, hash, signature)).await.status_code() == 200
+    }
+
+    async fn delete_user(&self, uuid: &str, hash: &str) -> bool {
+        let timestamp = self::timestamp();
+        let signature = self.keys.sign(format!("{}{}{}", timestamp, uuid, hash));
+        let payload = json!({"timestamp": timestamp, "userUUID": uuid, "hash": hash, "signature": signature.to_string()});
+
+        self.test_server.delete("/user/delete").json(&payload).await.status_code() == 200
+    }
+}
+
+#[tokio::test]
+async fn test_mocha_client() {
+    let storage_uri = storage_uri("test_contract_mocha_client");
+    let test_server = setup_test_server(storage_uri.clone());
+    let continuebee = Continuebee { test_server: &test_server, keys: Sessionless::new() };
+    let hash = "firstHash";
+    let second_hash = "secondHash";
+
+    // it('should register a user')
+    let uuid = continuebee.create_user(hash).await.expect("userUUID missing");
+    assert_eq!(uuid.len(), 36);
+
+    // it('should check hash')
+    assert!(continuebee.check_hash(&uuid, hash).await);
+
+    // it('should save hash')
+    assert!(continuebee.update_hash(&uuid, hash, second_hash).await);
+
+    // it('should delete a user')
+    assert!(continuebee.delete_user(&uuid, second_hash).await);
+
+    cleanup_test_files(&storage_uri.to_string()).await;
+}
+
+#[tokio::test]
+async fn test_readme_bodies() {
+    let storage_uri = storage_uri("test_contract_readme_bodies");
+    let test_server = setup_test_server(storage_uri.clone());
+    let keys = Sessionless::new();
+    let pub_key = keys.public_key().to_string();
+
+    // POST /user/create: {"userUUID": <uuid>}
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}", timestamp, pub_key, "firstHash"));
+    let payload = json!({"timestamp": timestamp, "pubKey": pub_key, "hash": "firstHash", "signature": signature.to_string()});
+    let response = test_server.post("/user/create").json(&payload).await;
+    let body = response.json::<Value>();
+    let uuid = body["userUUID"].as_str().expect("userUUID missing").to_string();
+    assert_eq!(body, json!({"userUUID": uuid}));
+
+    // GET /user/{uuid}: {"userUUID": <uuid>}
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}", timestamp, uuid, "firstHash"));
+    let response = test_server.get(&format!("/user/{}?timestamp={}&hash={}&signature={}", uuid, timestamp, "firstHash", signature)).await;
+    assert_eq!(response.json::<Value>(), json!({"userUUID": uuid}));
+
+    // PUT /user/update-hash: {"userUUID": <uuid>}
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}{}", timestamp, uuid, "firstHash", "secondHash"));
+    let payload = json!({"timestamp": timestamp, "userUUID": uuid, "hash": "firstHash", "newHash": "secondHash", "signature": signature.to_string()});
+    let response = test_server.put("/user/update-hash").json(&payload).await;
+    assert_eq!(response.json::<Value>(), json!({"userUUID": uuid}));
+
+    // DELETE /user/delete: {"success": true}
+    let timestamp = self::timestamp();
+    let signature = keys.sign(format!("{}{}{}", timestamp, uuid, "secondHash"));
+    let payload = json!({"timestamp": timestamp, "userUUID": uuid, "hash": "secondHash", "signature": signature.to_string()});
+    let response = test_server.delete("/user/delete").json(&payload).await;
+    assert_eq!(response.json::<Value>(), json!({"success": true}));
+
+    cleanup_test_files(&storage_uri.to_string()).await;
+}
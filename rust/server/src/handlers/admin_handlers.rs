@@ -0,0 +1,261 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, Query, State}, Json};
+
+use crate::{config::AppState, service::Auth};
+
+use super::{AuditQuery, Fresh, KeyQuery, OperatorQuery, OperatorRequest, Response};
+
+
+// Records returned when the query doesn't say, and the most it can ask for
+static DEFAULT_AUDIT_LIMIT: usize = 100;
+static MAX_AUDIT_LIMIT: usize = 1000;
+
+// Audit records from sequence from (the first by default) on, only userUUID's when given.
+// signature message is: timestamp + "audit", by an operator key
+pub async fn audit_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Query(query)): Fresh<Query<AuditQuery>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+    let from = query.from.unwrap_or(1);
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).min(MAX_AUDIT_LIMIT);
+
+    match data.user_service.audit_records(&data.operator_pub_keys, from, limit, query.user_uuid.as_deref(), auth).await {
+        Ok(records) => Response::audit_success(records),
+        Err(e) => e.into(),
+    }
+}
+
+// Whether every audit record is unchanged and in place.
+// signature message is: timestamp + "verifyAudit", by an operator key
+pub async fn verify_audit_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Query(query)): Fresh<Query<OperatorQuery>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.verify_audit(&data.operator_pub_keys, auth).await {
+        Ok(check) => Response::audit_check(check),
+        Err(e) => e.into(),
+    }
+}
+
+// The user as stored, along with its mismatch count while lockouts are on.
+// signature message is: timestamp + "lookupUser" + uuid, by an operator key
+pub async fn lookup_user_handler(
+    State(data): State<Arc<AppState>>,
+    Path(uuid): Path<String>,
+    Fresh(Query(query)): Fresh<Query<OperatorQuery>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.lookup_user(&data.operator_pub_keys, &uuid, auth).await {
+        Ok((user, lockout)) => Response::user_record(user, lockout),
+        Err(e) => e.into(),
+    }
+}
+
+// Deletes the user without its hash.
+// signature message is: timestamp + "deleteUser" + uuid, by an operator key
+pub async fn force_delete_handler(
+    State(data): State<Arc<AppState>>,
+    Path(uuid): Path<String>,
+    Fresh(Json(body)): Fresh<Json<OperatorRequest>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
+
+    match data.user_service.force_delete(&data.operator_pub_keys, &uuid, auth).await {
+        Ok(()) => Response::success(),
+        Err(e) => e.into(),
+    }
+}
+
+// The user the pub_key + hash index entry points at, and whether the user agrees.
+// signature message is: timestamp + "lookupKey" + pubKey + hash, by an operator key
+pub async fn lookup_key_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Query(query)): Fresh<Query<KeyQuery>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.lookup_key(&data.operator_pub_keys, &query.pub_key, &query.hash, auth).await {
+        Ok(check) => Response::key_check(check),
+        Err(e) => e.into(),
+    }
+}
+
+// Users and index entries in storage.
+// signature message is: timestamp + "stats", by an operator key
+pub async fn admin_stats_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Query(query)): Fresh<Query<OperatorQuery>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.operator_stats(&data.operator_pub_keys, auth).await {
+        Ok(stats) => Response::stats(stats),
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sessionless::Sessionless;
+
+    use crate::handlers::{AuditQuery, CreateUserRequest, DeleteUserRequest, KeyQuery, OperatorQuery, OperatorRequest, Response, UpdateHashRequest};
+    use crate::storage::{AuditAction, AuditRecord, ChainCheck, KeyCheck, UserStats};
+    use crate::test_common::{cleanup_test_files, storage_uri, TestServerBuilder, USER_CREATE_PATH, USER_DELETE_PATH, USER_UPDATE_HASH_PATH};
+
+
+    fn audit_query(operator: &Sessionless, timestamp: i64, user_uuid: Option<&str>) -> AuditQuery {
+        AuditQuery {
+            timestamp: timestamp.to_string(),
+            signature: operator.sign(format!("{}audit", timestamp)).to_string(),
+            user_uuid: user_uuid.map(str::to_string),
+            from: None,
+            limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_handlers() {
+        let storage_uri = storage_uri("test_audit_handlers");
+        let operator = Sessionless::new();
+        let test_server = TestServerBuilder::new(storage_uri.clone()).operator_pub_key(&operator.public_key().to_string()).build();
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let now = Utc::now().timestamp_millis();
+        let timestamp = now.to_string();
+
+        // a user is created, updated and deleted
+        let payload = CreateUserRequest {
+            timestamp: timestamp.clone(),
+            pub_key: pub_key.clone(),
+            hash: "first".to_string(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, pub_key, "first")).to_string(),
+            slot: None,
+        };
+        let response = test_server.post(USER_CREATE_PATH).json(&payload).await;
+        let Response::User { user_uuid: uuid } = response.json::<Response>() else { panic!("Expected a user") };
+        // creating it again finds the same user and records nothing
+        let timestamp = (now + 1).to_string();
+        let payload = CreateUserRequest {
+            timestamp: timestamp.clone(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, pub_key, "first")).to_string(),
+            ..payload
+        };
+        assert_eq!(test_server.post(USER_CREATE_PATH).json(&payload).await.status_code(), 200);
+
+        let payload = UpdateHashRequest {
+            timestamp: timestamp.clone(),
+            user_uuid: uuid.clone(),
+            hash: "first".to_string(),
+            new_hash: "second".to_string(),
+            signature: sessionless.sign(format!("{}{}{}{}", timestamp, uuid, "first", "second")).to_string(),
+            slot: None,
+        };
+        assert_eq!(test_server.put(USER_UPDATE_HASH_PATH).json(&payload).await.status_code(), 202);
+
+        let payload = DeleteUserRequest {
+            timestamp: timestamp.clone(),
+            user_uuid: uuid.clone(),
+            hash: "second".to_string(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, uuid, "second")).to_string(),
+            slot: None,
+        };
+        assert_eq!(test_server.delete(USER_DELETE_PATH).json(&payload).await.status_code(), 200);
+
+        // and the operator sees each step, with the key that signed it
+        let response = test_server.get("/admin/audit").add_query_params(audit_query(&operator, now, Some(&uuid))).await;
+        assert_eq!(response.status_code(), 200);
+        let Response::Audit { records } = response.json::<Response>() else { panic!("Expected audit records") };
+        let actions = records.iter().map(|record| record.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![AuditAction::Create, AuditAction::UpdateHash, AuditAction::Delete]);
+        assert!(records.iter().all(|record: &AuditRecord| record.pub_key.as_deref() == Some(pub_key.as_str())));
+
+        let response = test_server.get("/admin/audit").add_query_params(audit_query(&operator, now, Some("someone else"))).await;
+        assert!(matches!(response.json::<Response>(), Response::Audit { records } if records.is_empty()));
+
+        let timestamp = now.to_string();
+        let query = OperatorQuery { signature: operator.sign(format!("{}verifyAudit", timestamp)).to_string(), timestamp };
+        let response = test_server.get("/admin/audit/verify").add_query_params(&query).await;
+        assert_eq!(response.status_code(), 200);
+        let Response::AuditCheck(check) = response.json::<Response>() else { panic!("Expected a chain check") };
+        assert_eq!(check, ChainCheck { intact: true, records: 3, broken_at: None, reason: None });
+
+        // only for operators
+        let response = test_server.get("/admin/audit").add_query_params(audit_query(&sessionless, now, None)).await;
+        assert_eq!(response.status_code(), 403);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_operator_handlers() {
+        let storage_uri = storage_uri("test_operator_handlers");
+        let operator = Sessionless::new();
+        let test_server = TestServerBuilder::new(storage_uri.clone()).operator_pub_key(&operator.public_key().to_string()).build();
+        let now = Utc::now().timestamp_millis();
+        let signed = |timestamp: i64, message: String| OperatorQuery {
+            timestamp: timestamp.to_string(),
+            signature: operator.sign(format!("{}{}", timestamp, message)).to_string(),
+        };
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let timestamp = now.to_string();
+        let payload = CreateUserRequest {
+            timestamp: timestamp.clone(),
+            pub_key: pub_key.clone(),
+            hash: "hash".to_string(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, pub_key, "hash")).to_string(),
+            slot: None,
+        };
+        let response = test_server.post(USER_CREATE_PATH).json(&payload).await;
+        let Response::User { user_uuid: uuid } = response.json::<Response>() else { panic!("Expected a user") };
+        let user_path = format!("/admin/user/{}", uuid);
+
+        // the operator sees the user, where its key points and how many there are
+        let response = test_server.get(&user_path).add_query_params(signed(now, format!("lookupUser{}", uuid))).await;
+        assert_eq!(response.status_code(), 200);
+        let Response::UserRecord { user, lockout } = response.json::<Response>() else { panic!("Expected a user record") };
+        assert_eq!((user.uuid.as_str(), user.pub_key.as_str(), user.hash.as_str()), (uuid.as_str(), pub_key.as_str(), "hash"));
+        assert!(lockout.is_none());
+
+        let timestamp = now.to_string();
+        let query = KeyQuery {
+            signature: operator.sign(format!("{}lookupKey{}{}", timestamp, pub_key, "hash")).to_string(),
+            timestamp,
+            pub_key: pub_key.clone(),
+            hash: "hash".to_string(),
+        };
+        let response = test_server.get("/admin/key").add_query_params(&query).await;
+        assert!(matches!(response.json::<Response>(), Response::KeyCheck(check) if check == KeyCheck { user_uuid: uuid.clone(), consistent: true }));
+
+        let response = test_server.get("/admin/stats").add_query_params(signed(now, "stats".to_string())).await;
+        assert!(matches!(response.json::<Response>(), Response::Stats(UserStats { users: 1, pub_keys: 1 })));
+
+        // only operators, and a signature is for its own user
+        let timestamp = now.to_string();
+        let query = OperatorQuery { signature: sessionless.sign(format!("{}lookupUser{}", timestamp, uuid)).to_string(), timestamp };
+        assert_eq!(test_server.get(&user_path).add_query_params(&query).await.status_code(), 403);
+        let response = test_server.get("/admin/user/someone-else").add_query_params(signed(now, format!("lookupUser{}", uuid))).await;
+        assert_eq!(response.status_code(), 403);
+
+        // a forced delete needs no hash, and is audited under the operator's key
+        let query = signed(now, format!("deleteUser{}", uuid));
+        let body = OperatorRequest { timestamp: query.timestamp, signature: query.signature };
+        assert_eq!(test_server.delete(&user_path).json(&body).await.status_code(), 200);
+
+        let response = test_server.get(&user_path).add_query_params(signed(now, format!("lookupUser{}", uuid))).await;
+        assert_eq!(response.status_code(), 404);
+        let response = test_server.get("/admin/audit").add_query_params(audit_query(&operator, now, Some(&uuid))).await;
+        let Response::Audit { records } = response.json::<Response>() else { panic!("Expected audit records") };
+        assert_eq!(records.last().map(|record| (record.action, record.pub_key.clone())), Some((AuditAction::Delete, Some(operator.public_key().to_string()))));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
@@ -1,55 +1,23 @@
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use axum::{extract::State, Json};
-use sessionless::{secp256k1::PublicKey, Sessionless, Signature};
 
-use crate::{config::AppState, storage::PubKeys};
+use crate::{config::AppState, service::Auth};
 
-use super::{CreateUserRequest, Response};
+use super::{CreateUserRequest, Fresh, Response};
 
 
 // Creates a new user if pubKey does not exist, and returns existing uuid if it does.
 // signature message is: timestamp + pubKey + hash
 pub async fn create_user_handler(
     State(data): State<Arc<AppState>>,
-    Json(body): Json<CreateUserRequest>,
-) -> Json<Response> { 
-    let message = format!("{}{}{}", body.timestamp, body.pub_key, body.hash);
-    let sessionless = Sessionless::new();
-
-    if let Ok(pub_key) = PublicKey::from_str(body.pub_key.as_str()) {
-        let sig = match Signature::from_str(body.signature.as_str()) {
-            Ok(s) => s,
-            Err(_) => {
-                return Json(Response::auth_error());
-            }
-        };
+    Fresh(Json(body)): Fresh<Json<CreateUserRequest>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
 
-        if sessionless.verify(message, &pub_key, &sig).is_err() {
-            return Json(Response::auth_error());
-        }
-
-        let key = PubKeys::key(&body.hash, &body.pub_key);
-        match data.user_client.clone().get_user_uuid(&key).await {
-            // If user exists with given (pub_key + hash), return back the user_uuid
-            Some(user_uuid) => Json(Response::user_success(user_uuid)),
-            None => {
-                // otherwise, put a new user
-                let new_uuid = Sessionless::generate_uuid();
-                match data.user_client.clone().put_user(&new_uuid.to_string(), &body.pub_key, &body.hash).await {
-                    Ok(user) => {
-                        // add pub key + hash with user uuid
-                        match data.user_client.clone().update_keys(&key, &user.uuid).await {
-                            Ok(_) => Json(Response::user_success(user.uuid)),
-                            Err(_) => Json(Response::server_error("Failed to update keys".to_string()))
-                        }
-                    },
-                    Err(_) => Json(Response::server_error("Failed to put user".to_string()))
-                }
-            }
-        }
-    } else {
-        return Json(Response::auth_error());
+    match data.user_service.create(&body.pub_key, &body.hash, body.slot.as_deref(), auth).await {
+        Ok(user) => Response::user_success(user.uuid),
+        Err(e) => e.into(),
     }
 }
 
@@ -60,11 +28,13 @@ mod tests {
     use chrono::Utc;
     use sessionless::Sessionless;
 
+    use crate::config::{Clock, FixedClock};
     use crate::handlers::{CreateUserRequest, Response};
     use crate::storage::PubKeys;
-    use crate::test_common::{self, check_path_exists, cleanup_test_files, read_keys, setup_test_server, storage_uri};
+    use crate::test_common::{self, check_path_exists, cleanup_test_files, count_users, key_entry_path, read_keys, setup_test_server, storage_uri, TestServerBuilder};
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants, clippy::bool_assert_comparison, clippy::redundant_field_names, clippy::to_string_in_format_args)]
     async fn test_create_user_handler() {
         let storage_uri = storage_uri("test_create_user_handler");
         let test_server = setup_test_server(storage_uri.clone());
@@ -73,7 +43,7 @@ mod tests {
         let sessionless = Sessionless::new();
 
         let pub_key = sessionless.public_key();
-        let timestamp = Utc::now().timestamp().to_string();
+        let timestamp = Utc::now().timestamp_millis().to_string();
         let hash = "random_hash".to_string();
 
         let message = format!("{}{}{}", timestamp, pub_key, hash);
@@ -84,12 +54,13 @@ mod tests {
             timestamp: timestamp,
             hash: hash.clone(),
             signature: signature.to_string(),
+            slot: None,
         };
 
 
         let response = test_server.post(test_common::USER_CREATE_PATH).json(&payload).await;
 
-        assert_eq!(response.clone().status_code(), 200);
+        assert_eq!(response.status_code(), 200);
         // get the user_uuid from the response
         // parse as Response
         let user_resposne = response.json::<Response>();
@@ -101,12 +72,11 @@ mod tests {
                 let file_path = format!("{}/user:{}", storage_uri.to_string(), user_uuid);
                 assert!(check_path_exists(file_path.as_str()).await);
 
-                // check the keys file also exists
-                let keys_file_path = format!("{}/keys", storage_uri.to_string());
-                assert!(check_path_exists(keys_file_path.as_str()).await);
-
-                // TODO check the keys file has the correct pub_key + hash  and user_uuid
+                // check the index entry for pub_key + hash also exists
                 let key = PubKeys::key(&hash.clone(), &pub_key.to_string());
+                assert!(check_path_exists(key_entry_path(&storage_uri.to_string(), &key).as_str()).await);
+
+                // check the index has the correct pub_key + hash and user_uuid
                 let pub_keys = read_keys(&storage_uri.to_string()).await.expect("Failed to read keys");
                 assert!(pub_keys.num_keys() == 1);
                 assert!(pub_keys.get_user_uuid(key.as_str()).is_some());
@@ -120,6 +90,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_create_user_handler_auth_error() {
         let storage_uri = storage_uri("test_create_user_handler_auth_error");
         let test_server = setup_test_server(storage_uri.clone());
@@ -128,7 +99,7 @@ mod tests {
         let sessionless = Sessionless::new();
 
         let pub_key = sessionless.public_key();
-        let timestamp = Utc::now().timestamp().to_string();
+        let timestamp = Utc::now().timestamp_millis().to_string();
         let hash = "random_hash".to_string();
 
         let invalid_payload = CreateUserRequest {
@@ -136,6 +107,7 @@ mod tests {
             timestamp: timestamp.clone(),
             hash: hash.clone(),
             signature: "invalid_signature".to_string(),
+            slot: None,
         };
 
         let post_path = "/user/create";
@@ -143,6 +115,7 @@ mod tests {
         let response = test_server.post(post_path).json(&invalid_payload).await;
 
         let expected_code = 403;
+        assert_eq!(response.status_code(), expected_code);
 
         // parse as Response
         let error_response = response.json::<Response>();
@@ -165,9 +138,11 @@ mod tests {
             timestamp: timestamp.clone(),
             hash: hash.clone(),
             signature: signature.to_string(),
+            slot: None,
         };
 
         let response = test_server.post(post_path).json(&invalid_payload).await;
+        assert_eq!(response.status_code(), expected_code);
 
         // parse as Response
         let error_response = response.json::<Response>();
@@ -183,4 +158,87 @@ mod tests {
 
         // TODO handle internal server errors
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_create_user_handler_stale_timestamp() {
+        let storage_uri = storage_uri("test_create_user_handler_stale_timestamp");
+        let clock = std::sync::Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let test_server = TestServerBuilder::new(storage_uri.clone()).clock(clock.clone()).build();
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let timestamp = clock.now_millis().to_string();
+        let hash = "random_hash".to_string();
+        let signature = sessionless.sign(format!("{}{}{}", timestamp, pub_key, hash));
+
+        let payload = CreateUserRequest {
+            pub_key,
+            timestamp,
+            hash,
+            signature: signature.to_string(),
+            slot: None,
+        };
+
+        // a correctly signed request replayed after the allowed time difference
+        clock.advance(test_common::ALLOWED_TIME_DIFFERENCE.as_millis() as i64 + 1);
+        let response = test_server.post(test_common::USER_CREATE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 400);
+
+        match response.json::<Response>() {
+            Response::Error { code, message } => {
+                assert_eq!(code, 400);
+                assert_eq!(message, "Stale Timestamp");
+            },
+            _ => {
+                panic!("Unexpected response");
+            }
+        }
+        // nothing was stored
+        assert!(!check_path_exists(&storage_uri.to_string()).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_create_user_handler_concurrent() {
+        let storage_uri = storage_uri("test_create_user_handler_concurrent");
+        let test_server = std::rc::Rc::new(TestServerBuilder::new(storage_uri.clone()).http_transport().build());
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let hash = "random_hash".to_string();
+
+        // hammer create with the same pub_key + hash, each request separately signed.
+        // Requests are sent from one local task set; the server handles them in parallel
+        let local = tokio::task::LocalSet::new();
+        let mut requests = tokio::task::JoinSet::new();
+        for i in 0..24 {
+            let timestamp = (Utc::now().timestamp_millis() + i).to_string();
+            let signature = sessionless.sign(format!("{}{}{}", timestamp, pub_key, hash));
+            let payload = CreateUserRequest {
+                pub_key: pub_key.clone(),
+                timestamp,
+                hash: hash.clone(),
+                signature: signature.to_string(),
+                slot: None,
+            };
+
+            let test_server = test_server.clone();
+            requests.spawn_local_on(async move {
+                test_server.post(test_common::USER_CREATE_PATH).json(&payload).await.json::<Response>()
+            }, &local);
+        }
+
+        let uuids: Vec<String> = local.run_until(requests.join_all()).await.into_iter().map(|response| match response {
+            Response::User { user_uuid } => user_uuid,
+            _ => panic!("Unexpected response"),
+        }).collect();
+
+        // one user, one index entry
+        assert!(uuids.iter().all(|uuid| uuid == &uuids[0]));
+        let pub_keys = read_keys(&storage_uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key(&hash, &pub_key)), Some(&uuids[0]));
+        assert_eq!(count_users(&storage_uri.to_string()).await, 1);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
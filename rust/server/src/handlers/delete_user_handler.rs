@@ -1,60 +1,23 @@
-
-
-
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use axum::{extract::State, Json};
-use sessionless::{Sessionless, Signature};
 
-use crate::{config::AppState, storage::PubKeys};
+use crate::{config::AppState, service::Auth};
 
-use super::{DeleteUserRequest, Response};
+use super::{DeleteUserRequest, Fresh, Response};
 
 // Deletes the user from storage and the public key + hash
+// signature message is: timestamp + userUUID + hash
 pub async fn delete_user_handler(
     State(data): State<Arc<AppState>>,
-    Json(body): Json<DeleteUserRequest>,
-) -> Json<Response> {
-
-    let message = format!("{}{}{}", body.timestamp, body.user_uuid, body.hash);
-    let sessionless = Sessionless::new();
-
-    let sig = match Signature::from_str(body.signature.as_str()) {
-        Ok(s) => s,
-        Err(_) => {
-            return Json(Response::auth_error());
-        }
-    };
-
-    let found_user = match data.user_client.clone().get_user(&body.user_uuid).await {
-        Some(user) => user,
-        None => {
-            return Json(Response::not_found());
-        }
-    };
-
-    let pub_key = match found_user.pub_key() {
-        Ok(key) => key,
-        Err(_) => {
-            return Json(Response::auth_error());
-        }
-    };
+    Fresh(Json(body)): Fresh<Json<DeleteUserRequest>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
 
-    if sessionless.verify(message, &pub_key, &sig).is_err() {
-        return Json(Response::auth_error());
+    match data.user_service.delete(&body.user_uuid, &body.hash, body.slot.as_deref(), auth).await {
+        Ok(()) => Response::success(),
+        Err(e) => e.into(),
     }
-
-    let key = PubKeys::key(&body.hash, &pub_key.to_string());
-    if data.user_client.clone().delete_user(&found_user.uuid).await {
-        if let Err(_) = data.user_client.clone().remove_key(&key).await {
-            return Json(Response::server_error("Failed to delete key".to_string()))
-        }
-
-        Json(Response::success(202))
-    } else {
-        Json(Response::server_error("Failed to delete user".to_string()))
-    }
-
 }
 
 #[cfg(test)]
@@ -62,23 +25,23 @@ mod tests {
     use chrono::Utc;
     use sessionless::Sessionless;
 
-    use crate::{handlers::{DeleteUserRequest, Response}, storage::PubKeys, test_common::{check_path_exists, cleanup_test_files, read_keys, setup_test_server, storage_uri, write_keys, write_user, USER_DELETE_PATH}};
+    use crate::{handlers::{DeleteUserRequest, Response}, storage::PubKeys, test_common::{check_path_exists, cleanup_test_files, key_entry_path, read_keys, setup_test_server, storage_uri, write_keys, write_user, USER_DELETE_PATH}};
 
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_delete_user_handler() {
         let initial_uuid_1 = "1234";
         let initial_hash_1 = "initial_hash_1";
         let initial_uuid_2 = "1235";
         let initial_hash_2 = "initial_hash_2";
 
-        let timestamp = Utc::now().timestamp().to_string();
+        let timestamp = Utc::now().timestamp_millis().to_string();
 
         let storage_uri = storage_uri("test_delete_user_handler");
         let test_server = setup_test_server(storage_uri.clone());
         let user_file_path_1 = format!("{}/user:{}", &storage_uri.to_string(), initial_uuid_1);
         let user_file_path_2 = format!("{}/user:{}", &storage_uri.to_string(), initial_uuid_2);
-        let key_file_path = format!("{}/keys", &storage_uri.to_string());
 
         assert!(test_server.is_running());
         let sessionless = Sessionless::new();
@@ -104,7 +67,8 @@ mod tests {
         assert!(check_path_exists(&user_file_path_1).await);
         assert!(check_path_exists(&user_file_path_2).await);
         // check if keys exist
-        assert!(check_path_exists(&key_file_path).await);
+        assert!(check_path_exists(&key_entry_path(&storage_uri.to_string(), &key_1)).await);
+        assert!(check_path_exists(&key_entry_path(&storage_uri.to_string(), &key_2)).await);
 
         let message = format!("{}{}{}", timestamp, initial_uuid_1, initial_hash_1);
         let signature = sessionless.sign(message);
@@ -114,19 +78,21 @@ mod tests {
             user_uuid: initial_uuid_1.to_string(),
             hash: initial_hash_1.to_string(),
             signature: signature.to_string(),
+            slot: None,
         };
 
         let response = test_server.delete(USER_DELETE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 200);
         let delete_response = response.json::<Response>();
         match delete_response.clone() {
-            Response::Success { code } => {
-                assert_eq!(code, 202);
+            Response::Success { success } => {
+                assert!(success);
                 // check if the first user file exists
                 assert!(!check_path_exists(&user_file_path_1).await);
                 // check if the second user file exists
                 assert!(check_path_exists(&user_file_path_2).await);
-                // check if keys exist
-                assert!(check_path_exists(&key_file_path).await);
+                // check that the first user's key was removed
+                assert!(!check_path_exists(&key_entry_path(&storage_uri.to_string(), &key_1)).await);
                 // check that only one key remains
                 let pub_keys = read_keys(&storage_uri.to_string()).await.expect("Failed to read keys");
                 assert_eq!(pub_keys.num_keys(), 1);
@@ -141,4 +107,51 @@ mod tests {
         // clean up
         cleanup_test_files(&storage_uri.to_string()).await;
     }
+
+    #[tokio::test]
+    async fn test_delete_user_handler_replayed() {
+        let uuid = "1234";
+        let hash = "initial_hash";
+
+        let storage_uri = storage_uri("test_delete_user_handler_replayed");
+        let test_server = setup_test_server(storage_uri.clone());
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key();
+
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        assert!(write_user(&storage_uri.to_string(), uuid, &pub_key.to_string(), hash).await);
+
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let signature = sessionless.sign(format!("{}{}{}", timestamp, uuid, hash));
+        let payload = DeleteUserRequest {
+            timestamp,
+            user_uuid: uuid.to_string(),
+            hash: hash.to_string(),
+            signature: signature.to_string(),
+            slot: None,
+        };
+
+        let response = test_server.delete(USER_DELETE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 200);
+        assert!(matches!(response.json::<Response>(), Response::Success { success: true }));
+
+        // the user comes back, and a captured delete is sent again within the time window
+        assert!(write_user(&storage_uri.to_string(), uuid, &pub_key.to_string(), hash).await);
+        let response = test_server.delete(USER_DELETE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 403);
+        match response.json::<Response>() {
+            Response::Error { code, message } => {
+                assert_eq!(code, 403);
+                assert_eq!(message, "Replayed Request");
+            },
+            _ => {
+                panic!("Unexpected response");
+            }
+        }
+        assert!(check_path_exists(&format!("{}/user:{}", &storage_uri.to_string(), uuid)).await);
+
+        // clean up
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
 }
\ No newline at end of file
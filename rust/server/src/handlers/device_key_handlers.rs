@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Json};
+
+use crate::{config::AppState, service::Auth};
+
+use super::{AddKeyRequest, Fresh, Response, RevokeKeyRequest};
+
+
+// Authorizes newPubKey for the user as well, e.g. for another device.
+// signature (by any of the user's keys) and newSignature (by newPubKey) are both over:
+// timestamp + "addKey" + userUUID + hash + newPubKey
+pub async fn add_key_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Json(body)): Fresh<Json<AddKeyRequest>>,
+) -> (StatusCode, Response) {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
+
+    match data.user_service.add_key(&body.user_uuid, &body.hash, &body.new_pub_key, &body.new_signature, auth).await {
+        Ok(user) => (StatusCode::ACCEPTED, Response::user_success(user.uuid)),
+        Err(e) => Response::from(e).with_status(),
+    }
+}
+
+// Revokes pubKey; any of the user's keys can sign, including pubKey itself.
+// signature message is: timestamp + "revokeKey" + userUUID + hash + pubKey
+pub async fn revoke_key_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Json(body)): Fresh<Json<RevokeKeyRequest>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
+
+    match data.user_service.revoke_key(&body.user_uuid, &body.hash, &body.pub_key, auth).await {
+        Ok(user) => Response::user_success(user.uuid),
+        Err(e) => e.into(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sessionless::Sessionless;
+
+    use crate::handlers::{AddKeyRequest, QueryParams, Response, RevokeKeyRequest, UpdateHashRequest};
+    use crate::test_common::{cleanup_test_files, read_user, setup_test_server, storage_uri, write_user, USER_ADD_KEY_PATH, USER_REVOKE_KEY_PATH, USER_UPDATE_HASH_PATH};
+
+
+    fn add_key_request(signer: &Sessionless, new: &Sessionless, timestamp: &str, uuid: &str, hash: &str) -> AddKeyRequest {
+        let new_pub_key = new.public_key().to_string();
+        let message = format!("{}addKey{}{}{}", timestamp, uuid, hash, new_pub_key);
+        AddKeyRequest {
+            timestamp: timestamp.to_string(),
+            user_uuid: uuid.to_string(),
+            hash: hash.to_string(),
+            new_pub_key,
+            signature: signer.sign(&message).to_string(),
+            new_signature: new.sign(&message).to_string(),
+        }
+    }
+
+    fn revoke_key_request(signer: &Sessionless, pub_key: &str, timestamp: &str, uuid: &str, hash: &str) -> RevokeKeyRequest {
+        let message = format!("{}revokeKey{}{}{}", timestamp, uuid, hash, pub_key);
+        RevokeKeyRequest {
+            timestamp: timestamp.to_string(),
+            user_uuid: uuid.to_string(),
+            hash: hash.to_string(),
+            pub_key: pub_key.to_string(),
+            signature: signer.sign(&message).to_string(),
+        }
+    }
+
+    fn get_query(sessionless: &Sessionless, timestamp: &str, uuid: &str, hash: &str) -> QueryParams {
+        QueryParams {
+            timestamp: timestamp.to_string(),
+            hash: hash.to_string(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, uuid, hash)).to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_device_keys() {
+        let uuid = "1234";
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let get_user_path = format!("/user/{}", uuid);
+
+        let storage_uri = storage_uri("test_device_key_handlers");
+        let test_server = setup_test_server(storage_uri.clone());
+
+        let phone = Sessionless::new();
+        let desktop = Sessionless::new();
+        let phone_pub_key = phone.public_key().to_string();
+        let desktop_pub_key = desktop.public_key().to_string();
+
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        assert!(write_user(&storage_uri.to_string(), uuid, &phone_pub_key, "hash").await);
+
+        // a key the user doesn't hold can't add one
+        let payload = add_key_request(&desktop, &desktop, &timestamp, uuid, "hash");
+        let response = test_server.put(USER_ADD_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 403);
+
+        let payload = add_key_request(&phone, &desktop, &timestamp, uuid, "hash");
+        let response = test_server.put(USER_ADD_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+        let user = read_user(&storage_uri.to_string(), uuid).await.expect("Failed to read user");
+        assert_eq!(user.keys().collect::<Vec<_>>(), vec![phone_pub_key.as_str(), desktop_pub_key.as_str()]);
+
+        // either key verifies
+        for sessionless in [&phone, &desktop] {
+            let response = test_server.get(&get_user_path).add_query_params(get_query(sessionless, &timestamp, uuid, "hash")).await;
+            assert_eq!(response.status_code(), 200);
+        }
+
+        // and either key can change the hash
+        let message = format!("{}{}{}{}", timestamp, uuid, "hash", "new_hash");
+        let payload = UpdateHashRequest {
+            user_uuid: uuid.to_string(),
+            timestamp: timestamp.clone(),
+            hash: "hash".to_string(),
+            new_hash: "new_hash".to_string(),
+            signature: desktop.sign(message).to_string(),
+            slot: None,
+        };
+        let response = test_server.put(USER_UPDATE_HASH_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+
+        // the desktop revokes the phone
+        let payload = revoke_key_request(&desktop, &phone_pub_key, &timestamp, uuid, "new_hash");
+        let response = test_server.delete(USER_REVOKE_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 200);
+        assert!(matches!(response.json::<Response>(), Response::User { user_uuid } if user_uuid == uuid));
+
+        let response = test_server.get(&get_user_path).add_query_params(get_query(&phone, &timestamp, uuid, "new_hash")).await;
+        assert_eq!(response.status_code(), 403);
+        let response = test_server.get(&get_user_path).add_query_params(get_query(&desktop, &timestamp, uuid, "new_hash")).await;
+        assert_eq!(response.status_code(), 200);
+
+        // a key the user no longer holds
+        let payload = revoke_key_request(&desktop, &phone_pub_key, &(timestamp.parse::<i64>().unwrap() + 1).to_string(), uuid, "new_hash");
+        let response = test_server.delete(USER_REVOKE_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 404);
+
+        // the last key stays
+        let payload = revoke_key_request(&desktop, &desktop_pub_key, &timestamp, uuid, "new_hash");
+        let response = test_server.delete(USER_REVOKE_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 409);
+        let user = read_user(&storage_uri.to_string(), uuid).await.expect("Failed to read user");
+        assert_eq!(user.keys().collect::<Vec<_>>(), vec![desktop_pub_key.as_str()]);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use axum::{extract::{FromRequest, Query, Request}, response::{IntoResponse, Response as AxumResponse}, Json};
+
+use crate::config::AppState;
+
+use super::{AddKeyRequest, AuditQuery, CreateUserRequest, DeleteUserRequest, KeyQuery, OperatorQuery, OperatorRequest, PutStateRequest, QueryParams, Response, RevokeKeyRequest, RollbackRequest, RotateKeyRequest, UpdateHashRequest};
+
+
+// Requests carrying the signed timestamp
+pub trait Timestamped {
+    fn timestamp(&self) -> &str;
+}
+
+// For requests whose signed timestamp is their timestamp field
+macro_rules! timestamped {
+    ($($request:ty),+ $(,)?) => {
+        $(
+            impl Timestamped for $request {
+                fn timestamp(&self) -> &str {
+                    &self.timestamp
+                }
+            }
+        )+
+    };
+}
+
+timestamped!(
+    CreateUserRequest, UpdateHashRequest, DeleteUserRequest, RotateKeyRequest, AddKeyRequest, RevokeKeyRequest,
+    PutStateRequest, RollbackRequest, QueryParams, OperatorQuery, AuditQuery, KeyQuery, OperatorRequest,
+);
+
+impl<T: Timestamped> Timestamped for Json<T> {
+    fn timestamp(&self) -> &str {
+        self.0.timestamp()
+    }
+}
+
+impl<T: Timestamped> Timestamped for Query<T> {
+    fn timestamp(&self) -> &str {
+        self.0.timestamp()
+    }
+}
+
+// Whether timestamp (unix millis) is within allowed of now, in either direction
+pub fn is_fresh(timestamp: &str, now_millis: i64, allowed_millis: i64) -> bool {
+    match timestamp.parse::<i64>() {
+        Ok(timestamp) => now_millis.abs_diff(timestamp) <= allowed_millis.unsigned_abs(),
+        Err(_) => false,
+    }
+}
+
+// Wraps an extractor and rejects the request when its timestamp is outside
+// the allowed time difference, so an old signed request can't be replayed later
+pub struct Fresh<E>(pub E);
+
+impl<E, M> FromRequest<Arc<AppState>, M> for Fresh<E>
+where
+    E: FromRequest<Arc<AppState>, M> + Timestamped,
+{
+    type Rejection = AxumResponse;
+
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        // malformed bodies and queries get the documented error shape
+        let inner = E::from_request(req, state).await.map_err(|_| Response::bad_request().into_response())?;
+
+        if state.user_service.check_fresh(inner.timestamp()).is_err() {
+            return Err(Response::stale_timestamp().into_response());
+        }
+        Ok(Fresh(inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{routing::post, Router};
+    use axum_test::TestServer;
+
+    use super::*;
+    use crate::{config::FixedClock, middleware::{RateLimiter, RateLimits}, service::{LockoutPolicy, UserService}, storage::{ReplayCache, UserClient}, test_common::storage_uri};
+
+    static NOW: i64 = 1_700_000_000_000;
+
+    #[test]
+    fn test_is_fresh() {
+        assert!(is_fresh(&NOW.to_string(), NOW, 1000));
+        assert!(is_fresh(&(NOW - 1000).to_string(), NOW, 1000));
+        assert!(is_fresh(&(NOW + 1000).to_string(), NOW, 1000));
+
+        // stale and from the future
+        assert!(!is_fresh(&(NOW - 1001).to_string(), NOW, 1000));
+        assert!(!is_fresh(&(NOW + 1001).to_string(), NOW, 1000));
+
+        // not a timestamp
+        assert!(!is_fresh("now", NOW, 1000));
+        assert!(!is_fresh("", NOW, 1000));
+    }
+
+    async fn echo_timestamp(Fresh(Json(body)): Fresh<Json<CreateUserRequest>>) -> String {
+        body.timestamp
+    }
+
+    #[tokio::test]
+    async fn test_fresh_extractor() {
+        let clock = Arc::new(FixedClock::new(NOW));
+        let user_service = UserService::new(
+            UserClient::new(storage_uri("test_fresh_extractor")),
+            ReplayCache::new(10),
+            clock.clone(),
+            Duration::from_secs(60),
+            1024,
+            10,
+            LockoutPolicy::default(),
+        );
+        let rate_limiter = Arc::new(RateLimiter::new(RateLimits::default(), clock.clone()));
+        let state = Arc::new(AppState { user_service, fount_pub_key: None, operator_pub_keys: vec![], rate_limiter });
+        let router = Router::new().route("/", post(echo_timestamp)).with_state(state);
+        let test_server = TestServer::new(router).unwrap();
+
+        let request = |timestamp: i64| CreateUserRequest {
+            pub_key: "pub_key".to_string(),
+            hash: "hash".to_string(),
+            timestamp: timestamp.to_string(),
+            signature: "signature".to_string(),
+            slot: None,
+        };
+
+        let response = test_server.post("/").json(&request(NOW - 30_000)).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.text(), (NOW - 30_000).to_string());
+
+        // the same request is rejected once the clock moves past the window
+        clock.advance(60_000);
+        let response = test_server.post("/").json(&request(NOW - 30_000)).await;
+        assert_eq!(response.status_code(), 400);
+        match response.json::<Response>() {
+            Response::Error { code, message } => {
+                assert_eq!(code, 400);
+                assert_eq!(message, "Stale Timestamp");
+            },
+            _ => panic!("Unexpected response"),
+        }
+
+        // too far in the future
+        let response = test_server.post("/").json(&request(NOW + 180_000)).await;
+        assert_eq!(response.status_code(), 400);
+        assert!(matches!(response.json::<Response>(), Response::Error { code: 400, .. }));
+    }
+}
@@ -1,10 +1,9 @@
-use std::{str::FromStr, sync::Arc};
-use axum::{extract::{Path, Query, State}, Json};
-use sessionless::{secp256k1::PublicKey, Sessionless, Signature};
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
 
-use crate::config::AppState;
+use crate::{config::AppState, service::Auth};
 
-use super::{QueryParams, Response};
+use super::{Fresh, QueryParams, Response, SlotQuery};
 
 
 
@@ -13,44 +12,15 @@ use super::{QueryParams, Response};
 pub async fn get_user_handler(
     State(data): State<Arc<AppState>>,
     Path(uuid): Path<String>,
-    Query(query): Query<QueryParams>,
-) -> Json<Response> {
-
-    let user_uuid = uuid.to_string();
-    let timestamp  = query.timestamp.to_string();
-    let hash = query.hash.to_string();
-    let signature = query.signature.to_string();
-    let message = format!("{}{}{}", timestamp, user_uuid, hash);
-
-    // get user from user_uuid
-     match data.user_client.clone().get_user(&user_uuid).await {
-        Some(found_user) => {
-            let sessionless = Sessionless::new();
-
-            if let Ok(pub_key) = PublicKey::from_str(found_user.pub_key.as_str()) {
-                let sig = match Signature::from_str(signature.as_str()) {
-                    Ok(s) => s,
-                    Err(_) => {
-                        return Json(Response::auth_error());
-                    }
-                };
-                
-                // Verify with query params and user's pub_key
-                if sessionless.verify(message, &pub_key, &sig).is_err() {
-                    return Json(Response::auth_error());
-                }
-            } else {
-                return Json(Response::auth_error());
-            }
-            
-            if found_user.hash == hash {
-                return Json(Response::user_success(found_user.uuid));
-            } else {
-                return Json(Response::not_acceptable());
-            }
-        },
-        None => Json(Response::not_found())
-     }
+    Query(slot): Query<SlotQuery>,
+    Fresh(Query(query)): Fresh<Query<QueryParams>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.verify(&uuid, &query.hash, slot.slot.as_deref(), auth).await {
+        Ok(user) => Response::user_success(user.uuid),
+        Err(e) => e.into(),
+    }
 }
 
 #[cfg(test)]
@@ -58,14 +28,15 @@ mod tests {
     use chrono::Utc;
     use sessionless::Sessionless;
 
-    use crate::{handlers::{QueryParams, Response}, test_common::{cleanup_test_files, setup_test_server, storage_uri, write_user}};
+    use crate::{handlers::{CreateUserRequest, QueryParams, Response, SlotQuery, UpdateHashRequest}, test_common::{cleanup_test_files, setup_test_server, storage_uri, write_user, USER_CREATE_PATH, USER_UPDATE_HASH_PATH}};
 
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_get_user_handler() {
         let inital_uuid = "1234";
         let initial_hash = "initial_hash";
-        let timestamp = Utc::now().timestamp().to_string();
+        let timestamp = Utc::now().timestamp_millis().to_string();
         let get_user_path = format!("/user/{}", inital_uuid);
 
         let storage_uri = storage_uri("test_get_user_handler");
@@ -106,6 +77,7 @@ mod tests {
         // get a user that does not exist
         let get_user_path = format!("/user/{}", "non_existent_user");
         let response = test_server.get(&get_user_path).add_query_params(&query_param).await;
+        assert_eq!(response.status_code(), 404);
         let user_response = response.json::<Response>();
         match user_response {
             Response::Error { code , message } => {
@@ -126,6 +98,7 @@ mod tests {
             signature: signature.to_string()
         };
         let response = test_server.get(&get_user_path).add_query_params(&query_param).await;
+        assert_eq!(response.status_code(), 403);
         let user_response = response.json::<Response>();
         match user_response {
             Response::Error { code , message } => {
@@ -136,8 +109,120 @@ mod tests {
                 assert!(false);
             }
         }
-        
+
+        // a correctly signed request for the wrong hash
+        let timestamp = (Utc::now().timestamp_millis() + 1).to_string();
+        let signature = sessionless.sign(format!("{}{}{}", timestamp, &inital_uuid, wrong_hash));
+        let query_param = QueryParams {
+            timestamp,
+            hash: wrong_hash.to_string(),
+            signature: signature.to_string()
+        };
+        let response = test_server.get(&get_user_path).add_query_params(&query_param).await;
+        assert_eq!(response.status_code(), 406);
+        assert!(matches!(response.json::<Response>(), Response::Error { code: 406, .. }));
+
+        // clean up test files
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_get_corrupt_user() {
+        let storage_uri = storage_uri("test_get_corrupt_user");
+        let test_server = setup_test_server(storage_uri.clone());
+        let sessionless = Sessionless::new();
+
+        // a user record that isn't valid JSON
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        tokio::fs::write(format!("{}/user:1234", storage_uri), [0xff, 0xfe]).await.expect("Failed to write file");
+
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let signature = sessionless.sign(format!("{}{}{}", timestamp, "1234", "hash"));
+        let query_param = QueryParams {
+            timestamp,
+            hash: "hash".to_string(),
+            signature: signature.to_string()
+        };
+
+        // reported as a server error rather than a missing user or a crashed worker
+        let response = test_server.get("/user/1234").add_query_params(&query_param).await;
+        assert_eq!(response.status_code(), 500);
+        match response.json::<Response>() {
+            Response::Error { code, message } => {
+                assert_eq!(code, 500);
+                assert_eq!(message, "Corrupt Data");
+            },
+            _ => {
+                panic!("Unexpected response");
+            }
+        }
+
         // clean up test files
         cleanup_test_files(&storage_uri.to_string()).await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_get_user_slot() {
+        let now = Utc::now().timestamp_millis();
+        let storage_uri = storage_uri("test_get_user_slot");
+        let test_server = setup_test_server(storage_uri.clone());
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+
+        // a slot starts out at the hash it is created with
+        let payload = CreateUserRequest {
+            pub_key: pub_key.clone(),
+            timestamp: now.to_string(),
+            hash: "hash".to_string(),
+            signature: sessionless.sign(format!("{}slot:settings:{}{}", now, pub_key, "hash")).to_string(),
+            slot: Some("settings".to_string()),
+        };
+        let response = test_server.post(USER_CREATE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 200);
+        let Response::User { user_uuid: uuid } = response.json::<Response>() else {
+            panic!("Unexpected response");
+        };
+        let get_user_path = format!("/user/{}", uuid);
+
+        let slot_query = |timestamp: i64, slot: &str, hash: &str| QueryParams {
+            timestamp: timestamp.to_string(),
+            hash: hash.to_string(),
+            signature: sessionless.sign(format!("{}slot:{}:{}{}", timestamp, slot, uuid, hash)).to_string(),
+        };
+        let slot = |slot: &str| SlotQuery { slot: Some(slot.to_string()) };
+
+        // updating a slot leaves the user's own hash alone
+        let payload = UpdateHashRequest {
+            user_uuid: uuid.clone(),
+            timestamp: now.to_string(),
+            hash: "hash".to_string(),
+            new_hash: "settings_hash".to_string(),
+            signature: sessionless.sign(format!("{}slot:settings:{}{}{}", now, uuid, "hash", "settings_hash")).to_string(),
+            slot: Some("settings".to_string()),
+        };
+        let response = test_server.put(USER_UPDATE_HASH_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+
+        let response = test_server.get(&get_user_path).add_query_params(slot("settings")).add_query_params(slot_query(now, "settings", "settings_hash")).await;
+        assert_eq!(response.status_code(), 200);
+        let response = test_server.get(&get_user_path).add_query_params(slot("settings")).add_query_params(slot_query(now, "settings", "hash")).await;
+        assert_eq!(response.status_code(), 406);
+
+        // without a slot it is still the user's own hash, and a slot's signature doesn't pass for it
+        let signature = sessionless.sign(format!("{}{}{}", now, uuid, "hash")).to_string();
+        let response = test_server.get(&get_user_path).add_query_params(QueryParams { timestamp: now.to_string(), hash: "hash".to_string(), signature }).await;
+        assert_eq!(response.status_code(), 200);
+        let response = test_server.get(&get_user_path).add_query_params(slot_query(now, "settings", "hash")).await;
+        assert_eq!(response.status_code(), 403);
+
+        // a slot the user doesn't have
+        let response = test_server.get(&get_user_path).add_query_params(slot("progress")).add_query_params(slot_query(now, "progress", "hash")).await;
+        assert_eq!(response.status_code(), 404);
+
+        // slot names are checked
+        let response = test_server.get(&get_user_path).add_query_params(slot("not:a slot")).add_query_params(slot_query(now, "not:a slot", "hash")).await;
+        assert_eq!(response.status_code(), 400);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
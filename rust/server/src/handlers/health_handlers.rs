@@ -0,0 +1,142 @@
+use std::{collections::BTreeMap, sync::Arc, time::{Duration, Instant}};
+
+use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response as AxumResponse}, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppState;
+
+
+// Longest a readiness check waits on storage before calling it unavailable
+static READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DependencyHealth {
+    pub status: HealthStatus,
+    pub backend: String,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// {"status": "ok" | "unavailable", "checks": {<dependency>: {"status", "backend", "latencyMs", "error"}}}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub status: HealthStatus,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<String, DependencyHealth>,
+}
+
+// 200 when ok, 503 when anything is unavailable
+impl IntoResponse for HealthResponse {
+    fn into_response(self) -> AxumResponse {
+        let status = match self.status {
+            HealthStatus::Ok => StatusCode::OK,
+            HealthStatus::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+// The process is up and serving; says nothing about storage
+pub async fn health_live_handler() -> HealthResponse {
+    HealthResponse { status: HealthStatus::Ok, checks: BTreeMap::new() }
+}
+
+// Ready when a value can be written to, read from and deleted from storage
+pub async fn health_ready_handler(State(data): State<Arc<AppState>>) -> HealthResponse {
+    let started = Instant::now();
+    let result = match tokio::time::timeout(READY_TIMEOUT, data.user_service.check_storage()).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err("Timed out".to_string()),
+    };
+
+    let backend = data.user_service.storage_backend();
+    // the detail may name hosts or paths, so it's only logged
+    if let Err(error) = &result {
+        tracing::warn!(backend, error = %error, "Storage is not ready");
+    }
+    let storage = DependencyHealth {
+        status: if result.is_ok() { HealthStatus::Ok } else { HealthStatus::Unavailable },
+        backend: backend.to_string(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        error: result.err().map(|_| "Storage unavailable".to_string()),
+    };
+
+    HealthResponse { status: storage.status, checks: BTreeMap::from([("storage".to_string(), storage)]) }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+
+    use super::*;
+    use crate::test_common::{cleanup_test_files, postgres_test_uri, setup_test_server, storage_uri};
+
+
+    #[tokio::test]
+    async fn test_health_handlers() {
+        let storage_uri = storage_uri("test_health_handlers");
+        let test_server = setup_test_server(storage_uri.clone());
+
+        let response = test_server.get("/health/live").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.json::<serde_json::Value>(), serde_json::json!({"status": "ok"}));
+
+        let response = test_server.get("/health/ready").await;
+        assert_eq!(response.status_code(), 200);
+        let health = response.json::<HealthResponse>();
+        assert_eq!(health.status, HealthStatus::Ok);
+        assert_eq!(health.checks["storage"].status, HealthStatus::Ok);
+        assert_eq!(health.checks["storage"].backend, "file");
+        assert!(health.checks["storage"].error.is_none());
+
+        // the old path only says the server is up
+        let response = test_server.get("/heath_check").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.text(), "Success");
+
+        // the round trip leaves nothing behind
+        let mut dir = tokio::fs::read_dir(storage_uri.to_string()).await.expect("Failed to read storage");
+        assert!(dir.next_entry().await.unwrap().is_none());
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_health_ready_unavailable() {
+        let test_server = setup_test_server(Uri::from_static("http://example.com"));
+
+        let response = test_server.get("/health/ready").await;
+        assert_eq!(response.status_code(), 503);
+        let health = response.json::<HealthResponse>();
+        assert_eq!(health.status, HealthStatus::Unavailable);
+        assert_eq!(health.checks["storage"].backend, "not_implemented");
+        assert_eq!(health.checks["storage"].error.as_deref(), Some("Storage unavailable"));
+
+        // still alive
+        let response = test_server.get("/health/live").await;
+        assert_eq!(response.status_code(), 200);
+        let response = test_server.get("/heath_check").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.text(), "Success");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs POSTGRES_TEST_URI"]
+    async fn test_health_ready_postgres() {
+        // Needs a database, see docker-compose.yml
+        let uri = postgres_test_uri();
+        let test_server = setup_test_server(uri);
+
+        let response = test_server.get("/health/ready").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.json::<HealthResponse>().checks["storage"].backend, "postgres");
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
+
+use crate::{config::AppState, service::Auth};
+
+use super::{Fresh, QueryParams, Response, RollbackRequest};
+
+
+// Lists the user's previous hashes, most recent first, if hash is its current hash.
+// signature message is: timestamp + "history" + uuid + hash
+pub async fn history_handler(
+    State(data): State<Arc<AppState>>,
+    Path(uuid): Path<String>,
+    Fresh(Query(query)): Fresh<Query<QueryParams>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.history(&uuid, &query.hash, auth).await {
+        Ok(user) => Response::history_success(user),
+        Err(e) => e.into(),
+    }
+}
+
+// Goes back to previousHash from the user's history.
+// signature message is: timestamp + "rollback" + userUUID + hash + previousHash
+pub async fn rollback_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Json(body)): Fresh<Json<RollbackRequest>>,
+) -> (StatusCode, Response) {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
+
+    match data.user_service.rollback(&body.user_uuid, &body.hash, &body.previous_hash, auth).await {
+        Ok(user) => (StatusCode::ACCEPTED, Response::user_success(user.uuid)),
+        Err(e) => Response::from(e).with_status(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sessionless::Sessionless;
+
+    use crate::handlers::{QueryParams, RollbackRequest, UpdateHashRequest};
+    use crate::storage::PubKeys;
+    use crate::test_common::{cleanup_test_files, read_keys, read_user, setup_test_server, storage_uri, write_keys, write_user, USER_ROLLBACK_PATH, USER_UPDATE_HASH_PATH};
+
+
+    fn history_query(sessionless: &Sessionless, timestamp: &str, uuid: &str, hash: &str) -> QueryParams {
+        QueryParams {
+            timestamp: timestamp.to_string(),
+            hash: hash.to_string(),
+            signature: sessionless.sign(format!("{}history{}{}", timestamp, uuid, hash)).to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_and_rollback() {
+        let uuid = "1234";
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let history_path = format!("/user/{}/history", uuid);
+
+        let storage_uri = storage_uri("test_history_handlers");
+        let test_server = setup_test_server(storage_uri.clone());
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        assert!(write_user(&storage_uri.to_string(), uuid, &pub_key, "good").await);
+        let mut pub_keys = PubKeys::default();
+        pub_keys.add_user_uuid(uuid, &PubKeys::key("good", &pub_key));
+        assert!(write_keys(&storage_uri.to_string(), &pub_keys).await);
+
+        // a bad state is pushed
+        let payload = UpdateHashRequest {
+            user_uuid: uuid.to_string(),
+            timestamp: timestamp.clone(),
+            hash: "good".to_string(),
+            new_hash: "bad".to_string(),
+            signature: sessionless.sign(format!("{}{}{}{}", timestamp, uuid, "good", "bad")).to_string(),
+            slot: None,
+        };
+        let response = test_server.put(USER_UPDATE_HASH_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+
+        let response = test_server.get(&history_path).add_query_params(history_query(&sessionless, &timestamp, uuid, "bad")).await;
+        assert_eq!(response.status_code(), 200);
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["userUUID"], uuid);
+        assert_eq!(body["hash"], "bad");
+        assert_eq!(body["history"].as_array().map(|history| history.len()), Some(1));
+        assert_eq!(body["history"][0]["hash"], "good");
+        assert!(body["history"][0]["timestamp"].is_i64());
+
+        // and rolled back
+        let payload = RollbackRequest {
+            timestamp: timestamp.clone(),
+            user_uuid: uuid.to_string(),
+            hash: "bad".to_string(),
+            previous_hash: "good".to_string(),
+            signature: sessionless.sign(format!("{}rollback{}{}{}", timestamp, uuid, "bad", "good")).to_string(),
+        };
+        let response = test_server.put(USER_ROLLBACK_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+
+        let user = read_user(&storage_uri.to_string(), uuid).await.expect("Failed to read user");
+        assert_eq!(user.hash, "good");
+        assert_eq!(user.history.iter().map(|record| record.hash.as_str()).collect::<Vec<_>>(), vec!["bad"]);
+
+        // the index points from the hash rolled back to
+        let pub_keys = read_keys(&storage_uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("good", &pub_key)), Some(&uuid.to_string()));
+
+        // a hash the user never had
+        let payload = RollbackRequest {
+            timestamp: timestamp.clone(),
+            user_uuid: uuid.to_string(),
+            hash: "good".to_string(),
+            previous_hash: "never".to_string(),
+            signature: sessionless.sign(format!("{}rollback{}{}{}", timestamp, uuid, "good", "never")).to_string(),
+        };
+        let response = test_server.put(USER_ROLLBACK_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 404);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use axum::{extract::{rejection::JsonRejection, Path, State}, http::StatusCode, Json};
+
+use crate::{config::AppState, magic::{self, Spell, SpellResponse}, service::UserError};
+
+
+
+// Casts a spell forwarded by the resolver (Fount).
+// The caster was authenticated and charged by the resolver, so instead of a user
+// signature the resolver's signature over the spell is verified
+pub async fn magic_spell_handler(
+    State(data): State<Arc<AppState>>,
+    Path(spell_name): Path<String>,
+    body: Result<Json<Spell>, JsonRejection>,
+) -> (StatusCode, Json<SpellResponse>) {
+    let Ok(Json(spell)) = body else {
+        return (StatusCode::BAD_REQUEST, Json(SpellResponse::error("Bad Request")));
+    };
+
+    let Some(resolver_pub_key) = &data.fount_pub_key else {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(SpellResponse::error("No resolver configured")));
+    };
+
+    if spell.spell != spell_name || !spell.verify_resolver(resolver_pub_key) {
+        return (StatusCode::FORBIDDEN, Json(SpellResponse::error("Auth Error")));
+    }
+
+    let checked = match data.user_service.check_fresh(&spell.timestamp) {
+        Ok(()) => data.user_service.check_replay(resolver_pub_key, &spell.resolver_signature, &spell.timestamp).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = checked {
+        let status = match e {
+            UserError::StaleTimestamp => StatusCode::BAD_REQUEST,
+            UserError::Replayed => StatusCode::FORBIDDEN,
+            _ => return (StatusCode::INTERNAL_SERVER_ERROR, Json(SpellResponse::error("Failed to check for replayed request"))),
+        };
+        return (status, Json(SpellResponse::error(e.to_string())));
+    }
+
+    match magic::cast(&data.user_service, &spell).await {
+        Some(response) => (StatusCode::OK, Json(response)),
+        None => (StatusCode::NOT_FOUND, Json(SpellResponse::error("Unknown spell"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_test::TestServer;
+    use chrono::Utc;
+    use serde_json::{json, Value};
+    use sessionless::Sessionless;
+
+    use crate::magic::{Spell, SpellResponse, SpellUser};
+    use crate::test_common::{cleanup_test_files, storage_uri, TestServerBuilder};
+
+    fn spell(resolver: &Sessionless, name: &str, components: Value) -> Spell {
+        let mut spell = Spell {
+            spell: name.to_string(),
+            caster_uuid: Sessionless::generate_uuid().to_string(),
+            timestamp: Utc::now().timestamp_millis().to_string(),
+            total_cost: 50,
+            mp: true,
+            ordinal: 0,
+            caster_signature: None,
+            resolver_signature: "".to_string(),
+            components,
+        };
+        spell.resolver_signature = resolver.sign(spell.message()).to_string();
+        spell
+    }
+
+    async fn cast(test_server: &TestServer, spell: &Spell) -> (u16, SpellResponse) {
+        let response = test_server.post(&format!("/magic/spell/{}", spell.spell)).json(spell).await;
+        (response.status_code().as_u16(), response.json::<SpellResponse>())
+    }
+
+    #[tokio::test]
+    async fn test_user_spells() {
+        let storage_uri = storage_uri("test_user_spells");
+        let resolver = Sessionless::new();
+        let test_server = TestServerBuilder::new(storage_uri.clone()).fount_pub_key(&resolver.public_key().to_string()).build();
+
+        let pub_key = Sessionless::new().public_key().to_string();
+
+        // create
+        let create = spell(&resolver, "continuebeeUserCreate", json!({"pubKey": pub_key, "hash": "firstHash"}));
+        let user = match cast(&test_server, &create).await {
+            (200, SpellResponse::User { success: true, user }) => user,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(user.uuid.len(), 36);
+        assert_eq!(user, SpellUser { uuid: user.uuid.clone(), pub_key: pub_key.clone(), hash: "firstHash".to_string() });
+
+        // the same pubKey and hash returns the existing user
+        let create = spell(&resolver, "continuebeeUserCreate", json!({"pubKey": pub_key, "hash": "firstHash"}));
+        match cast(&test_server, &create).await {
+            (200, SpellResponse::User { user: existing, .. }) => assert_eq!(existing, user),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // update with the wrong current hash
+        let update = spell(&resolver, "continuebeeUserUpdateHash", json!({"userUUID": user.uuid, "hash": "wrongHash", "newHash": "secondHash"}));
+        match cast(&test_server, &update).await {
+            (200, SpellResponse::Error { success: false, error }) => assert_eq!(error, "Current hash does not match"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // update
+        let update = spell(&resolver, "continuebeeUserUpdateHash", json!({"userUUID": user.uuid, "hash": "firstHash", "newHash": "secondHash"}));
+        match cast(&test_server, &update).await {
+            (200, SpellResponse::User { success: true, user: updated }) => assert_eq!(updated.hash, "secondHash"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // delete with the old hash
+        let delete = spell(&resolver, "continuebeeUserDelete", json!({"userUUID": user.uuid, "hash": "firstHash"}));
+        match cast(&test_server, &delete).await {
+            (200, SpellResponse::Error { success: false, error }) => assert_eq!(error, "Hash does not match"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // delete
+        let delete = spell(&resolver, "continuebeeUserDelete", json!({"userUUID": user.uuid, "hash": "secondHash"}));
+        let response = test_server.post("/magic/spell/continuebeeUserDelete").json(&delete).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.json::<Value>(), json!({"success": true}));
+
+        // and it is gone
+        let delete = spell(&resolver, "continuebeeUserDelete", json!({"userUUID": user.uuid, "hash": "secondHash"}));
+        match cast(&test_server, &delete).await {
+            (200, SpellResponse::Error { success: false, error }) => assert_eq!(error, "User not found"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_missing_components() {
+        let storage_uri = storage_uri("test_spell_missing_components");
+        let resolver = Sessionless::new();
+        let test_server = TestServerBuilder::new(storage_uri.clone()).fount_pub_key(&resolver.public_key().to_string()).build();
+
+        let cases = [
+            ("continuebeeUserCreate", json!({"hash": "hash"}), "Missing required fields: pubKey, hash"),
+            ("continuebeeUserCreate", json!({"pubKey": "pub_key"}), "Missing required fields: pubKey, hash"),
+            ("continuebeeUserUpdateHash", json!({"userUUID": "1234", "hash": "hash"}), "Missing required fields: userUUID, hash, newHash"),
+            ("continuebeeUserDelete", json!({"userUUID": "1234"}), "Missing required fields: userUUID, hash"),
+        ];
+        for (name, components, expected) in cases {
+            match cast(&test_server, &spell(&resolver, name, components)).await {
+                (200, SpellResponse::Error { success: false, error }) => assert_eq!(error, expected),
+                other => panic!("Unexpected response {:?}", other),
+            }
+        }
+
+        // nothing was written
+        assert!(tokio::fs::metadata(storage_uri.to_string()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolver_auth() {
+        let storage_uri = storage_uri("test_spell_resolver_auth");
+        let resolver = Sessionless::new();
+        let test_server = TestServerBuilder::new(storage_uri.clone()).fount_pub_key(&resolver.public_key().to_string()).build();
+        let components = json!({"pubKey": "pub_key", "hash": "hash"});
+
+        // signed by someone other than the resolver
+        let forged = spell(&Sessionless::new(), "continuebeeUserCreate", components.clone());
+        match cast(&test_server, &forged).await {
+            (403, SpellResponse::Error { error, .. }) => assert_eq!(error, "Auth Error"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // cast on another spell's route
+        let create = spell(&resolver, "continuebeeUserCreate", components.clone());
+        let response = test_server.post("/magic/spell/continuebeeUserDelete").json(&create).await;
+        assert_eq!(response.status_code(), 403);
+
+        // a spell continuebee doesn't know
+        let unknown = spell(&resolver, "continuebeeUserRename", components.clone());
+        match cast(&test_server, &unknown).await {
+            (404, SpellResponse::Error { error, .. }) => assert_eq!(error, "Unknown spell"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // the same forwarded spell is only cast once
+        let create = spell(&resolver, "continuebeeUserCreate", components.clone());
+        assert_eq!(cast(&test_server, &create).await.0, 200);
+        match cast(&test_server, &create).await {
+            (403, SpellResponse::Error { error, .. }) => assert_eq!(error, "Replayed Request"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // stale
+        let mut stale = spell(&resolver, "continuebeeUserCreate", components.clone());
+        stale.timestamp = (Utc::now().timestamp_millis() - 3_600_000).to_string();
+        stale.resolver_signature = resolver.sign(stale.message()).to_string();
+        match cast(&test_server, &stale).await {
+            (400, SpellResponse::Error { error, .. }) => assert_eq!(error, "Stale Timestamp"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // not a spell
+        let response = test_server.post("/magic/spell/continuebeeUserCreate").json(&json!({"spell": "continuebeeUserCreate"})).await;
+        assert_eq!(response.status_code(), 400);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_no_resolver_configured() {
+        let test_server = crate::test_common::setup_test_server(storage_uri("test_spell_no_resolver"));
+        let spell = spell(&Sessionless::new(), "continuebeeUserCreate", json!({"pubKey": "pub_key", "hash": "hash"}));
+
+        assert_eq!(cast(&test_server, &spell).await.0, 503);
+    }
+}
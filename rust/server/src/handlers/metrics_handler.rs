@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use axum::{extract::State, http::header, response::{IntoResponse, Response as AxumResponse}};
+
+use crate::{config::AppState, metrics::metrics};
+
+
+
+// Prometheus metrics; the user and pub_key counts keep their last value when storage can't be counted
+pub async fn metrics_handler(State(data): State<Arc<AppState>>) -> AxumResponse {
+    if let Ok(stats) = data.user_service.stats().await {
+        metrics().set_counts(stats.users, stats.pub_keys);
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics().render()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use sessionless::Sessionless;
+
+    use crate::test_common::{cleanup_test_files, setup_test_server, storage_uri, write_keys, write_user, TestServerBuilder};
+    use crate::storage::PubKeys;
+
+
+    #[tokio::test]
+    async fn test_metrics_handler() {
+        let storage_uri = storage_uri("test_metrics_handler");
+        let test_server = setup_test_server(storage_uri.clone());
+        let metrics_server = TestServerBuilder::new(storage_uri.clone()).build_metrics();
+
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        let mut pub_keys = PubKeys::default();
+        for uuid in ["1234", "5678"] {
+            let pub_key = Sessionless::new().public_key().to_string();
+            assert!(write_user(&storage_uri.to_string(), uuid, &pub_key, "hash").await);
+            pub_keys.add_user_uuid(uuid, &PubKeys::key("hash", &pub_key));
+        }
+        assert!(write_keys(&storage_uri.to_string(), &pub_keys).await);
+
+        let response = test_server.get("/heath_check").await;
+        assert_eq!(response.status_code(), 200);
+        // a wrong hash for a user that exists
+        let response = test_server.get("/user/1234").add_query_params(serde_json::json!({"timestamp": "0", "hash": "wrong", "signature": "bad"})).await;
+        assert_ne!(response.status_code(), 200);
+
+        // not on the public port
+        let response = test_server.get("/metrics").await;
+        assert_eq!(response.status_code(), 404);
+
+        let response = metrics_server.get("/metrics").await;
+        assert_eq!(response.status_code(), 200);
+        let body = response.text();
+        assert!(body.contains(r#"continuebee_http_requests_total{method="GET",route="/heath_check",status="200"}"#));
+        assert!(body.contains(r#"route="/user/{uuid}""#));
+        assert!(body.contains(r#"continuebee_storage_operation_duration_seconds_count{backend="file",operation="get"}"#));
+        assert!(body.contains("continuebee_users 2"));
+        assert!(body.contains("continuebee_pub_keys 2"));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
@@ -1,15 +1,33 @@
 mod request;
 mod response;
 mod query;
+mod fresh;
 mod create_user_handler;
 mod get_user_handler;
 mod update_hash_handler;
 mod delete_user_handler;
+mod rotate_key_handler;
+mod device_key_handlers;
+mod state_handlers;
+mod history_handlers;
+mod magic_spell_handler;
+mod metrics_handler;
+mod health_handlers;
+mod admin_handlers;
 
 pub use request::*;
 pub use response::*;
 pub use query::*;
+pub use fresh::*;
 pub use create_user_handler::*;
 pub use get_user_handler::*;
 pub use update_hash_handler::*;
-pub use delete_user_handler::*;
\ No newline at end of file
+pub use delete_user_handler::*;
+pub use rotate_key_handler::*;
+pub use device_key_handlers::*;
+pub use state_handlers::*;
+pub use history_handlers::*;
+pub use magic_spell_handler::*;
+pub use metrics_handler::*;
+pub use health_handlers::*;
+pub use admin_handlers::*;
\ No newline at end of file
@@ -6,4 +6,40 @@ pub struct QueryParams {
     pub timestamp: String,
     pub hash: String,
     pub signature: String,
-}
\ No newline at end of file
+}
+
+// The optional slot of a user GET, kept apart from QueryParams which other GETs share
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SlotQuery {
+    pub slot: Option<String>,
+}
+
+// Signed by an operator; the signature message is the timestamp followed by the operation's tag
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OperatorQuery {
+    pub timestamp: String,
+    pub signature: String,
+}
+
+// An operator's audit log query, paged by sequence and optionally for one user
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuditQuery {
+    pub timestamp: String,
+    pub signature: String,
+    #[serde(rename = "userUUID", default, skip_serializing_if = "Option::is_none")]
+    pub user_uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+// An operator's look into the pub_key + hash index
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyQuery {
+    pub timestamp: String,
+    pub signature: String,
+    pub pub_key: String,
+    pub hash: String,
+}
@@ -7,23 +7,105 @@ pub struct CreateUserRequest {
     pub hash: String,
     pub timestamp: String,
     pub signature: String,
+    // a named slot of the user's, the user's own hash when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateHashRequest {
+    #[serde(rename = "userUUID")]
     pub user_uuid: String,
     pub timestamp: String,
     pub hash: String,
     pub new_hash: String,
     pub signature: String,
+    // a named slot of the user's, the user's own hash when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeleteUserRequest {
     pub timestamp: String,
+    #[serde(rename = "userUUID")]
     pub user_uuid: String,
     pub hash: String,
     pub signature: String,
-}
\ No newline at end of file
+    // a named slot of the user's, the user's own hash when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RotateKeyRequest {
+    pub timestamp: String,
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    pub hash: String,
+    pub new_pub_key: String,
+    // by the current key
+    pub signature: String,
+    // by new_pub_key, over the same message
+    pub new_signature: String,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddKeyRequest {
+    pub timestamp: String,
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    pub hash: String,
+    pub new_pub_key: String,
+    // by one of the user's keys
+    pub signature: String,
+    // by new_pub_key, over the same message
+    pub new_signature: String,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeKeyRequest {
+    pub timestamp: String,
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    pub hash: String,
+    // the key to revoke
+    pub pub_key: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutStateRequest {
+    pub timestamp: String,
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    pub hash: String,
+    // encrypted by the client, stored as is
+    pub state: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RollbackRequest {
+    pub timestamp: String,
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    pub hash: String,
+    // one of the hashes from the user's history
+    pub previous_hash: String,
+    pub signature: String,
+}
+
+// Signed by an operator; the signature message is the timestamp followed by the operation's tag
+#[derive(Deserialize, Debug, Serialize)]
+pub struct OperatorRequest {
+    pub timestamp: String,
+    pub signature: String,
+}
@@ -1,35 +1,300 @@
-use axum::http::StatusCode;
+use axum::{http::StatusCode, response::IntoResponse, Json};
 use serde::{Deserialize, Serialize};
 
+use crate::{service::UserError, storage::{AuditRecord, ChainCheck, HashRecord, KeyCheck, Lockout, StorageError, User, UserState, UserStats}};
+
+// Bodies match the README:
+// {"userUUID": <uuid>}, {"code": "<status>", "message": <message>} and {"success": true},
+// plus {"userUUID", "hash", "state", "version"} for saved state,
+// {"userUUID", "hash", "history": [{"hash", "timestamp"}]} for hash history,
+// {"code": "409", "message": "Conflict", "hash": <current hash>} for a lost update and
+// {"records": [...]} or {"intact", "records", "brokenAt", "reason"} for the audit log and
+// {"user", "lockout"}, {"userUUID", "consistent"} or {"users", "pubKeys"} for operators
 #[derive(Debug, Serialize, Clone, Deserialize)]
+#[serde(untagged)]
 pub enum Response {
-    User { user_uuid: String },
-    Error { code: u16, message: String },
-    Success { code: u16 }
+    // Before User, which would match any body with a userUUID
+    State {
+        #[serde(rename = "userUUID")]
+        user_uuid: String,
+        hash: String,
+        state: String,
+        version: u64,
+    },
+    History {
+        #[serde(rename = "userUUID")]
+        user_uuid: String,
+        hash: String,
+        history: Vec<HashRecord>,
+    },
+    Audit {
+        records: Vec<AuditRecord>,
+    },
+    AuditCheck(ChainCheck),
+    // The user as stored
+    UserRecord {
+        user: User,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lockout: Option<Lockout>,
+    },
+    // Before User, which would match it without consistent
+    KeyCheck(KeyCheck),
+    Stats(UserStats),
+    User {
+        #[serde(rename = "userUUID")]
+        user_uuid: String
+    },
+    // Before Error, which would match it without the hash
+    HashConflict {
+        #[serde(with = "code_string")]
+        code: u16,
+        message: String,
+        // the current hash, which the update has to start from
+        hash: String,
+    },
+    Error {
+        #[serde(with = "code_string")]
+        code: u16,
+        message: String
+    },
+    Success { success: bool }
+}
+
+// The README documents error codes as strings
+mod code_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(code: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&code.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 impl Response {
+    #[allow(clippy::needless_return)]
     pub fn auth_error() -> Self {
         return Response::Error { code: StatusCode::FORBIDDEN.as_u16(), message: "Auth Error".to_string() };
     }
 
+    #[allow(clippy::needless_return, clippy::redundant_field_names)]
     pub fn user_success(user_uuid: String) -> Self {
         return Response::User { user_uuid: user_uuid }
     }
 
+    pub fn state_success(user_uuid: String, state: UserState) -> Self {
+        Response::State { user_uuid, hash: state.hash, state: state.state, version: state.version }
+    }
+
+    pub fn history_success(user: User) -> Self {
+        Response::History { user_uuid: user.uuid, hash: user.hash, history: user.history }
+    }
+
+    pub fn audit_success(records: Vec<AuditRecord>) -> Self {
+        Response::Audit { records }
+    }
+
+    pub fn audit_check(check: ChainCheck) -> Self {
+        Response::AuditCheck(check)
+    }
+
+    pub fn user_record(user: User, lockout: Option<Lockout>) -> Self {
+        Response::UserRecord { user, lockout }
+    }
+
+    pub fn key_check(check: KeyCheck) -> Self {
+        Response::KeyCheck(check)
+    }
+
+    pub fn stats(stats: UserStats) -> Self {
+        Response::Stats(stats)
+    }
+
+    pub fn bad_request() -> Self {
+        Response::Error { code: StatusCode::BAD_REQUEST.as_u16(), message: "Bad Request".to_string() }
+    }
+
+    #[allow(clippy::needless_return, clippy::redundant_field_names)]
     pub fn server_error(message: String) -> Self {
         return Response::Error { code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(), message: message };
     }
 
+    #[allow(clippy::needless_return)]
     pub fn not_found() -> Self {
         return Response::Error { code: StatusCode::NOT_FOUND.as_u16(), message: "Not Found".to_string() };
     }
 
+    // A concurrent change won; the request can be retried
+    pub fn conflict() -> Self {
+        Response::Error { code: StatusCode::CONFLICT.as_u16(), message: "Conflict".to_string() }
+    }
+
+    // Revoking the key would leave the user without any
+    pub fn last_key() -> Self {
+        Response::Error { code: StatusCode::CONFLICT.as_u16(), message: "Cannot revoke the last key".to_string() }
+    }
+
+    pub fn payload_too_large() -> Self {
+        Response::Error { code: StatusCode::PAYLOAD_TOO_LARGE.as_u16(), message: "Payload Too Large".to_string() }
+    }
+
+    // The update started from a hash that is no longer current
+    pub fn hash_conflict(hash: String) -> Self {
+        Response::HashConflict { code: StatusCode::CONFLICT.as_u16(), message: "Conflict".to_string(), hash }
+    }
+
+    // Too many hash mismatches for the user; checks resume after the cool-down
+    pub fn locked() -> Self {
+        Response::Error { code: StatusCode::LOCKED.as_u16(), message: "Locked".to_string() }
+    }
+
+    pub fn too_many_requests() -> Self {
+        Response::Error { code: StatusCode::TOO_MANY_REQUESTS.as_u16(), message: "Too Many Requests".to_string() }
+    }
+
+    pub fn storage_unavailable() -> Self {
+        Response::Error { code: StatusCode::SERVICE_UNAVAILABLE.as_u16(), message: "Storage Unavailable".to_string() }
+    }
+
     pub fn not_acceptable() -> Self {
-        return Response::Error { code: StatusCode::NOT_ACCEPTABLE.as_u16(), message: "Not Acceptable".to_string() };
+        Response::Error { code: StatusCode::NOT_ACCEPTABLE.as_u16(), message: "Not acceptable".to_string() }
+    }
+
+    // The request timestamp is outside the allowed time difference
+    pub fn stale_timestamp() -> Self {
+        Response::Error { code: StatusCode::BAD_REQUEST.as_u16(), message: "Stale Timestamp".to_string() }
+    }
+
+    // The same signed request was already accepted
+    pub fn replayed() -> Self {
+        Response::Error { code: StatusCode::FORBIDDEN.as_u16(), message: "Replayed Request".to_string() }
+    }
+
+    pub fn success() -> Self {
+        Response::Success { success: true }
+    }
+}
+
+impl From<UserError> for Response {
+    fn from(error: UserError) -> Self {
+        match &error {
+            UserError::Storage(e) => tracing::error!(error = %e, "User operation failed"),
+            e => tracing::debug!(error = %e, "User operation refused"),
+        }
+
+        match error {
+            UserError::Auth => Response::auth_error(),
+            UserError::StaleTimestamp => Response::stale_timestamp(),
+            UserError::Replayed => Response::replayed(),
+            UserError::NotFound | UserError::KeyNotFound | UserError::NoState | UserError::NotInHistory | UserError::SlotNotFound => Response::not_found(),
+            UserError::InvalidSlot => Response::bad_request(),
+            UserError::HashMismatch => Response::not_acceptable(),
+            UserError::HashConflict(hash) => Response::hash_conflict(hash),
+            UserError::LastKey => Response::last_key(),
+            UserError::StateTooLarge => Response::payload_too_large(),
+            UserError::LockedOut => Response::locked(),
+            UserError::Storage(StorageError::Conflict(_)) => Response::conflict(),
+            UserError::Storage(StorageError::Io(_)) => Response::storage_unavailable(),
+            // a record that exists but can't be read, or an index entry missing under a user
+            UserError::Storage(StorageError::Corrupt { .. } | StorageError::NotFound(_)) => {
+                Response::server_error("Corrupt Data".to_string())
+            },
+        }
+    }
+}
+
+impl Response {
+    // Errors are sent with the code in their body, everything else with 200.
+    // Handlers answering a change with 202 send (StatusCode::ACCEPTED, response) instead
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Response::Error { code, .. } | Response::HashConflict { code, .. } => {
+                StatusCode::from_u16(*code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+            },
+            _ => StatusCode::OK,
+        }
+    }
+
+    // The response along with its own status, for handlers that send another one on success
+    pub fn with_status(self) -> (StatusCode, Self) {
+        (self.status(), self)
     }
+}
 
-    pub fn success(code: u16) -> Self {
-        return Response::Success { code: code };
+impl IntoResponse for Response {
+    fn into_response(self) -> axum::response::Response {
+        (self.status(), Json(self)).into_response()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        let status = |response: Response| response.into_response().status().as_u16();
+
+        assert_eq!(status(Response::user_success("1234".to_string())), 200);
+        assert_eq!((StatusCode::ACCEPTED, Response::user_success("1234".to_string())).into_response().status().as_u16(), 202);
+        assert_eq!(status(Response::success()), 200);
+        assert_eq!(status(Response::bad_request()), 400);
+        assert_eq!(status(Response::stale_timestamp()), 400);
+        assert_eq!(status(Response::auth_error()), 403);
+        assert_eq!(status(Response::replayed()), 403);
+        assert_eq!(status(Response::not_found()), 404);
+        assert_eq!(status(Response::not_acceptable()), 406);
+        assert_eq!(status(Response::conflict()), 409);
+        assert_eq!(status(Response::last_key()), 409);
+        assert_eq!(status(Response::payload_too_large()), 413);
+        assert_eq!(status(Response::locked()), 423);
+        assert_eq!(status(Response::too_many_requests()), 429);
+        assert_eq!(status(Response::hash_conflict("hash".to_string())), 409);
+        assert_eq!(status(Response::server_error("error".to_string())), 500);
+        assert_eq!(status(Response::storage_unavailable()), 503);
+        // a body code that isn't a status is still an error on the wire
+        assert_eq!(status(Response::Error { code: 42, message: "error".to_string() }), 500);
+    }
+
+    #[test]
+    fn test_user_errors() {
+        let status = |error: UserError| Response::from(error).into_response().status().as_u16();
+
+        assert_eq!(status(UserError::NotFound), 404);
+        assert_eq!(status(UserError::HashMismatch), 406);
+        assert_eq!(status(UserError::KeyNotFound), 404);
+        assert_eq!(status(UserError::LastKey), 409);
+        assert_eq!(status(UserError::NoState), 404);
+        assert_eq!(status(UserError::StateTooLarge), 413);
+        assert_eq!(status(UserError::NotInHistory), 404);
+        assert_eq!(status(UserError::SlotNotFound), 404);
+        assert_eq!(status(UserError::InvalidSlot), 400);
+        assert_eq!(status(UserError::Storage(StorageError::Conflict("user:1234".to_string()))), 409);
+        assert_eq!(status(UserError::Storage(StorageError::corrupt("user:1234", "expected value"))), 500);
+        assert_eq!(status(UserError::Storage(StorageError::Io(std::io::Error::other("disk full")))), 503);
+    }
+
+    #[test]
+    fn test_serialized_shapes() {
+        let json = |response: Response| serde_json::to_value(response).unwrap();
+
+        assert_eq!(json(Response::user_success("1234".to_string())), serde_json::json!({"userUUID": "1234"}));
+        assert_eq!(json(Response::not_acceptable()), serde_json::json!({"code": "406", "message": "Not acceptable"}));
+        assert_eq!(json(Response::hash_conflict("hash".to_string())), serde_json::json!({"code": "409", "message": "Conflict", "hash": "hash"}));
+        assert_eq!(json(Response::success()), serde_json::json!({"success": true}));
+
+        let state = UserState { hash: "hash".to_string(), state: "encrypted".to_string(), version: 2 };
+        assert_eq!(json(Response::state_success("1234".to_string(), state)), serde_json::json!({"userUUID": "1234", "hash": "hash", "state": "encrypted", "version": 2}));
+
+        let check = KeyCheck { user_uuid: "1234".to_string(), consistent: false };
+        assert_eq!(json(Response::key_check(check)), serde_json::json!({"userUUID": "1234", "consistent": false}));
+        assert_eq!(json(Response::stats(UserStats { users: 1, pub_keys: 2 })), serde_json::json!({"users": 1, "pubKeys": 2}));
+        let key_check: Response = serde_json::from_value(serde_json::json!({"userUUID": "1234", "consistent": true})).unwrap();
+        assert!(matches!(key_check, Response::KeyCheck(KeyCheck { consistent: true, .. })));
+
+        let error: Response = serde_json::from_value(serde_json::json!({"code": "403", "message": "Auth Error"})).unwrap();
+        assert!(matches!(error, Response::Error { code: 403, .. }));
+    }
+}
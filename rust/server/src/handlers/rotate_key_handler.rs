@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Json};
+
+use crate::{config::AppState, service::Auth};
+
+use super::{Fresh, Response, RotateKeyRequest};
+
+
+// Moves the user to newPubKey if hash is the current one.
+// signature (by the current key) and newSignature (by newPubKey) are both over:
+// timestamp + "rotateKey" + userUUID + hash + newPubKey
+pub async fn rotate_key_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Json(body)): Fresh<Json<RotateKeyRequest>>,
+) -> (StatusCode, Response) {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
+
+    match data.user_service.rotate_key(&body.user_uuid, &body.hash, &body.new_pub_key, &body.new_signature, auth).await {
+        Ok(user) => (StatusCode::ACCEPTED, Response::user_success(user.uuid)),
+        Err(e) => Response::from(e).with_status(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sessionless::Sessionless;
+
+    use crate::handlers::{QueryParams, Response, RotateKeyRequest};
+    use crate::storage::PubKeys;
+    use crate::test_common::{cleanup_test_files, read_keys, read_user, setup_test_server, storage_uri, write_keys, write_user, USER_ROTATE_KEY_PATH};
+
+
+    fn rotate_key_request(old: &Sessionless, new: &Sessionless, timestamp: &str, uuid: &str, hash: &str) -> RotateKeyRequest {
+        let new_pub_key = new.public_key().to_string();
+        let message = format!("{}rotateKey{}{}{}", timestamp, uuid, hash, new_pub_key);
+        RotateKeyRequest {
+            timestamp: timestamp.to_string(),
+            user_uuid: uuid.to_string(),
+            hash: hash.to_string(),
+            new_pub_key,
+            signature: old.sign(&message).to_string(),
+            new_signature: new.sign(&message).to_string(),
+        }
+    }
+
+    fn get_query(sessionless: &Sessionless, timestamp: &str, uuid: &str, hash: &str) -> QueryParams {
+        QueryParams {
+            timestamp: timestamp.to_string(),
+            hash: hash.to_string(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, uuid, hash)).to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key() {
+        let uuid = "1234";
+        let hash = "hash";
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let get_user_path = format!("/user/{}", uuid);
+
+        let storage_uri = storage_uri("test_rotate_key_handler");
+        let test_server = setup_test_server(storage_uri.clone());
+
+        let old = Sessionless::new();
+        let new = Sessionless::new();
+        let old_pub_key = old.public_key().to_string();
+        let new_pub_key = new.public_key().to_string();
+
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        assert!(write_user(&storage_uri.to_string(), uuid, &old_pub_key, hash).await);
+        let mut pub_keys = PubKeys::default();
+        pub_keys.add_user_uuid(uuid, &PubKeys::key(hash, &old_pub_key));
+        assert!(write_keys(&storage_uri.to_string(), &pub_keys).await);
+
+        // without the new key's signature
+        let mut payload = rotate_key_request(&old, &new, &timestamp, uuid, hash);
+        payload.new_signature = payload.signature.clone();
+        let response = test_server.put(USER_ROTATE_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 403);
+
+        let payload = rotate_key_request(&old, &new, &timestamp, uuid, hash);
+        let response = test_server.put(USER_ROTATE_KEY_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+        assert!(matches!(response.json::<Response>(), Response::User { user_uuid } if user_uuid == uuid));
+
+        // the user and its index entry moved to the new key
+        let user = read_user(&storage_uri.to_string(), uuid).await.expect("Failed to read user");
+        assert_eq!(user.pub_key, new_pub_key);
+        let pub_keys = read_keys(&storage_uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key(hash, &new_pub_key)), Some(&uuid.to_string()));
+
+        // the old key no longer verifies
+        let response = test_server.get(&get_user_path).add_query_params(get_query(&old, &timestamp, uuid, hash)).await;
+        assert_eq!(response.status_code(), 403);
+
+        // the new one does
+        let response = test_server.get(&get_user_path).add_query_params(get_query(&new, &timestamp, uuid, hash)).await;
+        assert_eq!(response.status_code(), 200);
+        assert!(matches!(response.json::<Response>(), Response::User { user_uuid } if user_uuid == uuid));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
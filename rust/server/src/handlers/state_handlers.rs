@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
+
+use crate::{config::AppState, service::Auth};
+
+use super::{Fresh, PutStateRequest, QueryParams, Response};
+
+
+// Returns the state last saved for the user, if hash is its current hash.
+// signature message is: timestamp + "getState" + uuid + hash
+pub async fn get_state_handler(
+    State(data): State<Arc<AppState>>,
+    Path(uuid): Path<String>,
+    Fresh(Query(query)): Fresh<Query<QueryParams>>,
+) -> Response {
+    let auth = Auth::Signed { timestamp: &query.timestamp, signature: &query.signature };
+
+    match data.user_service.get_state(&uuid, &query.hash, auth).await {
+        Ok(state) => Response::state_success(uuid, state),
+        Err(e) => e.into(),
+    }
+}
+
+// Saves the client encrypted state alongside the user's current hash.
+// signature message is: timestamp + "putState" + userUUID + hash + state
+pub async fn put_state_handler(
+    State(data): State<Arc<AppState>>,
+    Fresh(Json(body)): Fresh<Json<PutStateRequest>>,
+) -> (StatusCode, Response) {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
+
+    match data.user_service.put_state(&body.user_uuid, &body.hash, &body.state, auth).await {
+        Ok(state) => (StatusCode::ACCEPTED, Response::state_success(body.user_uuid, state)),
+        Err(e) => Response::from(e).with_status(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sessionless::Sessionless;
+
+    use crate::handlers::{PutStateRequest, QueryParams, Response};
+    use crate::test_common::{cleanup_test_files, setup_test_server, storage_uri, write_user, MAX_STATE_SIZE, USER_STATE_PATH};
+
+
+    fn put_state_request(sessionless: &Sessionless, timestamp: &str, uuid: &str, hash: &str, state: &str) -> PutStateRequest {
+        PutStateRequest {
+            timestamp: timestamp.to_string(),
+            user_uuid: uuid.to_string(),
+            hash: hash.to_string(),
+            state: state.to_string(),
+            signature: sessionless.sign(format!("{}putState{}{}{}", timestamp, uuid, hash, state)).to_string(),
+        }
+    }
+
+    fn get_state_query(sessionless: &Sessionless, timestamp: &str, uuid: &str, hash: &str) -> QueryParams {
+        QueryParams {
+            timestamp: timestamp.to_string(),
+            hash: hash.to_string(),
+            signature: sessionless.sign(format!("{}getState{}{}", timestamp, uuid, hash)).to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_handlers() {
+        let uuid = "1234";
+        let now = Utc::now().timestamp_millis();
+        let get_state_path = format!("/user/{}/state", uuid);
+
+        let storage_uri = storage_uri("test_state_handlers");
+        let test_server = setup_test_server(storage_uri.clone());
+
+        let sessionless = Sessionless::new();
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        assert!(write_user(&storage_uri.to_string(), uuid, &sessionless.public_key().to_string(), "hash").await);
+
+        // nothing saved yet
+        let response = test_server.get(&get_state_path).add_query_params(get_state_query(&sessionless, &now.to_string(), uuid, "hash")).await;
+        assert_eq!(response.status_code(), 404);
+
+        let payload = put_state_request(&sessionless, &now.to_string(), uuid, "hash", "encrypted");
+        let response = test_server.put(USER_STATE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
+        assert!(matches!(response.json::<Response>(), Response::State { version: 1, .. }));
+
+        // a read isn't a replay, the same signed query now finds the state
+        let response = test_server.get(&get_state_path).add_query_params(get_state_query(&sessionless, &now.to_string(), uuid, "hash")).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            serde_json::json!({"userUUID": uuid, "hash": "hash", "state": "encrypted", "version": 1})
+        );
+
+        // signed by another key
+        let response = test_server.get(&get_state_path).add_query_params(get_state_query(&Sessionless::new(), &now.to_string(), uuid, "hash")).await;
+        assert_eq!(response.status_code(), 403);
+
+        // over the configured size
+        let payload = put_state_request(&sessionless, &now.to_string(), uuid, "hash", &"x".repeat(MAX_STATE_SIZE + 1));
+        let response = test_server.put(USER_STATE_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 413);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
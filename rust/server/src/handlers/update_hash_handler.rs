@@ -1,64 +1,27 @@
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
-use axum::{extract::State, Json};
-use sessionless::{Sessionless, Signature};
+use axum::{extract::State, http::StatusCode, Json};
 
-use crate::{config::AppState, storage::PubKeys};
+use crate::{config::AppState, service::Auth};
 
-use super::{Response, UpdateHashRequest};
+use super::{Fresh, Response, UpdateHashRequest};
 
 
+// Replaces the user's hash if hash is the current one.
+// signature message is: timestamp + userUUID + hash + newHash
 pub async fn update_hash_handler(
     State(data): State<Arc<AppState>>,
-    Json(body): Json<UpdateHashRequest>,
-) -> Json<Response> {
-    let message = format!("{}{}{}{}", body.timestamp, body.user_uuid, body.hash, body.new_hash);
-    let sessionless = Sessionless::new();
-
-    let sig = match Signature::from_str(body.signature.as_str()) {
-        Ok(s) => s,
-        Err(_) => {
-            return Json(Response::auth_error());
-        }
-    };
-
-    let found_user = match data.user_client.clone().get_user(&body.user_uuid).await {
-        Some(user) => user,
-        None => {
-            return Json(Response::not_found());
-        }
-    };
-
-    let pub_key = match found_user.pub_key() {
-        Ok(key) => key,
-        Err(_) => {
-            return Json(Response::auth_error());
-        }
-    };
-
-    if sessionless.verify(message, &pub_key, &sig).is_err() {
-        return Json(Response::auth_error());
-    }
+    Fresh(Json(body)): Fresh<Json<UpdateHashRequest>>,
+) -> (StatusCode, Response) {
+    let auth = Auth::Signed { timestamp: &body.timestamp, signature: &body.signature };
 
-    match data.user_client.put_user(&found_user.uuid, &pub_key.to_string(), &body.new_hash).await {
-        Ok(new_user) => {
-            // Need to update the pub keys map with the new hash
-            let old_key = PubKeys::key(&body.hash, &pub_key.to_string());
-            if let Ok(_) = data.user_client.remove_key(&old_key).await {
-                let new_key = PubKeys::key(&body.new_hash, &pub_key.to_string());
-                if data.user_client.update_keys(&new_key, &new_user.uuid).await.is_err() {
-                    return Json(Response::server_error("Failed to update keys".to_string()));
-                }             
-            } else {
-                return Json(Response::server_error("Failed to delete old key".to_string()));
-            }
-            Json(Response::user_success(new_user.uuid))
-        },
-        Err(_) => Json(Response::server_error("Failed to update hash".to_string()))
+    match data.user_service.update_hash(&body.user_uuid, &body.hash, &body.new_hash, body.slot.as_deref(), auth).await {
+        Ok(user) => (StatusCode::ACCEPTED, Response::user_success(user.uuid)),
+        Err(e) => Response::from(e).with_status(),
     }
-
 }
 
+
 #[cfg(test)]
 mod tests {
     use chrono::Utc;
@@ -66,21 +29,21 @@ mod tests {
 
     use crate::handlers::{UpdateHashRequest, Response};
     use crate::storage::PubKeys;
-    use crate::test_common::{check_path_exists, cleanup_test_files, read_keys, read_user, setup_test_server, storage_uri, write_keys, write_user, USER_UPDATE_HASH_PATH};
+    use crate::test_common::{check_path_exists, cleanup_test_files, key_entry_path, read_keys, read_user, setup_test_server, storage_uri, write_keys, write_user, USER_UPDATE_HASH_PATH};
 
 
     #[tokio::test]
+    #[allow(clippy::needless_borrow, clippy::redundant_field_names)]
     async fn test_update_hash() {
         let inital_uuid_1 = "1234";
         let initial_hash_1 = "initial_hash_1";
         let new_hash_1 = "new_hash_1";
 
-        let timestamp = Utc::now().timestamp().to_string();
+        let timestamp = Utc::now().timestamp_millis().to_string();
 
         let storage_uri = storage_uri("test_update_hash");
         let test_server = setup_test_server(storage_uri.clone());
         let user_file_path_1 = format!("{}/user:{}", &storage_uri.to_string(), inital_uuid_1);
-        let key_file_path = format!("{}/keys", &storage_uri.to_string());
 
         assert!(test_server.is_running());
         let sessionless = Sessionless::new();
@@ -100,7 +63,7 @@ mod tests {
         // check if the files exist
         assert!(check_path_exists(&user_file_path_1).await);
         // check if keys exists
-        assert!(check_path_exists(&key_file_path).await);
+        assert!(check_path_exists(&key_entry_path(&storage_uri.to_string(), &PubKeys::key(initial_hash_1, &pub_key.to_string()))).await);
 
         let message = format!("{}{}{}{}", timestamp, inital_uuid_1, initial_hash_1, new_hash_1);
         let signature = sessionless.sign(message);
@@ -111,9 +74,11 @@ mod tests {
             hash: initial_hash_1.to_string(),
             new_hash: new_hash_1.to_string(),
             signature: signature.to_string(),
+            slot: None,
         };
 
         let response = test_server.put(USER_UPDATE_HASH_PATH).json(&payload).await;
+        assert_eq!(response.status_code(), 202);
         let update_response = response.json::<Response>();
         match update_response.clone() {
             Response::User { user_uuid } => {
@@ -145,4 +110,48 @@ mod tests {
         // cleanup
         cleanup_test_files(&storage_uri.to_string()).await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_update_hash_conflict() {
+        let uuid = "1234";
+        let timestamp = Utc::now().timestamp_millis().to_string();
+
+        let storage_uri = storage_uri("test_update_hash_conflict");
+        let test_server = setup_test_server(storage_uri.clone());
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+
+        assert!(tokio::fs::create_dir_all(&storage_uri.to_string()).await.is_ok());
+        assert!(write_user(&storage_uri.to_string(), uuid, &pub_key, "hash").await);
+        let mut pub_keys = PubKeys::default();
+        pub_keys.add_user_uuid(uuid, &PubKeys::key("hash", &pub_key));
+        assert!(write_keys(&storage_uri.to_string(), &pub_keys).await);
+
+        let update = |new_hash: &str| UpdateHashRequest {
+            user_uuid: uuid.to_string(),
+            timestamp: timestamp.clone(),
+            hash: "hash".to_string(),
+            new_hash: new_hash.to_string(),
+            signature: sessionless.sign(format!("{}{}{}{}", timestamp, uuid, "hash", new_hash)).to_string(),
+            slot: None,
+        };
+
+        // two devices update from the same hash, the phone first
+        let response = test_server.put(USER_UPDATE_HASH_PATH).json(&update("phone_hash")).await;
+        assert_eq!(response.status_code(), 202);
+
+        // the desktop is told what the hash is now
+        let response = test_server.put(USER_UPDATE_HASH_PATH).json(&update("desktop_hash")).await;
+        assert_eq!(response.status_code(), 409);
+        assert_eq!(response.json::<serde_json::Value>(), serde_json::json!({"code": "409", "message": "Conflict", "hash": "phone_hash"}));
+
+        // and changed nothing, the index included
+        let user = read_user(&storage_uri.to_string(), uuid).await.expect("Failed to read user");
+        assert_eq!(user.hash, "phone_hash");
+        let pub_keys = read_keys(&storage_uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("phone_hash", &pub_key)), Some(&uuid.to_string()));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
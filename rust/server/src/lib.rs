@@ -0,0 +1,64 @@
+mod handlers;
+mod magic;
+mod metrics;
+pub mod config;
+pub mod storage;
+pub mod service;
+pub mod middleware;
+pub mod admin;
+
+use std::sync::Arc;
+use axum::{routing::{delete, get, post, put}, Router};
+
+use config::AppState;
+
+#[cfg(test)]
+mod test_common;
+#[cfg(test)]
+mod contract_tests;
+
+
+pub fn setup_router(app_state: AppState) -> Router {
+    let app_state = Arc::new(app_state);
+
+    Router::new()
+        .route("/user/create", post(handlers::create_user_handler))
+        .route("/user/{uuid}", get(handlers::get_user_handler))
+        .route("/user/{uuid}/state", get(handlers::get_state_handler))
+        .route("/user/{uuid}/history", get(handlers::history_handler))
+        .route("/user/update-hash", put(handlers::update_hash_handler))
+        .route("/user/state", put(handlers::put_state_handler))
+        .route("/user/rollback", put(handlers::rollback_handler))
+        .route("/user/delete", delete(handlers::delete_user_handler))
+        .route("/user/rotate-key", put(handlers::rotate_key_handler))
+        .route("/user/add-key", put(handlers::add_key_handler))
+        .route("/user/revoke-key", delete(handlers::revoke_key_handler))
+        .route("/magic/spell/{spell_name}", post(handlers::magic_spell_handler))
+        .route("/admin/audit", get(handlers::audit_handler))
+        .route("/admin/audit/verify", get(handlers::verify_audit_handler))
+        .route("/admin/user/{uuid}", get(handlers::lookup_user_handler).delete(handlers::force_delete_handler))
+        .route("/admin/key", get(handlers::lookup_key_handler))
+        .route("/admin/stats", get(handlers::admin_stats_handler))
+        // every route above, health checks aren't limited
+        .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), middleware::rate_limit))
+        .route("/health/live", get(handlers::health_live_handler))
+        .route("/health/ready", get(handlers::health_ready_handler))
+        // the original, misspelled health check; alive only, like /health/live
+        .route("/heath_check", get(health_check))
+        .layer(axum::middleware::from_fn(middleware::track_metrics))
+        // outermost, so everything below logs under the request's id
+        .layer(axum::middleware::from_fn(middleware::request_id))
+        .with_state(app_state)
+}
+
+// Served on its own listener at METRICS_ADDRESS, so it can be kept off the public port
+pub fn setup_metrics_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(handlers::metrics_handler))
+        .with_state(Arc::new(app_state))
+}
+
+#[allow(clippy::needless_return)]
+async fn health_check() -> String {
+    return "Success".to_string();
+}
\ No newline at end of file
@@ -0,0 +1,5 @@
+mod spell;
+mod spells;
+
+pub use spell::*;
+pub use spells::*;
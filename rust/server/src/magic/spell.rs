@@ -0,0 +1,150 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sessionless::{secp256k1::PublicKey, Sessionless, Signature};
+
+use crate::storage::User;
+
+
+// A spell as forwarded by the resolver (Fount) once it has checked the caster and charged MP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spell {
+    pub spell: String,
+    #[serde(rename = "casterUUID")]
+    pub caster_uuid: String,
+    pub timestamp: String,
+    pub total_cost: u64,
+    pub mp: bool,
+    pub ordinal: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caster_signature: Option<String>,
+    pub resolver_signature: String,
+    #[serde(default)]
+    pub components: serde_json::Value,
+}
+
+impl Spell {
+    // message signed by the caster, and by the resolver when forwarding:
+    // timestamp + spell + casterUUID + totalCost + mp + ordinal
+    pub fn message(&self) -> String {
+        format!("{}{}{}{}{}{}", self.timestamp, self.spell, self.caster_uuid, self.total_cost, self.mp, self.ordinal)
+    }
+
+    // Whether the resolver with resolver_pub_key signed this spell
+    pub fn verify_resolver(&self, resolver_pub_key: &str) -> bool {
+        let Ok(pub_key) = PublicKey::from_str(resolver_pub_key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_str(&self.resolver_signature) else {
+            return false;
+        };
+        Sessionless::new().verify(self.message(), &pub_key, &signature).is_ok()
+    }
+
+    // A string component, None when missing or empty
+    pub fn component(&self, name: &str) -> Option<&str> {
+        self.components.get(name).and_then(|value| value.as_str()).filter(|value| !value.is_empty())
+    }
+}
+
+// The user as spells return it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SpellUser {
+    pub uuid: String,
+    pub pub_key: String,
+    pub hash: String,
+}
+
+impl From<User> for SpellUser {
+    fn from(user: User) -> Self {
+        Self { uuid: user.uuid, pub_key: user.pub_key, hash: user.hash }
+    }
+}
+
+// {success: true, user}, {success} or {success: false, error}, as in MAGIC-ROUTES.md
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SpellResponse {
+    User { success: bool, user: SpellUser },
+    Error { success: bool, error: String },
+    Success { success: bool },
+}
+
+impl SpellResponse {
+    pub fn user(user: User) -> Self {
+        SpellResponse::User { success: true, user: user.into() }
+    }
+
+    pub fn success(success: bool) -> Self {
+        SpellResponse::Success { success }
+    }
+
+    pub fn error(error: impl Into<String>) -> Self {
+        SpellResponse::Error { success: false, error: error.into() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spell(sessionless: &Sessionless) -> Spell {
+        let mut spell = Spell {
+            spell: "continuebeeUserCreate".to_string(),
+            caster_uuid: "caster".to_string(),
+            timestamp: "1700000000000".to_string(),
+            total_cost: 50,
+            mp: true,
+            ordinal: 0,
+            caster_signature: None,
+            resolver_signature: "".to_string(),
+            components: serde_json::json!({"pubKey": "pub_key", "hash": ""}),
+        };
+        spell.resolver_signature = sessionless.sign(spell.message()).to_string();
+        spell
+    }
+
+    #[test]
+    fn test_message() {
+        let spell = spell(&Sessionless::new());
+        assert_eq!(spell.message(), "1700000000000continuebeeUserCreatecaster50true0");
+    }
+
+    #[test]
+    fn test_verify_resolver() {
+        let resolver = Sessionless::new();
+        let mut spell = spell(&resolver);
+
+        assert!(spell.verify_resolver(&resolver.public_key().to_string()));
+        assert!(!spell.verify_resolver(&Sessionless::new().public_key().to_string()));
+        assert!(!spell.verify_resolver("not a key"));
+
+        // any change to the signed fields invalidates it
+        spell.total_cost = 0;
+        assert!(!spell.verify_resolver(&resolver.public_key().to_string()));
+    }
+
+    #[test]
+    fn test_component() {
+        let spell = spell(&Sessionless::new());
+        assert_eq!(spell.component("pubKey"), Some("pub_key"));
+        assert_eq!(spell.component("hash"), None);
+        assert_eq!(spell.component("newHash"), None);
+    }
+
+    #[test]
+    fn test_response_shapes() {
+        let user = User::new(Some("1234".to_string()), "pub_key".to_string(), "hash".to_string());
+        assert_eq!(
+            serde_json::to_value(SpellResponse::user(user)).unwrap(),
+            serde_json::json!({"success": true, "user": {"uuid": "1234", "pubKey": "pub_key", "hash": "hash"}})
+        );
+        assert_eq!(serde_json::to_value(SpellResponse::success(true)).unwrap(), serde_json::json!({"success": true}));
+        assert_eq!(
+            serde_json::to_value(SpellResponse::error("User not found")).unwrap(),
+            serde_json::json!({"success": false, "error": "User not found"})
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::service::{Auth, UserError, UserService};
+
+use super::{Spell, SpellResponse};
+
+
+pub static USER_CREATE_SPELL: &str = "continuebeeUserCreate";
+pub static USER_UPDATE_HASH_SPELL: &str = "continuebeeUserUpdateHash";
+pub static USER_DELETE_SPELL: &str = "continuebeeUserDelete";
+
+// Runs a spell the resolver authorized; None when continuebee doesn't know the spell
+pub async fn cast(user_service: &UserService, spell: &Spell) -> Option<SpellResponse> {
+    let response = match spell.spell.as_str() {
+        name if name == USER_CREATE_SPELL => user_create(user_service, spell).await,
+        name if name == USER_UPDATE_HASH_SPELL => user_update_hash(user_service, spell).await,
+        name if name == USER_DELETE_SPELL => user_delete(user_service, spell).await,
+        _ => return None,
+    };
+    Some(response)
+}
+
+// Returns the existing user for pubKey + hash, otherwise creates one
+async fn user_create(user_service: &UserService, spell: &Spell) -> SpellResponse {
+    let (Some(pub_key), Some(hash)) = (spell.component("pubKey"), spell.component("hash")) else {
+        return SpellResponse::error("Missing required fields: pubKey, hash");
+    };
+
+    match user_service.create(pub_key, hash, None, Auth::Resolver).await {
+        Ok(user) => SpellResponse::user(user),
+        Err(e) => {
+            tracing::error!(spell = %spell.spell, error = %e, "Failed to put user");
+            SpellResponse::error("Failed to put user")
+        },
+    }
+}
+
+async fn user_update_hash(user_service: &UserService, spell: &Spell) -> SpellResponse {
+    let (Some(user_uuid), Some(hash), Some(new_hash)) = (spell.component("userUUID"), spell.component("hash"), spell.component("newHash")) else {
+        return SpellResponse::error("Missing required fields: userUUID, hash, newHash");
+    };
+
+    match user_service.update_hash(user_uuid, hash, new_hash, None, Auth::Resolver).await {
+        Ok(user) => SpellResponse::user(user),
+        Err(UserError::HashMismatch | UserError::HashConflict(_)) => SpellResponse::error("Current hash does not match"),
+        Err(UserError::Storage(e)) => {
+            tracing::error!(spell = %spell.spell, error = %e, "Failed to update hash");
+            SpellResponse::error("Failed to update hash")
+        },
+        Err(e) => SpellResponse::error(e.to_string()),
+    }
+}
+
+async fn user_delete(user_service: &UserService, spell: &Spell) -> SpellResponse {
+    let (Some(user_uuid), Some(hash)) = (spell.component("userUUID"), spell.component("hash")) else {
+        return SpellResponse::error("Missing required fields: userUUID, hash");
+    };
+
+    match user_service.delete(user_uuid, hash, None, Auth::Resolver).await {
+        Ok(()) => SpellResponse::success(true),
+        Err(UserError::Storage(e)) => {
+            tracing::error!(spell = %spell.spell, error = %e, "Failed to delete user");
+            SpellResponse::success(false)
+        },
+        Err(e) => SpellResponse::error(e.to_string()),
+    }
+}
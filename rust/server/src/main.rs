@@ -1,42 +1,65 @@
-mod config;
-mod storage;
-mod handlers;
+use std::{net::SocketAddr, sync::Arc};
 
-use std::sync::Arc;
-use axum::{routing::{delete, get, post, put}, Router};
-
-use config::{AppState, ServerConfig};
-use storage::UserClient;
-
-#[cfg(test)]
-mod test_common;
+use server::config::{init_logging, AppState, Clock, ServerConfig, SystemClock};
+use server::middleware::{RateLimiter, RateLimits};
+use server::service::{LockoutPolicy, UserService};
+use server::{setup_metrics_router, setup_router};
+use server::storage::{ReplayCache, UserClient};
 
 
 #[tokio::main]
 async fn main() {
     let server_config = ServerConfig::from_env();
+    init_logging(server_config.log_format, &server_config.log_level);
 
-    let app = setup_router(&server_config);
-    let listener = tokio::net::TcpListener::bind(server_config.server_url()).await.expect("Failed to bind to port");
-    axum::serve(listener, app).await.expect("Server failed to start");
-}
-
-fn setup_router(server_config: &ServerConfig) -> Router {
     let user_client = UserClient::new(server_config.storage_uri.clone());
+    let leftovers = user_client.client.recover().await.expect("Failed to recover storage");
+    for leftover in leftovers {
+        tracing::warn!(leftover = %leftover, "Removed incomplete write left over from a previous run");
+    }
 
-    let app_state = Arc::new(AppState {
-        user_client: user_client,
-    });
-
-    Router::new()
-        .route("/heath_check", get(health_check))
-        .route("/user/create", post(handlers::create_user_handler))
-        .route("/user/{uuid}", get(handlers::get_user_handler))
-        .route("/user/update-hash", put(handlers::update_hash_handler))
-        .route("/user/delete", delete(handlers::delete_user_handler))
-        .with_state(app_state)
-}
+    let migrated = user_client.migrate_legacy_keys().await.expect("Failed to migrate legacy keys");
+    if migrated > 0 {
+        tracing::info!(migrated, "Migrated legacy keys to the pub_key index");
+    }
+
+    let replay_cache = if server_config.persist_replay_cache {
+        ReplayCache::persistent(server_config.replay_cache_capacity, user_client.client.clone())
+    } else {
+        ReplayCache::new(server_config.replay_cache_capacity)
+    };
 
-async fn health_check() -> String {
-    return "Success".to_string();
-}
\ No newline at end of file
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let rate_limits = RateLimits {
+        window: server_config.rate_limit_window,
+        per_ip: server_config.rate_limit_per_ip,
+        per_key: server_config.rate_limit_per_key,
+        trust_forwarded_for: server_config.trust_forwarded_for,
+    };
+
+    let lockout = LockoutPolicy { max_failures: server_config.lockout_threshold, duration: server_config.lockout_duration };
+
+    let user_service = UserService::new(user_client, replay_cache, clock.clone(), server_config.allowed_time_difference, server_config.max_state_size, server_config.hash_history_size, lockout);
+    let app_state = AppState {
+        user_service,
+        fount_pub_key: server_config.fount_pub_key.clone(),
+        operator_pub_keys: server_config.operator_pub_keys.clone(),
+        rate_limiter: Arc::new(RateLimiter::new(rate_limits, clock)),
+    };
+
+    if let Some(metrics_address) = server_config.metrics_address.clone() {
+        let metrics_app = setup_metrics_router(app_state.clone());
+        let metrics_listener = tokio::net::TcpListener::bind(&metrics_address).await.expect("Failed to bind to METRICS_ADDRESS");
+        tracing::info!(address = %metrics_address, "Serving metrics");
+        tokio::spawn(async move {
+            axum::serve(metrics_listener, metrics_app).await.expect("Metrics server failed to start");
+        });
+    }
+
+    let app = setup_router(app_state);
+    let server_url = server_config.server_url();
+    let listener = tokio::net::TcpListener::bind(&server_url).await.expect("Failed to bind to port");
+    tracing::info!(address = %server_url, "Listening");
+    // connect info gives the rate limiter the client's address
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.expect("Server failed to start");
+}
@@ -0,0 +1,114 @@
+use std::{sync::LazyLock, time::Duration};
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+// The process wide metrics, recorded by the HTTP layer and every storage client
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+// What /metrics reports, in the Prometheus text format
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    // by method, route and status
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    // by backend and operation
+    storage_operation_duration: HistogramVec,
+    storage_errors: IntCounterVec,
+    // set from storage on every scrape
+    users: IntGauge,
+    pub_keys: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("continuebee".to_string()), None).expect("Invalid metrics prefix");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        ).expect("Invalid metric");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time taken to handle HTTP requests"),
+            &["method", "route", "status"],
+        ).expect("Invalid metric");
+        let storage_operation_duration = HistogramVec::new(
+            HistogramOpts::new("storage_operation_duration_seconds", "Time taken by storage operations"),
+            &["backend", "operation"],
+        ).expect("Invalid metric");
+        let storage_errors = IntCounterVec::new(
+            Opts::new("storage_errors_total", "Storage operations that failed with an I/O error or corrupt data"),
+            &["backend", "operation"],
+        ).expect("Invalid metric");
+        let users = IntGauge::new("users", "Users in storage").expect("Invalid metric");
+        let pub_keys = IntGauge::new("pub_keys", "Entries in the pub_key index").expect("Invalid metric");
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_request_duration.clone()),
+            Box::new(storage_operation_duration.clone()),
+            Box::new(storage_errors.clone()),
+            Box::new(users.clone()),
+            Box::new(pub_keys.clone()),
+        ] {
+            registry.register(collector).expect("Metric registered twice");
+        }
+
+        Self { registry, http_requests, http_request_duration, storage_operation_duration, storage_errors, users, pub_keys }
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_storage(&self, backend: &str, operation: &str, elapsed: Duration, failed: bool) {
+        let labels = [backend, operation];
+        self.storage_operation_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+        if failed {
+            self.storage_errors.with_label_values(&labels).inc();
+        }
+    }
+
+    pub fn set_counts(&self, users: u64, pub_keys: u64) {
+        self.users.set(users as i64);
+        self.pub_keys.set(pub_keys as i64);
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).expect("Failed to encode metrics");
+        String::from_utf8(buffer).expect("Metrics are not UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe_request("GET", "/user/{uuid}", 406, Duration::from_millis(5));
+        metrics.observe_request("GET", "/user/{uuid}", 406, Duration::from_millis(7));
+        metrics.observe_storage("file", "get", Duration::from_millis(1), false);
+        metrics.observe_storage("postgres", "set", Duration::from_millis(1), true);
+        metrics.set_counts(3, 4);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"continuebee_http_requests_total{method="GET",route="/user/{uuid}",status="406"} 2"#));
+        assert!(rendered.contains(r#"continuebee_http_request_duration_seconds_count{method="GET",route="/user/{uuid}",status="406"} 2"#));
+        assert!(rendered.contains(r#"continuebee_storage_operation_duration_seconds_count{backend="file",operation="get"} 1"#));
+        assert!(rendered.contains(r#"continuebee_storage_errors_total{backend="postgres",operation="set"} 1"#));
+        assert!(!rendered.contains(r#"continuebee_storage_errors_total{backend="file""#));
+        assert!(rendered.contains("continuebee_users 3"));
+        assert!(rendered.contains("continuebee_pub_keys 4"));
+    }
+}
@@ -0,0 +1,22 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response as AxumResponse,
+};
+
+use crate::metrics::metrics;
+
+
+// Counts and times every request by method, route and status. Routes are the
+// router's patterns, so uuids in paths don't make a label each
+pub async fn track_metrics(matched_path: Option<MatchedPath>, request: Request, next: Next) -> AxumResponse {
+    let route = matched_path.map(|path| path.as_str().to_string()).unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics().observe_request(&method, &route, response.status().as_u16(), started.elapsed());
+    response
+}
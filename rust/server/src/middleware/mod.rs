@@ -0,0 +1,7 @@
+mod rate_limit;
+mod metrics;
+mod request_id;
+
+pub use rate_limit::*;
+pub use metrics::*;
+pub use request_id::*;
@@ -0,0 +1,344 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, RawPathParams, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response as AxumResponse},
+};
+
+use crate::{config::{AppState, Clock}, handlers::Response};
+
+
+// Bodies are read to find the key they are signed for; larger ones are refused by the handlers anyway
+static MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+// Counters kept; past this, expired windows are swept, then the oldest is dropped
+static MAX_TRACKED: usize = 100_000;
+
+// Requests allowed per window, for each client IP and for each pub_key or user uuid.
+// A limit of 0 turns that limit off
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub window: Duration,
+    pub per_ip: u32,
+    pub per_key: u32,
+    // Take the client IP from the last X-Forwarded-For address, only behind a proxy that appends it
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started_at: i64,
+    count: u32,
+}
+
+// Fixed window counters, by "ip:<ip>" and "key:<pub_key or uuid>"
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    clock: Arc<dyn Clock>,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, clock: Arc<dyn Clock>) -> Self {
+        Self { limits, clock, windows: Mutex::new(HashMap::new()) }
+    }
+
+    // Counts a request for key; Err with the time until the window ends once over limit.
+    // Ok with the start of the window it was counted in, for refund
+    fn check(&self, key: String, limit: u32) -> Result<i64, Duration> {
+        let now = self.clock.now_millis();
+        if limit == 0 {
+            return Ok(now);
+        }
+        let window = self.limits.window.as_millis() as i64;
+
+        let mut windows = self.windows.lock().expect("Rate limiter poisoned");
+        if windows.len() >= MAX_TRACKED && !windows.contains_key(&key) {
+            windows.retain(|_, counter| now - counter.started_at < window);
+            // all still counting: the oldest window gives way
+            if windows.len() >= MAX_TRACKED
+                && let Some(oldest) = windows.iter().min_by_key(|(_, counter)| counter.started_at).map(|(key, _)| key.clone())
+            {
+                windows.remove(&oldest);
+            }
+        }
+
+        let counter = windows.entry(key).or_insert(Window { started_at: now, count: 0 });
+        if now - counter.started_at >= window {
+            *counter = Window { started_at: now, count: 0 };
+        }
+        if counter.count >= limit {
+            return Err(Duration::from_millis((counter.started_at + window - now).max(0) as u64));
+        }
+        counter.count += 1;
+        Ok(counter.started_at)
+    }
+
+    // Takes back a request counted by check in the window started at started_at, if it is still open
+    fn refund(&self, key: &str, started_at: i64) {
+        let mut windows = self.windows.lock().expect("Rate limiter poisoned");
+        if let Some(counter) = windows.get_mut(key)
+            && counter.started_at == started_at
+            && counter.count > 0
+        {
+            counter.count -= 1;
+            if counter.count == 0 {
+                windows.remove(key);
+            }
+        }
+    }
+
+    // With trust_forwarded_for, the last X-Forwarded-For address, the one the proxy added;
+    // proxies append to the header, so the ones before it are whatever the client sent
+    fn client_ip(&self, request: &Request) -> Option<String> {
+        if self.limits.trust_forwarded_for {
+            let forwarded = request.headers().get("x-forwarded-for").and_then(|value| value.to_str().ok());
+            if let Some(ip) = forwarded.and_then(|value| value.rsplit(',').next()) {
+                return Some(ip.trim().to_string());
+            }
+        }
+        request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip().to_string())
+    }
+}
+
+// Refuses requests over the per IP or per key limit with 429 and Retry-After.
+// The key is the uuid in the path, else the userUUID or pubKey of a JSON body. A request takes
+// its place in the key's window before it runs, so parallel requests can't all get in, and gives it
+// back if refused before its signature was accepted, since anyone can name a key; see refused_unsigned
+pub async fn rate_limit(State(state): State<Arc<AppState>>, params: RawPathParams, request: Request, next: Next) -> AxumResponse {
+    let limiter = &state.rate_limiter;
+
+    if let Some(ip) = limiter.client_ip(&request)
+        && let Err(retry_after) = limiter.check(format!("ip:{}", ip), limiter.limits.per_ip)
+    {
+        return too_many_requests(retry_after);
+    }
+
+    let path_uuid = params.iter().find(|(name, _)| *name == "uuid").map(|(_, value)| value.to_string());
+    let (key, request) = match path_uuid {
+        Some(uuid) => (Some(uuid), request),
+        None => match body_key(request).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        },
+    };
+
+    let Some(key) = key.map(|key| format!("key:{}", key)) else {
+        return next.run(request).await;
+    };
+    let started_at = match limiter.check(key.clone(), limiter.limits.per_key) {
+        Ok(started_at) => started_at,
+        Err(retry_after) => return too_many_requests(retry_after),
+    };
+    let response = next.run(request).await;
+    if refused_unsigned(response.status()) {
+        limiter.refund(&key, started_at);
+    }
+    response
+}
+
+// The answers given before a request's signature is accepted: malformed, stale or oversized
+// requests and bad or replayed signatures
+fn refused_unsigned(status: StatusCode) -> bool {
+    matches!(status, StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN | StatusCode::PAYLOAD_TOO_LARGE)
+}
+
+// The userUUID or pubKey a JSON body is for, along with the request to pass on
+async fn body_key(request: Request) -> Result<(Option<String>, Request), AxumResponse> {
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_BODY_SIZE).await.map_err(|_| Response::bad_request().into_response())?;
+
+    let key = serde_json::from_slice::<serde_json::Value>(&bytes).ok().and_then(|body| {
+        ["userUUID", "pubKey"].iter().find_map(|field| body.get(field).and_then(|value| value.as_str()).map(str::to_string))
+    });
+    Ok((key, Request::from_parts(parts, Body::from(bytes))))
+}
+
+fn too_many_requests(retry_after: Duration) -> AxumResponse {
+    // whole seconds, rounded up
+    let seconds = retry_after.as_millis().div_ceil(1000).to_string();
+    ([(header::RETRY_AFTER, seconds)], Response::too_many_requests()).into_response()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sessionless::Sessionless;
+
+    use super::*;
+    use crate::config::FixedClock;
+    use crate::handlers::{CreateUserRequest, QueryParams};
+    use crate::test_common::{cleanup_test_files, storage_uri, TestServerBuilder};
+
+    fn probe() -> QueryParams {
+        QueryParams { timestamp: "0".to_string(), hash: "wrong".to_string(), signature: "bad".to_string() }
+    }
+
+    #[test]
+    fn test_check() {
+        let clock = Arc::new(FixedClock::new(0));
+        let limits = RateLimits { window: Duration::from_secs(60), per_ip: 2, per_key: 0, trust_forwarded_for: false };
+        let limiter = RateLimiter::new(limits, clock.clone());
+
+        assert!(limiter.check("ip:1".to_string(), 2).is_ok());
+        assert!(limiter.check("ip:1".to_string(), 2).is_ok());
+        clock.advance(15_000);
+        assert_eq!(limiter.check("ip:1".to_string(), 2), Err(Duration::from_secs(45)));
+        // counted apart
+        assert!(limiter.check("ip:2".to_string(), 2).is_ok());
+        // no limit
+        assert!(limiter.check("key:1".to_string(), 0).is_ok());
+        // refunded requests don't count
+        let started_at = limiter.check("key:2".to_string(), 1).unwrap();
+        assert!(limiter.check("key:2".to_string(), 1).is_err());
+        limiter.refund("key:2", started_at);
+        assert!(limiter.check("key:2".to_string(), 1).is_ok());
+        assert!(limiter.check("key:2".to_string(), 1).is_err());
+
+        // a new window
+        clock.advance(45_000);
+        assert!(limiter.check("ip:1".to_string(), 2).is_ok());
+        // which a refund from the last one doesn't go into
+        clock.advance(15_000);
+        assert!(limiter.check("key:2".to_string(), 1).is_ok());
+        limiter.refund("key:2", started_at);
+        assert!(limiter.check("key:2".to_string(), 1).is_err());
+    }
+
+    #[test]
+    fn test_check_max_tracked() {
+        let clock = Arc::new(FixedClock::new(0));
+        let limits = RateLimits { window: Duration::from_secs(60), per_ip: 1, per_key: 0, trust_forwarded_for: false };
+        let limiter = RateLimiter::new(limits, clock.clone());
+
+        for ip in 0..MAX_TRACKED {
+            assert!(limiter.check(format!("ip:{}", ip), 1).is_ok());
+        }
+        // every window is still counting, so the oldest makes room
+        clock.advance(1);
+        assert!(limiter.check("ip:new".to_string(), 1).is_ok());
+        assert_eq!(limiter.windows.lock().unwrap().len(), MAX_TRACKED);
+        assert!(limiter.check("ip:new".to_string(), 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let clock = Arc::new(FixedClock::new(0));
+        let limits = RateLimits { window: Duration::from_secs(60), per_ip: 3, per_key: 2, trust_forwarded_for: true };
+        let storage_uri = storage_uri("test_rate_limit");
+        let test_server = TestServerBuilder::new(storage_uri.clone()).clock(clock.clone()).rate_limits(limits).build();
+
+        // an IP gets per_ip requests, signed or not
+        for uuid in ["1", "2", "3"] {
+            let response = test_server.get(&format!("/user/{}", uuid)).add_query_params(probe()).add_header("x-forwarded-for", "10.0.0.1").await;
+            assert_ne!(response.status_code(), 429);
+        }
+        clock.advance(20_000);
+        let response = test_server.get("/user/4").add_query_params(probe()).add_header("x-forwarded-for", "10.0.0.1").await;
+        assert_eq!(response.status_code(), 429);
+        assert_eq!(response.header("retry-after"), "40");
+        // the proxy's address is the last one, whatever the client put before it
+        let response = test_server.get("/user/4").add_query_params(probe()).add_header("x-forwarded-for", "192.0.2.1, 10.0.0.1").await;
+        assert_eq!(response.status_code(), 429);
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let create = |timestamp: &str, signature: String| CreateUserRequest {
+            timestamp: timestamp.to_string(),
+            pub_key: pub_key.clone(),
+            hash: "hash".to_string(),
+            signature,
+            slot: None,
+        };
+        let signature = sessionless.sign(format!("{}{}{}", "0", pub_key, "hash")).to_string();
+        let response = test_server.post("/user/create").json(&create("0", signature)).add_header("x-forwarded-for", "10.0.1.1").await;
+        let Response::User { user_uuid: uuid } = response.json::<Response>() else { panic!("Expected a user") };
+        let user_path = format!("/user/{}", uuid);
+
+        // requests naming the user without its signature don't use up its budget
+        for ip in 2..8 {
+            let response = test_server.get(&user_path).add_query_params(probe()).add_header("x-forwarded-for", format!("10.0.1.{}", ip)).await;
+            assert_eq!(response.status_code(), 403);
+        }
+        let response = test_server.post("/user/create").json(&create("1", "bad".to_string())).add_header("x-forwarded-for", "10.0.1.8").await;
+        assert_eq!(response.status_code(), 403);
+
+        // signed ones do, wherever they come from
+        let signed_probe = |timestamp: &str| QueryParams {
+            timestamp: timestamp.to_string(),
+            hash: "wrong".to_string(),
+            signature: sessionless.sign(format!("{}{}{}", timestamp, uuid, "wrong")).to_string(),
+        };
+        for timestamp in ["1", "2"] {
+            let response = test_server.get(&user_path).add_query_params(signed_probe(timestamp)).add_header("x-forwarded-for", format!("10.0.2.{}", timestamp)).await;
+            assert_eq!(response.status_code(), 406);
+        }
+        let response = test_server.get(&user_path).add_query_params(signed_probe("3")).add_header("x-forwarded-for", "10.0.2.3").await;
+        assert_eq!(response.status_code(), 429);
+        assert_eq!(response.header("retry-after"), "60");
+
+        // keys from the body
+        let signature = sessionless.sign(format!("{}{}{}", "1", pub_key, "hash")).to_string();
+        let response = test_server.post("/user/create").json(&create("1", signature)).add_header("x-forwarded-for", "10.0.3.1").await;
+        assert_eq!(response.status_code(), 200);
+        let signature = sessionless.sign(format!("{}{}{}", "2", pub_key, "hash")).to_string();
+        let response = test_server.post("/user/create").json(&create("2", signature)).add_header("x-forwarded-for", "10.0.3.2").await;
+        assert_eq!(response.status_code(), 429);
+
+        // health checks are never limited
+        for _ in 0..4 {
+            let response = test_server.get("/health/live").add_header("x-forwarded-for", "10.0.0.1").await;
+            assert_eq!(response.status_code(), 200);
+        }
+
+        // a new window
+        clock.advance(60_000);
+        let response = test_server.get(&user_path).add_query_params(signed_probe("4")).add_header("x-forwarded-for", "10.0.0.1").await;
+        assert_eq!(response.status_code(), 406);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_rate_limit_concurrent() {
+        let clock = Arc::new(FixedClock::new(0));
+        let limits = RateLimits { window: Duration::from_secs(60), per_ip: 0, per_key: 2, trust_forwarded_for: true };
+        let storage_uri = storage_uri("test_rate_limit_concurrent");
+        let test_server = std::rc::Rc::new(TestServerBuilder::new(storage_uri.clone()).clock(clock.clone()).rate_limits(limits).http_transport().build());
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let signature = sessionless.sign(format!("{}{}{}", "0", pub_key, "hash")).to_string();
+        let create = CreateUserRequest { timestamp: "0".to_string(), pub_key: pub_key.clone(), hash: "hash".to_string(), signature, slot: None };
+        let response = test_server.post("/user/create").json(&create).await;
+        let Response::User { user_uuid: uuid } = response.json::<Response>() else { panic!("Expected a user") };
+        // a new window, with the whole budget left
+        clock.advance(60_000);
+
+        // signed hash guesses sent all at once only get per_key answers between them
+        let local = tokio::task::LocalSet::new();
+        let mut requests = tokio::task::JoinSet::new();
+        for i in 0..16 {
+            let timestamp = (60_000 + i).to_string();
+            let probe = QueryParams {
+                signature: sessionless.sign(format!("{}{}{}", timestamp, uuid, "wrong")).to_string(),
+                timestamp,
+                hash: "wrong".to_string(),
+            };
+            let test_server = test_server.clone();
+            let path = format!("/user/{}", uuid);
+            requests.spawn_local_on(async move {
+                test_server.get(&path).add_query_params(probe).await.status_code()
+            }, &local);
+        }
+
+        let statuses = local.run_until(requests.join_all()).await;
+        assert_eq!(statuses.iter().filter(|status| **status == StatusCode::NOT_ACCEPTABLE).count(), 2);
+        assert_eq!(statuses.iter().filter(|status| **status == StatusCode::TOO_MANY_REQUESTS).count(), 14);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
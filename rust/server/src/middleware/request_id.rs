@@ -0,0 +1,138 @@
+use std::time::Instant;
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response as AxumResponse,
+};
+use sessionless::Sessionless;
+use tracing::Instrument;
+
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longest X-Request-Id taken from a client; anything else gets a fresh id
+static MAX_REQUEST_ID_LENGTH: usize = 128;
+// Query parameters that are never logged
+static REDACTED_PARAMS: [&str; 6] = ["hash", "newHash", "previousHash", "signature", "newSignature", "state"];
+
+// Runs the request in a span carrying its X-Request-Id, which is taken from the client
+// (or made up) and sent back on the response, and logs how it went
+pub async fn request_id(mut request: Request, next: Next) -> AxumResponse {
+    let request_id = request.headers().get(&REQUEST_ID_HEADER)
+        .filter(|value| is_valid_request_id(value))
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&Sessionless::generate_uuid().to_string()).expect("uuids are valid headers"));
+    request.headers_mut().insert(REQUEST_ID_HEADER.clone(), request_id.clone());
+
+    let uri = match request.uri().query() {
+        Some(query) => format!("{}?{}", request.uri().path(), redact_query(query)),
+        None => request.uri().path().to_string(),
+    };
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id.to_str().unwrap_or_default(),
+        method = %request.method(),
+        uri = %uri,
+    );
+
+    async move {
+        let started = Instant::now();
+        let mut response = next.run(request).await;
+        let status = response.status().as_u16();
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match status {
+            500.. => tracing::error!(status, elapsed_ms, "Request failed"),
+            _ => tracing::info!(status, elapsed_ms, "Request handled"),
+        }
+
+        response.headers_mut().insert(REQUEST_ID_HEADER.clone(), request_id);
+        response
+    }.instrument(span).await
+}
+
+fn is_valid_request_id(value: &HeaderValue) -> bool {
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value.chars().all(|c| c.is_ascii_graphic())
+}
+
+// The query with the values of hashes, signatures and state swapped for [redacted]
+pub fn redact_query(query: &str) -> String {
+    query.split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if REDACTED_PARAMS.contains(&name) => format!("{}=[redacted]", name),
+            _ => param.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::{Arc, Mutex}};
+
+    use axum::{routing::get, Router};
+    use axum_test::TestServer;
+
+    use super::*;
+
+    // Collects log output in memory
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_redact_query() {
+        assert_eq!(
+            redact_query("timestamp=123&hash=abc&signature=def&slot=settings"),
+            "timestamp=123&hash=[redacted]&signature=[redacted]&slot=settings",
+        );
+        assert_eq!(redact_query("hashes=abc&flag"), "hashes=abc&flag");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt().json().with_writer(move || writer.clone()).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let router = Router::new()
+            .route("/user/{uuid}", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn(request_id));
+        let test_server = TestServer::new(router).unwrap();
+
+        // a client's id is passed on
+        let response = test_server.get("/user/1234?timestamp=1&hash=secret_hash&signature=secret_signature")
+            .add_header("x-request-id", "client-id-1")
+            .await;
+        assert_eq!(response.header("x-request-id"), "client-id-1");
+
+        // otherwise one is made up
+        let response = test_server.get("/user/1234").await;
+        let generated = response.header("x-request-id").to_str().unwrap().to_string();
+        assert_eq!(generated.len(), 36);
+        let response = test_server.get("/user/1234").add_header("x-request-id", "x".repeat(200)).await;
+        assert_ne!(response.header("x-request-id"), "x".repeat(200).as_str());
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("client-id-1"));
+        assert!(logs.contains(&generated));
+        assert!(logs.contains("hash=[redacted]"));
+        assert!(!logs.contains("secret_hash"));
+        assert!(!logs.contains("secret_signature"));
+    }
+}
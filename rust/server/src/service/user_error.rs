@@ -0,0 +1,71 @@
+use std::fmt;
+
+use crate::storage::StorageError;
+
+
+// Why a user operation was refused, independent of the front-end reporting it
+#[derive(Debug)]
+pub enum UserError {
+    // the signature (or the key it should verify with) is invalid
+    Auth,
+    // the timestamp is not a number or outside the allowed time difference
+    StaleTimestamp,
+    // the same signed request was already accepted
+    Replayed,
+    NotFound,
+    // the hash sent doesn't match the user's current hash
+    HashMismatch,
+    // an update from a hash that is no longer current; holds the current one
+    HashConflict(String),
+    // the key to revoke isn't one of the user's
+    KeyNotFound,
+    // revoking the key would leave the user without any
+    LastKey,
+    // no state was saved for the user
+    NoState,
+    // the state payload is over the configured size
+    StateTooLarge,
+    // the hash to roll back to isn't in the user's history
+    NotInHistory,
+    // the user has no slot by that name
+    SlotNotFound,
+    // too many hash mismatches in a row; hash checks are refused for a while
+    LockedOut,
+    // the slot name is empty, too long or has characters other than letters, digits, '-' and '_'
+    InvalidSlot,
+    Storage(StorageError),
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserError::Auth => write!(f, "Auth Error"),
+            UserError::StaleTimestamp => write!(f, "Stale Timestamp"),
+            UserError::Replayed => write!(f, "Replayed Request"),
+            UserError::NotFound => write!(f, "User not found"),
+            UserError::HashMismatch => write!(f, "Hash does not match"),
+            UserError::HashConflict(_) => write!(f, "Hash conflict"),
+            UserError::KeyNotFound => write!(f, "Key not found"),
+            UserError::LastKey => write!(f, "Cannot revoke the last key"),
+            UserError::NoState => write!(f, "No state saved"),
+            UserError::StateTooLarge => write!(f, "State too large"),
+            UserError::NotInHistory => write!(f, "Hash not in history"),
+            UserError::SlotNotFound => write!(f, "Slot not found"),
+            UserError::InvalidSlot => write!(f, "Invalid slot"),
+            UserError::LockedOut => write!(f, "Locked out"),
+            UserError::Storage(e) => write!(f, "Storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UserError {}
+
+// Only for storage calls about the user being operated on: a missing record is a missing user
+impl From<StorageError> for UserError {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::NotFound(_) => UserError::NotFound,
+            e => UserError::Storage(e),
+        }
+    }
+}
@@ -0,0 +1,954 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use sessionless::{secp256k1::PublicKey, Sessionless, Signature};
+
+use crate::{config::Clock, handlers::is_fresh, storage::{AuditAction, AuditEntry, AuditLog, AuditRecord, ChainCheck, KeyCheck, Lockout, OptionalExt, ReplayCache, StorageError, User, UserClient, UserState, UserStats}};
+
+use super::UserError;
+
+
+// How a request was authorized
+#[derive(Debug, Clone, Copy)]
+pub enum Auth<'a> {
+    // Signed by the user's key over the operation's message, which starts with timestamp
+    Signed { timestamp: &'a str, signature: &'a str },
+    // Already authorized by the resolver (Fount) for a MAGIC spell
+    Resolver,
+}
+
+// Hash checks refused for duration after max_failures mismatches in a row; 0 max_failures for none
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockoutPolicy {
+    pub max_failures: u32,
+    pub duration: Duration,
+}
+
+// The user operations shared by every front-end: signature checks, freshness,
+// replay protection and keeping the pub_key index in step with the users
+#[derive(Debug, Clone)]
+pub struct UserService {
+    user_client: UserClient,
+    replay_cache: Arc<ReplayCache>,
+    clock: Arc<dyn Clock>,
+    allowed_time_difference: Duration,
+    // Largest state payload accepted, in bytes
+    max_state_size: usize,
+    // Previous hashes kept per user
+    history_size: usize,
+    lockout: LockoutPolicy,
+    // Kept in the same storage as the users
+    audit_log: AuditLog,
+}
+
+impl UserService {
+    pub fn new(user_client: UserClient, replay_cache: ReplayCache, clock: Arc<dyn Clock>, allowed_time_difference: Duration, max_state_size: usize, history_size: usize, lockout: LockoutPolicy) -> Self {
+        let audit_log = AuditLog::new(user_client.client.clone());
+        Self { user_client, replay_cache: Arc::new(replay_cache), clock, allowed_time_difference, max_state_size, history_size, lockout, audit_log }
+    }
+
+    pub fn allowed_time_difference(&self) -> Duration {
+        self.allowed_time_difference
+    }
+
+    // Creates a user for pub_key + hash, or returns the one that already exists.
+    // With a slot, the slot starts out at hash as well unless the user already has it.
+    // signature message is: timestamp + pubKey + hash, see with_slot
+    pub async fn create(&self, pub_key: &str, hash: &str, slot: Option<&str>, auth: Auth<'_>) -> Result<User, UserError> {
+        check_slot(slot)?;
+        let signer = self.authorize_change([pub_key], || with_slot(slot, format!("{}{}", pub_key, hash)), auth).await?;
+
+        let (user, created) = self.user_client.create_user(pub_key, hash).await?;
+        match slot {
+            Some(slot) if !user.slots.contains_key(slot) => {
+                let user = self.user_client.update_user::<UserError>(&user.uuid, |user| {
+                    let mut user = user.clone();
+                    user.slots.entry(slot.to_string()).or_insert_with(|| hash.to_string());
+                    Ok(user)
+                }).await?;
+                self.audit(AuditAction::Create, &user.uuid, Some(slot), signer).await;
+                Ok(user)
+            },
+            _ => {
+                if created {
+                    self.audit(AuditAction::Create, &user.uuid, None, signer).await;
+                }
+                Ok(user)
+            },
+        }
+    }
+
+    // Returns the user if hash is the current hash of slot (or of the user).
+    // signature message is: timestamp + uuid + hash, see with_slot
+    pub async fn verify(&self, uuid: &str, hash: &str, slot: Option<&str>, auth: Auth<'_>) -> Result<User, UserError> {
+        check_slot(slot)?;
+        let user = self.find(uuid).await?;
+        self.authorize(user.keys(), || with_slot(slot, format!("{}{}", uuid, hash)), auth).await?;
+
+        self.check_user_hash(&user, slot, hash).await?;
+        Ok(user)
+    }
+
+    // Replaces the current hash of slot (or of the user) with new_hash, only while it is still hash.
+    // HashConflict, with the hash there is now, when another update got there first; see as_conflict.
+    // signature message is: timestamp + uuid + hash + newHash, see with_slot
+    pub async fn update_hash(&self, uuid: &str, hash: &str, new_hash: &str, slot: Option<&str>, auth: Auth<'_>) -> Result<User, UserError> {
+        check_slot(slot)?;
+        let user = self.find(uuid).await?;
+        let signer = self.authorize_change(user.keys(), || with_slot(slot, format!("{}{}{}", uuid, hash, new_hash)), auth).await?;
+
+        self.check_user_hash(&user, slot, hash).await.map_err(|e| as_conflict(e, &user, slot, hash))?;
+        let updated = match slot {
+            None => self.swap_hash(&user.uuid, hash, new_hash).await?,
+            Some(slot) => self.user_client.update_user::<UserError>(&user.uuid, |user| {
+                check_hash(user, Some(slot), hash).map_err(|e| lost_race(e, user, Some(slot)))?;
+                let mut user = user.clone();
+                user.slots.insert(slot.to_string(), new_hash.to_string());
+                Ok(user)
+            }).await?,
+        };
+
+        self.audit(AuditAction::UpdateHash, &user.uuid, slot, signer).await;
+        Ok(updated)
+    }
+
+    // Returns the user, its history included, if hash is its current hash.
+    // signature message is: timestamp + "history" + uuid + hash
+    pub async fn history(&self, uuid: &str, hash: &str, auth: Auth<'_>) -> Result<User, UserError> {
+        let user = self.find(uuid).await?;
+        self.authorize(user.keys(), || format!("history{}{}", uuid, hash), auth).await?;
+
+        self.check_user_hash(&user, None, hash).await?;
+        Ok(user)
+    }
+
+    // Goes back to previous_hash from the user's history; hash, the current one, goes into it.
+    // signature message is: timestamp + "rollback" + uuid + hash + previousHash
+    pub async fn rollback(&self, uuid: &str, hash: &str, previous_hash: &str, auth: Auth<'_>) -> Result<User, UserError> {
+        let user = self.find(uuid).await?;
+        let signer = self.authorize_change(user.keys(), || format!("rollback{}{}{}", uuid, hash, previous_hash), auth).await?;
+
+        self.check_user_hash(&user, None, hash).await.map_err(|e| as_conflict(e, &user, None, hash))?;
+        if !user.had_hash(previous_hash) {
+            return Err(UserError::NotInHistory);
+        }
+        let rolled_back = self.swap_hash(&user.uuid, hash, previous_hash).await?;
+        self.audit(AuditAction::Rollback, &user.uuid, None, signer).await;
+        Ok(rolled_back)
+    }
+
+    // Deletes the user along with its index entry, or with a slot only that slot.
+    // signature message is: timestamp + uuid + hash, see with_slot
+    pub async fn delete(&self, uuid: &str, hash: &str, slot: Option<&str>, auth: Auth<'_>) -> Result<(), UserError> {
+        check_slot(slot)?;
+        let user = self.find(uuid).await?;
+        let signer = self.authorize_change(user.keys(), || with_slot(slot, format!("{}{}", uuid, hash)), auth).await?;
+
+        self.check_user_hash(&user, slot, hash).await?;
+        match slot {
+            None => self.user_client.clone().delete_user(&user.uuid).await?,
+            Some(slot) => {
+                self.user_client.update_user::<UserError>(&user.uuid, |user| {
+                    check_hash(user, Some(slot), hash)?;
+                    let mut user = user.clone();
+                    user.slots.remove(slot);
+                    Ok(user)
+                }).await?;
+            },
+        }
+        self.audit(AuditAction::Delete, &user.uuid, slot, signer).await;
+        Ok(())
+    }
+
+    // The state last saved for the user, if hash is its current hash.
+    // signature message is: timestamp + "getState" + uuid + hash
+    pub async fn get_state(&self, uuid: &str, hash: &str, auth: Auth<'_>) -> Result<UserState, UserError> {
+        let user = self.find(uuid).await?;
+        self.authorize(user.keys(), || format!("getState{}{}", uuid, hash), auth).await?;
+
+        self.check_user_hash(&user, None, hash).await?;
+        self.user_client.get_state(uuid).await.map_err(|e| match e {
+            StorageError::NotFound(_) => UserError::NoState,
+            e => UserError::Storage(e),
+        })
+    }
+
+    // Saves state for the user while hash is its current hash. The client encrypts state,
+    // it is stored as sent.
+    // signature message is: timestamp + "putState" + uuid + hash + state
+    pub async fn put_state(&self, uuid: &str, hash: &str, state: &str, auth: Auth<'_>) -> Result<UserState, UserError> {
+        if state.len() > self.max_state_size {
+            return Err(UserError::StateTooLarge);
+        }
+
+        let user = self.find(uuid).await?;
+        self.authorize_change(user.keys(), || format!("putState{}{}{}", uuid, hash, state), auth).await?;
+
+        self.check_user_hash(&user, None, hash).await?;
+        Ok(self.user_client.put_state(uuid, hash, state).await?)
+    }
+
+    // Replaces the signing key with new_pub_key. Both keys sign the same message, so the old
+    // key hands its place over and the new key proves it is held by the caller.
+    // signature message is: timestamp + "rotateKey" + uuid + hash + newPubKey
+    pub async fn rotate_key(&self, uuid: &str, hash: &str, new_pub_key: &str, new_signature: &str, auth: Auth<'_>) -> Result<User, UserError> {
+        let fields = format!("rotateKey{}{}{}", uuid, hash, new_pub_key);
+        let signer = self.authorize_new_key(uuid, hash, new_pub_key, new_signature, fields, auth).await?;
+
+        let rotated = self.user_client.update_user(uuid, |user| match user.has_key(&signer) {
+            true => Ok(user.with_key_replaced(&signer, new_pub_key)),
+            false => Err(UserError::Auth),
+        }).await?;
+        self.audit(AuditAction::RotateKey, uuid, None, Some(signer)).await;
+        Ok(rotated)
+    }
+
+    // Authorizes new_pub_key alongside the user's other keys, signed by any of them
+    // and by new_pub_key itself.
+    // signature message is: timestamp + "addKey" + uuid + hash + newPubKey
+    pub async fn add_key(&self, uuid: &str, hash: &str, new_pub_key: &str, new_signature: &str, auth: Auth<'_>) -> Result<User, UserError> {
+        let fields = format!("addKey{}{}{}", uuid, hash, new_pub_key);
+        let signer = self.authorize_new_key(uuid, hash, new_pub_key, new_signature, fields, auth).await?;
+
+        let user = self.user_client.update_user::<UserError>(uuid, |user| Ok(user.with_key(new_pub_key))).await?;
+        self.audit(AuditAction::AddKey, uuid, None, Some(signer)).await;
+        Ok(user)
+    }
+
+    // Revokes pub_key, signed by any of the user's keys (pub_key included).
+    // The last key can't be revoked, delete the user instead.
+    // signature message is: timestamp + "revokeKey" + uuid + hash + pubKey
+    pub async fn revoke_key(&self, uuid: &str, hash: &str, pub_key: &str, auth: Auth<'_>) -> Result<User, UserError> {
+        let user = self.find(uuid).await?;
+        let signer = self.authorize_change(user.keys(), || format!("revokeKey{}{}{}", uuid, hash, pub_key), auth).await?;
+
+        self.check_user_hash(&user, None, hash).await?;
+        let revoked = self.user_client.update_user(uuid, |user| match user.has_key(pub_key) {
+            true => user.without_key(pub_key).ok_or(UserError::LastKey),
+            false => Err(UserError::KeyNotFound),
+        }).await?;
+        self.audit(AuditAction::RevokeKey, uuid, None, signer).await;
+        Ok(revoked)
+    }
+
+    // The one check that timestamp (unix millis) is within the allowed time difference of now,
+    // done on every signed request before anything else looks at it
+    pub fn check_fresh(&self, timestamp: &str) -> Result<(), UserError> {
+        match is_fresh(timestamp, self.clock.now_millis(), self.allowed_time_difference.as_millis() as i64) {
+            true => Ok(()),
+            false => Err(UserError::StaleTimestamp),
+        }
+    }
+
+    // Accepts a signature only once until its timestamp goes stale; check_fresh it first.
+    // Only call with verified signatures, so unsigned requests can't fill the cache
+    pub async fn check_replay(&self, pub_key: &str, signature: &str, timestamp: &str) -> Result<(), UserError> {
+        let now = self.clock.now_millis();
+        let allowed = self.allowed_time_difference.as_millis() as i64;
+        let expires_at = timestamp.parse::<i64>().map_err(|_| UserError::StaleTimestamp)? + allowed;
+
+        // the replay cache's storage errors are not about the user
+        let first_use = self.replay_cache.first_use(pub_key, signature, expires_at, now).await.map_err(UserError::Storage)?;
+        match first_use {
+            true => Ok(()),
+            false => Err(UserError::Replayed),
+        }
+    }
+
+    // Up to limit audit records from sequence from on, only user_uuid's when given.
+    // signature message is: timestamp + "audit", by one of operator_keys
+    pub async fn audit_records(&self, operator_keys: &[String], from: u64, limit: usize, user_uuid: Option<&str>, auth: Auth<'_>) -> Result<Vec<AuditRecord>, UserError> {
+        self.authorize_operator(operator_keys, || "audit".to_string(), auth).await?;
+        Ok(self.audit_log.records(from, limit, user_uuid).await?)
+    }
+
+    // Walks the audit log, reporting the first record that doesn't link up.
+    // signature message is: timestamp + "verifyAudit", by one of operator_keys
+    pub async fn verify_audit(&self, operator_keys: &[String], auth: Auth<'_>) -> Result<ChainCheck, UserError> {
+        self.authorize_operator(operator_keys, || "verifyAudit".to_string(), auth).await?;
+        Ok(self.audit_log.verify().await?)
+    }
+
+    // The stored user, with its mismatch count if lockouts are on, for support staff.
+    // signature message is: timestamp + "lookupUser" + uuid, by one of operator_keys
+    pub async fn lookup_user(&self, operator_keys: &[String], uuid: &str, auth: Auth<'_>) -> Result<(User, Option<Lockout>), UserError> {
+        self.authorize_operator(operator_keys, || format!("lookupUser{}", uuid), auth).await?;
+        let user = self.find(uuid).await?;
+        let lockout = self.lockout(uuid).await?;
+        Ok((user, lockout))
+    }
+
+    // Deletes the user, its state and index entry without its hash, e.g. for a user who lost it.
+    // signature message is: timestamp + "deleteUser" + uuid, by one of operator_keys
+    pub async fn force_delete(&self, operator_keys: &[String], uuid: &str, auth: Auth<'_>) -> Result<(), UserError> {
+        let signer = self.authorize_operator(operator_keys, || format!("deleteUser{}", uuid), auth).await?;
+        self.reject_replay(Some(&signer), auth).await?;
+        self.user_client.clone().delete_user(uuid).await?;
+        self.audit(AuditAction::Delete, uuid, None, Some(signer)).await;
+        Ok(())
+    }
+
+    // Where the index entry for pub_key + hash points, and whether that user agrees.
+    // signature message is: timestamp + "lookupKey" + pubKey + hash, by one of operator_keys
+    pub async fn lookup_key(&self, operator_keys: &[String], pub_key: &str, hash: &str, auth: Auth<'_>) -> Result<KeyCheck, UserError> {
+        self.authorize_operator(operator_keys, || format!("lookupKey{}{}", pub_key, hash), auth).await?;
+        Ok(self.user_client.check_key(pub_key, hash).await?)
+    }
+
+    // signature message is: timestamp + "stats", by one of operator_keys
+    pub async fn operator_stats(&self, operator_keys: &[String], auth: Auth<'_>) -> Result<UserStats, UserError> {
+        self.authorize_operator(operator_keys, || "stats".to_string(), auth).await?;
+        self.stats().await
+    }
+
+    // Checks a request signed by one of operator_keys over timestamp + fields and returns the key
+    // that signed. Operators always sign; the resolver can't act as one
+    pub async fn authorize_operator(&self, operator_keys: &[String], fields: impl FnOnce() -> String, auth: Auth<'_>) -> Result<String, UserError> {
+        if matches!(auth, Auth::Resolver) {
+            return Err(UserError::Auth);
+        }
+        let signer = self.authorize(operator_keys.iter().map(String::as_str), fields, auth).await?;
+        signer.ok_or(UserError::Auth)
+    }
+
+    // A round trip through storage, for readiness probes
+    pub async fn check_storage(&self) -> Result<(), StorageError> {
+        self.user_client.check_storage().await
+    }
+
+    // Which storage backend users are kept in
+    pub fn storage_backend(&self) -> &'static str {
+        self.user_client.client.backend()
+    }
+
+    // How many users and index entries there are, for metrics and operators
+    pub async fn stats(&self) -> Result<UserStats, UserError> {
+        Ok(self.user_client.stats().await?)
+    }
+
+    async fn find(&self, uuid: &str) -> Result<User, UserError> {
+        Ok(self.user_client.clone().get_user(uuid).await?)
+    }
+
+    // The user's mismatch count, if lockouts are on and there is one
+    async fn lockout(&self, uuid: &str) -> Result<Option<Lockout>, UserError> {
+        if self.lockout.max_failures == 0 {
+            return Ok(None);
+        }
+        Ok(self.user_client.get_lockout(uuid).await.optional()?)
+    }
+
+    // The hash check in front of every operation on a user. Refused with LockedOut while the user
+    // is locked out; a mismatch counts towards a lockout and a match clears the count. Hashes from
+    // the user's history aren't guesses and aren't counted, see as_conflict
+    async fn check_user_hash(&self, user: &User, slot: Option<&str>, hash: &str) -> Result<(), UserError> {
+        let lockout = self.lockout(&user.uuid).await?;
+        if lockout.as_ref().is_some_and(|lockout| lockout.is_locked(self.clock.now_millis())) {
+            return Err(UserError::LockedOut);
+        }
+        match check_hash(user, slot, hash) {
+            Err(UserError::HashMismatch) => {
+                if self.lockout.max_failures > 0 && !is_past_hash(user, slot, hash) {
+                    let lockout_millis = self.lockout.duration.as_millis() as i64;
+                    self.user_client.record_failure(&user.uuid, self.clock.now_millis(), self.lockout.max_failures, lockout_millis).await?;
+                }
+                Err(UserError::HashMismatch)
+            },
+            Err(e) => Err(e),
+            Ok(()) => {
+                if lockout.is_some() {
+                    self.user_client.reset_lockout(&user.uuid).await?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    // Records a mutation that already happened. The change is committed either way, so a record
+    // that can't be appended is logged and the request still succeeds; failing it would have the
+    // client retry a change that was made
+    async fn audit(&self, action: AuditAction, uuid: &str, slot: Option<&str>, pub_key: Option<String>) {
+        let entry = AuditEntry { action, user_uuid: uuid.to_string(), slot: slot.map(str::to_string), pub_key };
+        if let Err(e) = self.audit_log.append(entry, self.clock.now_millis()).await {
+            tracing::error!(action = ?action, user_uuid = %uuid, error = %e, "Failed to append audit record");
+        }
+    }
+
+    // Compare and set of the user's own hash; a lost race reports the hash that won,
+    // new_hash held by another user under one of the keys is a plain conflict
+    async fn swap_hash(&self, uuid: &str, hash: &str, new_hash: &str) -> Result<User, UserError> {
+        match self.user_client.update_hash(uuid, hash, new_hash, self.clock.now_millis(), self.history_size).await {
+            Ok(user) => Ok(user),
+            Err(StorageError::Conflict(key)) => match self.find(uuid).await? {
+                user if user.hash != hash => Err(UserError::HashConflict(user.hash)),
+                _ => Err(UserError::Storage(StorageError::Conflict(key))),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Checks a request handing the user a new key: new_signature by new_pub_key and the
+    // request's own signature by one of the user's keys, both over timestamp + fields.
+    // Returns the user's key that signed; only the user can hand out keys, never the resolver
+    async fn authorize_new_key(&self, uuid: &str, hash: &str, new_pub_key: &str, new_signature: &str, fields: String, auth: Auth<'_>) -> Result<String, UserError> {
+        let Auth::Signed { timestamp, .. } = auth else {
+            return Err(UserError::Auth);
+        };
+
+        let user = self.find(uuid).await?;
+        verify_signature(new_pub_key, new_signature, &format!("{}{}", timestamp, fields))?;
+        let signer = self.authorize_change(user.keys(), || fields, auth).await?;
+
+        self.check_user_hash(&user, None, hash).await?;
+        // a signed request always has a signer
+        signer.ok_or(UserError::Auth)
+    }
+
+    // Checks a signed request against any of keys and returns the one that signed; the message is
+    // the timestamp followed by the operation's fields, built lazily since resolver authorized
+    // requests don't need it (and have no signer). Reads can be retried as they are
+    async fn authorize<'k>(&self, keys: impl IntoIterator<Item = &'k str>, fields: impl FnOnce() -> String, auth: Auth<'_>) -> Result<Option<String>, UserError> {
+        let Auth::Signed { timestamp, signature } = auth else {
+            return Ok(None);
+        };
+
+        let message = format!("{}{}", timestamp, fields());
+        let signer = keys.into_iter()
+            .find(|key| verify_signature(key, signature, &message).is_ok())
+            .ok_or(UserError::Auth)?;
+        Ok(Some(signer.to_string()))
+    }
+
+    // authorize for a request that changes something, which is only accepted once
+    async fn authorize_change<'k>(&self, keys: impl IntoIterator<Item = &'k str>, fields: impl FnOnce() -> String, auth: Auth<'_>) -> Result<Option<String>, UserError> {
+        let signer = self.authorize(keys, fields, auth).await?;
+        self.reject_replay(signer.as_deref(), auth).await?;
+        Ok(signer)
+    }
+
+    async fn reject_replay(&self, signer: Option<&str>, auth: Auth<'_>) -> Result<(), UserError> {
+        match (signer, auth) {
+            (Some(signer), Auth::Signed { timestamp, signature }) => self.check_replay(signer, signature, timestamp).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+// Slot names are short and can't contain the ':' ending them in signature messages
+fn check_slot(slot: Option<&str>) -> Result<(), UserError> {
+    match slot {
+        Some(slot) if slot.is_empty() || slot.len() > 64 => Err(UserError::InvalidSlot),
+        Some(slot) if !slot.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => Err(UserError::InvalidSlot),
+        _ => Ok(()),
+    }
+}
+
+// Signature messages for a slot are prefixed with "slot:" + slot + ":" right after the timestamp,
+// so they can't be taken for the same operation on the user's own hash
+fn with_slot(slot: Option<&str>, fields: String) -> String {
+    match slot {
+        Some(slot) => format!("slot:{}:{}", slot, fields),
+        None => fields,
+    }
+}
+
+fn check_hash(user: &User, slot: Option<&str>, hash: &str) -> Result<(), UserError> {
+    match user.slot_hash(slot) {
+        None => Err(UserError::SlotNotFound),
+        Some(current) if current != hash => Err(UserError::HashMismatch),
+        Some(_) => Ok(()),
+    }
+}
+
+// Whether hash is one the user's own hash had before; slots keep no history
+fn is_past_hash(user: &User, slot: Option<&str>, hash: &str) -> bool {
+    slot.is_none() && user.had_hash(hash)
+}
+
+// A hash mismatch on an update from a hash the user had before is a stale client, answered with the
+// current hash. Any other mismatch stays one, so the current hash is only told to callers who
+// already knew a recent one
+fn as_conflict(e: UserError, user: &User, slot: Option<&str>, hash: &str) -> UserError {
+    match e {
+        UserError::HashMismatch if is_past_hash(user, slot, hash) => UserError::HashConflict(user.hash.clone()),
+        e => e,
+    }
+}
+
+// A mismatch on a hash that matched moments before is a lost race, answered with the current hash
+fn lost_race(e: UserError, user: &User, slot: Option<&str>) -> UserError {
+    match (e, user.slot_hash(slot)) {
+        (UserError::HashMismatch, Some(current)) => UserError::HashConflict(current.to_string()),
+        (e, _) => e,
+    }
+}
+
+fn verify_signature(pub_key: &str, signature: &str, message: &str) -> Result<(), UserError> {
+    let key = PublicKey::from_str(pub_key).map_err(|_| UserError::Auth)?;
+    let sig = Signature::from_str(signature).map_err(|_| UserError::Auth)?;
+    Sessionless::new().verify(message, &key, &sig).map_err(|_| UserError::Auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{config::FixedClock, storage::HashRecord, test_common::{cleanup_test_files, count_users, storage_uri, test_user_service, MAX_STATE_SIZE}};
+
+    // test_common::ALLOWED_TIME_DIFFERENCE
+    static ALLOWED_MILLIS: i64 = 600_000;
+
+    fn sign(sessionless: &Sessionless, timestamp: &str, fields: &str) -> String {
+        sessionless.sign(format!("{}{}", timestamp, fields)).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_user_operations() {
+        let storage_uri = storage_uri("test_user_service_operations");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let service = test_user_service(storage_uri.clone(), clock.clone());
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+        let timestamp = clock.now_millis().to_string();
+
+        // create
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}", pub_key, "hash"));
+        let user = service.create(&pub_key, "hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(user.pub_key, pub_key);
+
+        // the same signed create is a replay
+        let replayed = service.create(&pub_key, "hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(replayed, Err(UserError::Replayed)));
+
+        // verify
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}", user.uuid, "hash"));
+        let verified = service.verify(&user.uuid, "hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(verified, user);
+        // a read can be retried with the same signature
+        let retried = service.verify(&user.uuid, "hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(retried, user);
+
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}", user.uuid, "other"));
+        let mismatch = service.verify(&user.uuid, "other", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(mismatch, Err(UserError::HashMismatch)));
+
+        // update
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}{}", user.uuid, "hash", "new_hash"));
+        let updated = service.update_hash(&user.uuid, "hash", "new_hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(updated.hash, "new_hash");
+
+        // a client a hash behind is told the current one, a made up hash isn't
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}{}", user.uuid, "hash", "newer_hash"));
+        let stale = service.update_hash(&user.uuid, "hash", "newer_hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(stale, Err(UserError::HashConflict(current)) if current == "new_hash"));
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}{}", user.uuid, "made_up", "newer_hash"));
+        let guessed = service.update_hash(&user.uuid, "made_up", "newer_hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(guessed, Err(UserError::HashMismatch)));
+
+        // a signature by another key
+        let other = Sessionless::new();
+        let signature = sign(&other, &timestamp, &format!("{}{}", user.uuid, "new_hash"));
+        let forged = service.delete(&user.uuid, "new_hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(forged, Err(UserError::Auth)));
+
+        // a signed request that has gone stale, or is from too far ahead
+        assert!(service.check_fresh(&timestamp).is_ok());
+        let stale = (clock.now_millis() - ALLOWED_MILLIS - 1).to_string();
+        assert!(matches!(service.check_fresh(&stale), Err(UserError::StaleTimestamp)));
+        let ahead = (clock.now_millis() + ALLOWED_MILLIS + 1).to_string();
+        assert!(matches!(service.check_fresh(&ahead), Err(UserError::StaleTimestamp)));
+        assert!(matches!(service.check_fresh("not a timestamp"), Err(UserError::StaleTimestamp)));
+
+        // delete
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}", user.uuid, "new_hash"));
+        service.delete(&user.uuid, "new_hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+
+        let gone = service.verify(&user.uuid, "new_hash", None, Auth::Resolver).await;
+        assert!(matches!(gone, Err(UserError::NotFound)));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key() {
+        let storage_uri = storage_uri("test_user_service_rotate_key");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let service = test_user_service(storage_uri.clone(), clock.clone());
+
+        let old = Sessionless::new();
+        let new = Sessionless::new();
+        let new_pub_key = new.public_key().to_string();
+        let timestamp = clock.now_millis().to_string();
+        let user = service.create(&old.public_key().to_string(), "hash", None, Auth::Resolver).await.unwrap();
+
+        let fields = format!("rotateKey{}{}{}", user.uuid, "hash", new_pub_key);
+        let old_signature = sign(&old, &timestamp, &fields);
+        let new_signature = sign(&new, &timestamp, &fields);
+
+        // a rotation can't be passed off as an update to the new key as hash
+        let result = service.update_hash(&user.uuid, "hash", &new_pub_key, None, Auth::Signed { timestamp: &timestamp, signature: &old_signature }).await;
+        assert!(matches!(result, Err(UserError::Auth)));
+
+        // the new key has to sign too
+        let forged = sign(&Sessionless::new(), &timestamp, &fields);
+        let result = service.rotate_key(&user.uuid, "hash", &new_pub_key, &forged, Auth::Signed { timestamp: &timestamp, signature: &old_signature }).await;
+        assert!(matches!(result, Err(UserError::Auth)));
+        // and so does the old one
+        let result = service.rotate_key(&user.uuid, "hash", &new_pub_key, &new_signature, Auth::Signed { timestamp: &timestamp, signature: &new_signature }).await;
+        assert!(matches!(result, Err(UserError::Auth)));
+        // the resolver can't hand over a key
+        let result = service.rotate_key(&user.uuid, "hash", &new_pub_key, &new_signature, Auth::Resolver).await;
+        assert!(matches!(result, Err(UserError::Auth)));
+
+        let rotated = service.rotate_key(&user.uuid, "hash", &new_pub_key, &new_signature, Auth::Signed { timestamp: &timestamp, signature: &old_signature }).await.unwrap();
+        assert_eq!(rotated.pub_key, new_pub_key);
+        assert_eq!(rotated.uuid, user.uuid);
+
+        // the old key no longer verifies, the new one does
+        let signature = sign(&old, &timestamp, &format!("{}{}", user.uuid, "hash"));
+        let result = service.verify(&user.uuid, "hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(result, Err(UserError::Auth)));
+        let signature = sign(&new, &timestamp, &format!("{}{}", user.uuid, "hash"));
+        let verified = service.verify(&user.uuid, "hash", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(verified, rotated);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        let storage_uri = storage_uri("test_user_service_state");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let service = test_user_service(storage_uri.clone(), clock.clone());
+
+        let sessionless = Sessionless::new();
+        let timestamp = clock.now_millis().to_string();
+        let user = service.create(&sessionless.public_key().to_string(), "hash", None, Auth::Resolver).await.unwrap();
+
+        let signature = sign(&sessionless, &timestamp, &format!("getState{}{}", user.uuid, "hash"));
+        let result = service.get_state(&user.uuid, "hash", Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(result, Err(UserError::NoState)));
+
+        let signature = sign(&sessionless, &timestamp, &format!("putState{}{}{}", user.uuid, "hash", "encrypted"));
+        let saved = service.put_state(&user.uuid, "hash", "encrypted", Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(saved, UserState { hash: "hash".to_string(), state: "encrypted".to_string(), version: 1 });
+
+        let signature = sign(&sessionless, &(clock.now_millis() + 1).to_string(), &format!("getState{}{}", user.uuid, "hash"));
+        let state = service.get_state(&user.uuid, "hash", Auth::Signed { timestamp: &(clock.now_millis() + 1).to_string(), signature: &signature }).await.unwrap();
+        assert_eq!(state, saved);
+
+        // only for the current hash
+        let signature = sign(&sessionless, &timestamp, &format!("getState{}{}", user.uuid, "other"));
+        let result = service.get_state(&user.uuid, "other", Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(result, Err(UserError::HashMismatch)));
+
+        // test_common::MAX_STATE_SIZE
+        let too_large = "x".repeat(MAX_STATE_SIZE + 1);
+        let signature = sign(&sessionless, &timestamp, &format!("putState{}{}{}", user.uuid, "hash", too_large));
+        let result = service.put_state(&user.uuid, "hash", &too_large, Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(result, Err(UserError::StateTooLarge)));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let storage_uri = storage_uri("test_user_service_rollback");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let service = test_user_service(storage_uri.clone(), clock.clone());
+
+        let sessionless = Sessionless::new();
+        let timestamp = clock.now_millis().to_string();
+        let user = service.create(&sessionless.public_key().to_string(), "good", None, Auth::Resolver).await.unwrap();
+        let replaced_at = clock.now_millis();
+        service.update_hash(&user.uuid, "good", "bad", None, Auth::Resolver).await.unwrap();
+
+        let signature = sign(&sessionless, &timestamp, &format!("history{}{}", user.uuid, "bad"));
+        let listed = service.history(&user.uuid, "bad", Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(listed.history, vec![HashRecord { hash: "good".to_string(), timestamp: replaced_at }]);
+
+        // only hashes from the history
+        let signature = sign(&sessionless, &timestamp, &format!("rollback{}{}{}", user.uuid, "bad", "never"));
+        let result = service.rollback(&user.uuid, "bad", "never", Auth::Signed { timestamp: &timestamp, signature: &signature }).await;
+        assert!(matches!(result, Err(UserError::NotInHistory)));
+
+        clock.advance(1);
+        let signature = sign(&sessionless, &timestamp, &format!("rollback{}{}{}", user.uuid, "bad", "good"));
+        let rolled_back = service.rollback(&user.uuid, "bad", "good", Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(rolled_back.hash, "good");
+        assert_eq!(rolled_back.history, vec![HashRecord { hash: "bad".to_string(), timestamp: replaced_at + 1 }]);
+
+        // the index follows the hash
+        let signature = sign(&sessionless, &timestamp, &format!("{}{}", sessionless.public_key(), "good"));
+        let found = service.create(&sessionless.public_key().to_string(), "good", None, Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+        assert_eq!(found.uuid, user.uuid);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let storage_uri = storage_uri("test_user_service_lockout");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let lockout = LockoutPolicy { max_failures: 3, duration: Duration::from_secs(60) };
+        let service = UserService::new(UserClient::new(storage_uri.clone()), ReplayCache::new(100), clock.clone(), Duration::from_secs(600), MAX_STATE_SIZE, 10, lockout);
+
+        let user = service.create("pub_key", "good", None, Auth::Resolver).await.unwrap();
+
+        // a match resets the count
+        for _ in 0..2 {
+            assert!(matches!(service.verify(&user.uuid, "guess", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        }
+        assert!(service.verify(&user.uuid, "good", None, Auth::Resolver).await.is_ok());
+        for _ in 0..2 {
+            assert!(matches!(service.verify(&user.uuid, "guess", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        }
+        // slots count too
+        service.create("pub_key", "good", Some("settings"), Auth::Resolver).await.unwrap();
+        assert!(matches!(service.verify(&user.uuid, "guess", Some("settings"), Auth::Resolver).await, Err(UserError::HashMismatch)));
+
+        // even the right hash is refused until the cool-down is over
+        assert!(matches!(service.verify(&user.uuid, "good", None, Auth::Resolver).await, Err(UserError::LockedOut)));
+        clock.advance(59_999);
+        assert!(matches!(service.verify(&user.uuid, "good", None, Auth::Resolver).await, Err(UserError::LockedOut)));
+        clock.advance(1);
+        assert!(service.verify(&user.uuid, "good", None, Auth::Resolver).await.is_ok());
+
+        // and survives a restart
+        for _ in 0..3 {
+            assert!(matches!(service.verify(&user.uuid, "guess", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        }
+        let restarted = UserService::new(UserClient::new(storage_uri.clone()), ReplayCache::new(100), clock.clone(), Duration::from_secs(600), MAX_STATE_SIZE, 10, lockout);
+        assert!(matches!(restarted.verify(&user.uuid, "good", None, Auth::Resolver).await, Err(UserError::LockedOut)));
+
+        // an update resets the count
+        clock.advance(60_000);
+        for _ in 0..2 {
+            assert!(matches!(service.verify(&user.uuid, "guess", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        }
+        service.update_hash(&user.uuid, "good", "better", None, Auth::Resolver).await.unwrap();
+        assert!(matches!(service.verify(&user.uuid, "guess", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        assert!(service.verify(&user.uuid, "better", None, Auth::Resolver).await.is_ok());
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    static ROUTES: [&str; 10] = ["verify", "updateHash", "history", "rollback", "delete", "getState", "putState", "revokeKey", "addKey", "rotateKey"];
+
+    // The operation behind route on the user, with hash and signed by sessionless at timestamp
+    async fn call_route(service: &UserService, route: &str, sessionless: &Sessionless, uuid: &str, hash: &str, timestamp: &str) -> Result<(), UserError> {
+        let device = Sessionless::new();
+        let device_key = device.public_key().to_string();
+        let signed = |fields: String| sign(sessionless, timestamp, &fields);
+
+        match route {
+            "verify" => {
+                let signature = signed(format!("{}{}", uuid, hash));
+                service.verify(uuid, hash, None, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "updateHash" => {
+                let signature = signed(format!("{}{}{}", uuid, hash, "next"));
+                service.update_hash(uuid, hash, "next", None, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "history" => {
+                let signature = signed(format!("history{}{}", uuid, hash));
+                service.history(uuid, hash, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "rollback" => {
+                let signature = signed(format!("rollback{}{}{}", uuid, hash, "earlier"));
+                service.rollback(uuid, hash, "earlier", Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "delete" => {
+                let signature = signed(format!("{}{}", uuid, hash));
+                service.delete(uuid, hash, None, Auth::Signed { timestamp, signature: &signature }).await
+            },
+            "getState" => {
+                let signature = signed(format!("getState{}{}", uuid, hash));
+                service.get_state(uuid, hash, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "putState" => {
+                let signature = signed(format!("putState{}{}{}", uuid, hash, "state"));
+                service.put_state(uuid, hash, "state", Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "revokeKey" => {
+                let signature = signed(format!("revokeKey{}{}{}", uuid, hash, device_key));
+                service.revoke_key(uuid, hash, &device_key, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ())
+            },
+            "addKey" | "rotateKey" => {
+                let fields = format!("{}{}{}{}", route, uuid, hash, device_key);
+                let (signature, new_signature) = (signed(fields.clone()), sign(&device, timestamp, &fields));
+                match route {
+                    "addKey" => service.add_key(uuid, hash, &device_key, &new_signature, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ()),
+                    _ => service.rotate_key(uuid, hash, &device_key, &new_signature, Auth::Signed { timestamp, signature: &signature }).await.map(|_| ()),
+                }
+            },
+            _ => panic!("Unknown route {}", route),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lockout_every_route() {
+        let storage_uri = storage_uri("test_user_service_lockout_every_route");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let lockout = LockoutPolicy { max_failures: 3, duration: Duration::from_secs(60) };
+        let service = UserService::new(UserClient::new(storage_uri.clone()), ReplayCache::new(100), clock.clone(), Duration::from_secs(600), MAX_STATE_SIZE, 10, lockout);
+        let mut sent = 0;
+        let mut timestamp = || {
+            sent += 1;
+            (clock.now_millis() + sent).to_string()
+        };
+
+        for route in ROUTES {
+            // every route counts guesses towards the lockout
+            let sessionless = Sessionless::new();
+            let user = service.create(&sessionless.public_key().to_string(), "good", None, Auth::Resolver).await.unwrap();
+            for _ in 0..3 {
+                let result = call_route(&service, route, &sessionless, &user.uuid, "guess", &timestamp()).await;
+                assert!(matches!(result, Err(UserError::HashMismatch)), "{} answered {:?}", route, result);
+            }
+            let result = call_route(&service, "verify", &sessionless, &user.uuid, "good", &timestamp()).await;
+            assert!(matches!(result, Err(UserError::LockedOut)), "{} didn't lock out", route);
+
+            // and refuses a locked out user, even with the right hash
+            let sessionless = Sessionless::new();
+            let user = service.create(&sessionless.public_key().to_string(), "good", None, Auth::Resolver).await.unwrap();
+            for _ in 0..3 {
+                let result = call_route(&service, "verify", &sessionless, &user.uuid, "guess", &timestamp()).await;
+                assert!(matches!(result, Err(UserError::HashMismatch)));
+            }
+            let result = call_route(&service, route, &sessionless, &user.uuid, "good", &timestamp()).await;
+            assert!(matches!(result, Err(UserError::LockedOut)), "{} answered {:?}", route, result);
+        }
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_audit_key_changes() {
+        let storage_uri = storage_uri("test_user_service_audit_key_changes");
+        let clock = Arc::new(FixedClock::new(Utc::now().timestamp_millis()));
+        let service = test_user_service(storage_uri.clone(), clock.clone());
+        let timestamp = clock.now_millis().to_string();
+
+        let (first, device, next) = (Sessionless::new(), Sessionless::new(), Sessionless::new());
+        let key = |sessionless: &Sessionless| sessionless.public_key().to_string();
+        let user = service.create(&key(&first), "hash", None, Auth::Resolver).await.unwrap();
+
+        let fields = format!("addKey{}{}{}", user.uuid, "hash", key(&device));
+        let signature = sign(&first, &timestamp, &fields);
+        let auth = Auth::Signed { timestamp: &timestamp, signature: &signature };
+        service.add_key(&user.uuid, "hash", &key(&device), &sign(&device, &timestamp, &fields), auth).await.unwrap();
+
+        let fields = format!("rotateKey{}{}{}", user.uuid, "hash", key(&next));
+        let signature = sign(&first, &timestamp, &fields);
+        let auth = Auth::Signed { timestamp: &timestamp, signature: &signature };
+        service.rotate_key(&user.uuid, "hash", &key(&next), &sign(&next, &timestamp, &fields), auth).await.unwrap();
+
+        let signature = sign(&next, &timestamp, &format!("revokeKey{}{}{}", user.uuid, "hash", key(&device)));
+        service.revoke_key(&user.uuid, "hash", &key(&device), Auth::Signed { timestamp: &timestamp, signature: &signature }).await.unwrap();
+
+        // each change is recorded with the key that signed it
+        let audit_log = AuditLog::new(UserClient::new(storage_uri.clone()).client);
+        let records = audit_log.records(1, 10, Some(&user.uuid)).await.unwrap();
+        let recorded = records.into_iter().map(|record| (record.action, record.pub_key)).collect::<Vec<_>>();
+        assert_eq!(recorded, vec![
+            (AuditAction::Create, None),
+            (AuditAction::AddKey, Some(key(&first))),
+            (AuditAction::RotateKey, Some(key(&first))),
+            (AuditAction::RevokeKey, Some(key(&next))),
+        ]);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_audit_failure() {
+        let storage_uri = storage_uri("test_user_service_audit_failure");
+        let service = test_user_service(storage_uri.clone(), Arc::new(FixedClock::new(0)));
+
+        // audit storage fails: the first record can't be read or written
+        tokio::fs::create_dir_all(format!("{}/audit:{:020}", storage_uri, 1)).await.unwrap();
+        let audit_log = AuditLog::new(UserClient::new(storage_uri.clone()).client);
+        assert!(audit_log.append(AuditEntry { action: AuditAction::Create, user_uuid: "1234".to_string(), slot: None, pub_key: None }, 0).await.is_err());
+
+        // the changes are made, so they are reported as made
+        let user = service.create("pub_key", "hash", None, Auth::Resolver).await.unwrap();
+        let updated = service.update_hash(&user.uuid, "hash", "new_hash", None, Auth::Resolver).await.unwrap();
+        assert_eq!(updated.hash, "new_hash");
+
+        // and a client has no reason to retry into a second user
+        let found = service.create("pub_key", "new_hash", None, Auth::Resolver).await.unwrap();
+        assert_eq!(found.uuid, user.uuid);
+        assert_eq!(count_users(&storage_uri.to_string()).await, 1);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_lockout_off() {
+        let storage_uri = storage_uri("test_user_service_lockout_off");
+        let service = test_user_service(storage_uri.clone(), Arc::new(FixedClock::new(0)));
+        let user = service.create("pub_key", "good", None, Auth::Resolver).await.unwrap();
+
+        // mismatches are neither refused nor counted
+        for _ in 0..10 {
+            assert!(matches!(service.verify(&user.uuid, "guess", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        }
+        assert!(service.verify(&user.uuid, "good", None, Auth::Resolver).await.is_ok());
+        let user_client = UserClient::new(storage_uri.clone());
+        assert!(matches!(user_client.get_lockout(&user.uuid).await, Err(StorageError::NotFound(_))));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_slots() {
+        let storage_uri = storage_uri("test_user_service_slots");
+        let service = test_user_service(storage_uri.clone(), Arc::new(FixedClock::new(0)));
+
+        let user = service.create("pub_key", "hash", Some("settings"), Auth::Resolver).await.unwrap();
+        assert_eq!(user.slot_hash(Some("settings")), Some("hash"));
+        // creating again with another slot adds it to the same user
+        let user = service.create("pub_key", "hash", Some("progress"), Auth::Resolver).await.unwrap();
+        assert_eq!(user.slots.len(), 2);
+
+        let updated = service.update_hash(&user.uuid, "hash", "settings_hash", Some("settings"), Auth::Resolver).await.unwrap();
+        assert_eq!((updated.hash.as_str(), updated.slot_hash(Some("settings"))), ("hash", Some("settings_hash")));
+        assert!(updated.history.is_empty());
+
+        // slots keep no history, so a stale slot hash isn't told the current one
+        let result = service.update_hash(&user.uuid, "hash", "new_hash", Some("settings"), Auth::Resolver).await;
+        assert!(matches!(result, Err(UserError::HashMismatch)));
+        let result = service.verify(&user.uuid, "hash", Some("inventory"), Auth::Resolver).await;
+        assert!(matches!(result, Err(UserError::SlotNotFound)));
+        let result = service.verify(&user.uuid, "hash", Some(""), Auth::Resolver).await;
+        assert!(matches!(result, Err(UserError::InvalidSlot)));
+
+        // deleting a slot keeps the user
+        service.delete(&user.uuid, "settings_hash", Some("settings"), Auth::Resolver).await.unwrap();
+        let remaining = service.verify(&user.uuid, "hash", None, Auth::Resolver).await.unwrap();
+        assert_eq!(remaining.slots.keys().collect::<Vec<_>>(), vec!["progress"]);
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_resolver_auth() {
+        let storage_uri = storage_uri("test_user_service_resolver_auth");
+        let service = test_user_service(storage_uri.clone(), Arc::new(FixedClock::new(0)));
+        assert_eq!(service.allowed_time_difference().as_millis() as i64, ALLOWED_MILLIS);
+
+        // no signature needed, the hash still has to match
+        let user = service.create("not even a key", "hash", None, Auth::Resolver).await.unwrap();
+        assert!(matches!(service.update_hash(&user.uuid, "wrong", "new_hash", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+        assert!(matches!(service.delete(&user.uuid, "wrong", None, Auth::Resolver).await, Err(UserError::HashMismatch)));
+
+        let updated = service.update_hash(&user.uuid, "hash", "new_hash", None, Auth::Resolver).await.unwrap();
+        assert_eq!(updated.hash, "new_hash");
+        service.delete(&user.uuid, "new_hash", None, Auth::Resolver).await.unwrap();
+        assert!(matches!(service.delete(&user.uuid, "new_hash", None, Auth::Resolver).await, Err(UserError::NotFound)));
+
+        cleanup_test_files(&storage_uri.to_string()).await;
+    }
+}
@@ -0,0 +1,312 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{from_value, to_value, Client, KeyLocks, OptionalExt, StorageClient, StorageError, StorageResult};
+
+
+pub(crate) static AUDIT_STRING: &str = "audit";
+// The last record appended, so appends don't walk the chain
+pub(crate) static AUDIT_HEAD: &str = "audit_head";
+// What the first record follows
+pub static GENESIS_DIGEST: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// How often an append is retried when another process appends at the same time
+static MAX_APPEND_ATTEMPTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditAction {
+    Create,
+    UpdateHash,
+    Rollback,
+    Delete,
+    RotateKey,
+    AddKey,
+    RevokeKey,
+}
+
+// A mutation to record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub user_uuid: String,
+    pub slot: Option<String>,
+    // the key that signed; None when the resolver (Fount) authorized it
+    pub pub_key: Option<String>,
+}
+
+// One link of the chain, stored as audit:<sequence>. digest covers every other field,
+// previous_digest included, so changing or removing a record breaks every link after it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    // 1 for the first record, one more for every record after it
+    pub sequence: u64,
+    // milliseconds
+    pub timestamp: i64,
+    pub action: AuditAction,
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pub_key: Option<String>,
+    pub previous_digest: String,
+    pub digest: String,
+}
+
+impl AuditRecord {
+    fn new(sequence: u64, timestamp: i64, entry: AuditEntry, previous_digest: String) -> Self {
+        let AuditEntry { action, user_uuid, slot, pub_key } = entry;
+        let mut record = AuditRecord { sequence, timestamp, action, user_uuid, slot, pub_key, previous_digest, digest: String::new() };
+        record.digest = record.compute_digest();
+        record
+    }
+
+    // A JSON array of the fields, so no two different records digest the same input
+    pub fn compute_digest(&self) -> String {
+        let fields = serde_json::json!([
+            self.sequence, self.timestamp, self.action, self.user_uuid, self.slot, self.pub_key, self.previous_digest,
+        ]);
+        hex::encode(Sha256::digest(fields.to_string().as_bytes()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AuditHead {
+    sequence: u64,
+    digest: String,
+}
+
+// The outcome of walking the chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainCheck {
+    pub intact: bool,
+    // records that checked out, from the first
+    pub records: u64,
+    // the first record that is missing or doesn't link up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// Append only, hash chained record of account mutations, kept in the same storage as the users
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    client: Client,
+    // Serializes appends within this process
+    locks: Arc<KeyLocks>,
+}
+
+impl AuditLog {
+    pub fn new(client: Client) -> Self {
+        Self { client, locks: Arc::new(KeyLocks::new()) }
+    }
+
+    // Zero padded so keys sort in sequence
+    fn record_key(sequence: u64) -> String {
+        format!("{}:{:020}", AUDIT_STRING, sequence)
+    }
+
+    async fn get_record(&self, sequence: u64) -> StorageResult<Option<AuditRecord>> {
+        let key = AuditLog::record_key(sequence);
+        match self.client.get(&key).await.optional()? {
+            Some(value) => Ok(Some(from_value(&key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn head(&self) -> StorageResult<AuditHead> {
+        match self.client.get(AUDIT_HEAD).await.optional()? {
+            Some(value) => from_value(AUDIT_HEAD, value),
+            None => Ok(AuditHead { sequence: 0, digest: GENESIS_DIGEST.to_string() }),
+        }
+    }
+
+    // Chains entry onto the last record. The head is only a starting point: records other
+    // processes appended after it are skipped over, and the record's key can only be taken once
+    pub async fn append(&self, entry: AuditEntry, timestamp: i64) -> StorageResult<AuditRecord> {
+        let _guard = self.locks.lock(AUDIT_HEAD).await;
+
+        for _ in 0..MAX_APPEND_ATTEMPTS {
+            let mut last = self.head().await?;
+            while let Some(record) = self.get_record(last.sequence + 1).await? {
+                last = AuditHead { sequence: record.sequence, digest: record.digest };
+            }
+
+            let record = AuditRecord::new(last.sequence + 1, timestamp, entry.clone(), last.digest);
+            let key = AuditLog::record_key(record.sequence);
+            match self.client.compare_and_swap(&key, None, Some(to_value(&record)?)).await {
+                Ok(()) => {
+                    let head = AuditHead { sequence: record.sequence, digest: record.digest.clone() };
+                    self.client.set(AUDIT_HEAD, to_value(&head)?).await?;
+                    return Ok(record);
+                },
+                Err(StorageError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(StorageError::Conflict(AUDIT_HEAD.to_string()))
+    }
+
+    // Up to limit records from sequence from on, only user_uuid's when given
+    pub async fn records(&self, from: u64, limit: usize, user_uuid: Option<&str>) -> StorageResult<Vec<AuditRecord>> {
+        let mut records = vec![];
+        let mut sequence = from.max(1);
+        while records.len() < limit {
+            let Some(record) = self.get_record(sequence).await? else {
+                break;
+            };
+            if user_uuid.is_none_or(|uuid| record.user_uuid == uuid) {
+                records.push(record);
+            }
+            sequence += 1;
+        }
+        Ok(records)
+    }
+
+    // Walks the chain from the first record, checking every digest and link, and that it
+    // reaches the head. Records removed from the end along with the head can't be noticed
+    pub async fn verify(&self) -> StorageResult<ChainCheck> {
+        let broken = |records: u64, reason: &str| ChainCheck {
+            intact: false,
+            records,
+            broken_at: Some(records + 1),
+            reason: Some(reason.to_string()),
+        };
+
+        let mut previous = GENESIS_DIGEST.to_string();
+        let mut sequence = 1;
+        loop {
+            let record = match self.get_record(sequence).await {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(StorageError::Corrupt { .. }) => return Ok(broken(sequence - 1, "unreadable record")),
+                Err(e) => return Err(e),
+            };
+            if record.sequence != sequence {
+                return Ok(broken(sequence - 1, "record out of sequence"));
+            }
+            if record.previous_digest != previous {
+                return Ok(broken(sequence - 1, "record doesn't follow the one before it"));
+            }
+            if record.digest != record.compute_digest() {
+                return Ok(broken(sequence - 1, "record was changed"));
+            }
+            previous = record.digest;
+            sequence += 1;
+        }
+
+        let records = sequence - 1;
+        let head = self.head().await?;
+        if head.sequence > records {
+            return Ok(broken(records, "record is missing"));
+        }
+        Ok(ChainCheck { intact: true, records, broken_at: None, reason: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+
+    use super::*;
+    use crate::test_common::{cleanup_test_files, postgres_test_uri, storage_uri};
+
+    fn entry(action: AuditAction, user_uuid: &str) -> AuditEntry {
+        AuditEntry { action, user_uuid: user_uuid.to_string(), slot: None, pub_key: Some("pub_key".to_string()) }
+    }
+
+    async fn check_audit_log(uri: Uri) {
+        let audit_log = AuditLog::new(Client::new(uri.clone()));
+        assert_eq!(audit_log.verify().await.unwrap(), ChainCheck { intact: true, records: 0, broken_at: None, reason: None });
+
+        let first = audit_log.append(entry(AuditAction::Create, "1234"), 1000).await.expect("Failed to append");
+        assert_eq!(first.sequence, 1);
+        assert_eq!(first.previous_digest, GENESIS_DIGEST);
+        let second = audit_log.append(entry(AuditAction::Create, "5678"), 1001).await.expect("Failed to append");
+        assert_eq!(second.previous_digest, first.digest);
+
+        // carries on after a restart
+        let audit_log = AuditLog::new(Client::new(uri.clone()));
+        let third = audit_log.append(entry(AuditAction::UpdateHash, "1234"), 1002).await.expect("Failed to append");
+        assert_eq!(third.sequence, 3);
+        assert_eq!(third.previous_digest, second.digest);
+        assert!(audit_log.verify().await.unwrap().intact);
+
+        // paged and filtered
+        assert_eq!(audit_log.records(1, 100, None).await.unwrap(), vec![first.clone(), second.clone(), third.clone()]);
+        assert_eq!(audit_log.records(2, 1, None).await.unwrap(), vec![second.clone()]);
+        assert_eq!(audit_log.records(0, 100, Some("1234")).await.unwrap(), vec![first.clone(), third.clone()]);
+
+        // tampering breaks the chain where it happened
+        let mut changed = second.clone();
+        changed.user_uuid = "9999".to_string();
+        audit_log.client.set(&AuditLog::record_key(2), to_value(&changed).unwrap()).await.unwrap();
+        let check = audit_log.verify().await.unwrap();
+        assert!(!check.intact);
+        assert_eq!((check.records, check.broken_at), (1, Some(2)));
+
+        // and so does a record changed along with its digest
+        changed.digest = changed.compute_digest();
+        audit_log.client.set(&AuditLog::record_key(2), to_value(&changed).unwrap()).await.unwrap();
+        assert_eq!(audit_log.verify().await.unwrap().broken_at, Some(3));
+
+        // or one going missing
+        audit_log.client.set(&AuditLog::record_key(2), to_value(&second).unwrap()).await.unwrap();
+        audit_log.client.delete(&AuditLog::record_key(3)).await.unwrap();
+        let check = audit_log.verify().await.unwrap();
+        assert_eq!((check.intact, check.broken_at), (false, Some(3)));
+
+        // clean up, for storage shared between runs
+        for key in [AuditLog::record_key(1), AuditLog::record_key(2), AUDIT_HEAD.to_string()] {
+            audit_log.client.delete(&key).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let uri = storage_uri("audit_log");
+        check_audit_log(uri.clone()).await;
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_concurrently() {
+        let uri = storage_uri("audit_log_concurrently");
+        let audit_log = AuditLog::new(Client::new(uri.clone()));
+
+        let mut tasks = vec![];
+        for i in 0..16 {
+            let audit_log = audit_log.clone();
+            tasks.push(tokio::spawn(async move {
+                audit_log.append(entry(AuditAction::Create, &i.to_string()), i).await.expect("Failed to append")
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let check = audit_log.verify().await.unwrap();
+        assert!(check.intact);
+        assert_eq!(check.records, 16);
+
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs POSTGRES_TEST_URI"]
+    async fn test_postgres_audit_log() {
+        // Needs a database, see docker-compose.yml; the log is shared, so only check a fresh database
+        let uri = postgres_test_uri();
+        let audit_log = AuditLog::new(Client::new(uri.clone()));
+        if audit_log.head().await.unwrap().sequence > 0 {
+            return;
+        }
+        check_audit_log(uri).await;
+    }
+}
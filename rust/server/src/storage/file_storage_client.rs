@@ -1,19 +1,33 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use axum::http::Uri;
+use sessionless::Sessionless;
 use tokio::{fs::File, io::AsyncWriteExt};
 
-use super::StorageClient;
+use super::{KeyLocks, StorageClient, StorageError, StorageResult};
 
 
+// Values are written to ".{key}.{uuid}.tmp" and renamed into place
+static TEMP_SUFFIX: &str = ".tmp";
 
 #[derive(Debug, Clone)]
 pub struct FileStorageClient {
-    storage_uri: Uri,    
+    storage_uri: Uri,
+    // Serializes writers of the same file within this process
+    locks: Arc<KeyLocks>,
+    // fsync the directory after each rename so the rename itself survives a crash;
+    // enabled with ?sync_dir=true on the storage uri
+    sync_dir: bool,
 }
 
 impl FileStorageClient {
     pub fn new(storage_uri: Uri) -> Self {
-        Self { storage_uri }
+        let sync_dir = storage_uri.query()
+            .map(|query| query.split('&').any(|param| param == "sync_dir=true"))
+            .unwrap_or(false);
+
+        Self { storage_uri, locks: Arc::new(KeyLocks::new()), sync_dir }
     }
 
     pub fn dir(&self) -> String {
@@ -28,7 +42,7 @@ impl FileStorageClient {
         format!("{}/{}", self.dir(), key)
     }
 
-    pub async fn create_storage_dir(&self) -> anyhow::Result<bool> {
+    pub async fn create_storage_dir(&self) -> StorageResult<bool> {
         // Create the directory if it doesn't exist
         // returns true if the directory was created
         match tokio::fs::create_dir(self.dir()).await {
@@ -43,51 +57,147 @@ impl FileStorageClient {
         }
     }
 
-    pub async fn write(&self, key: &str, value: serde_json::Value) -> anyhow::Result<()> {
-        self.create_storage_dir().await.expect("Failed to create storage directory");
+    fn temp_file_path(&self, key: &str) -> String {
+        format!("{}/.{}.{}{}", self.dir(), key, Sessionless::generate_uuid(), TEMP_SUFFIX)
+    }
 
-        let file = match tokio::fs::File::create(self.file_path(key)).await {
-            Ok(file) => file,
-            Err(e) => return Err(e.into()),
-        };
+    // Writes to a temp file, fsyncs it and renames it over the target, so a crash
+    // leaves either the old or the new value in place and never a partial file
+    pub async fn write(&self, key: &str, value: serde_json::Value) -> StorageResult<()> {
+        self.create_storage_dir().await?;
 
-        self.serialize_and_write(value, file).await
+        let temp_path = self.temp_file_path(key);
+        let file = tokio::fs::File::create_new(&temp_path).await?;
+
+        if let Err(e) = self.serialize_and_write(value, file).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+
+        tokio::fs::rename(&temp_path, self.file_path(key)).await?;
+
+        if self.sync_dir {
+            File::open(self.dir()).await?.sync_all().await?;
+        }
+        Ok(())
     }
 
-    pub async fn serialize_and_write(&self, value: serde_json::Value, mut file: File) -> anyhow::Result<()> {
-        let serialized = serde_json::to_string(&value).expect("Failed to serialize value");
+    pub async fn serialize_and_write(&self, value: serde_json::Value, mut file: File) -> StorageResult<()> {
+        let serialized = serde_json::to_string(&value).map_err(std::io::Error::other)?;
 
-        match file.write_all(serialized.as_bytes()).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+        file.write_all(serialized.as_bytes()).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    // Startup pass over the storage directory: temp files only survive a crash
+    // between creating and renaming them, so they are removed and reported
+    pub async fn recover(&self) -> StorageResult<Vec<String>> {
+        let mut dir = match tokio::fs::read_dir(self.dir()).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut leftovers = vec![];
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') && name.ends_with(TEMP_SUFFIX) {
+                tokio::fs::remove_file(entry.path()).await?;
+                leftovers.push(name);
+            }
         }
+        Ok(leftovers)
     }
 }
 
 #[async_trait]
 impl StorageClient for FileStorageClient {
-    async fn get(&self, key: &str) -> Option<serde_json::Value> {
-
+    async fn get(&self, key: &str) -> StorageResult<serde_json::Value> {
         // Read file to string -> serialize to V
-        match tokio::fs::read(self.file_path(key)).await {
-            Ok(data) => {
-                let data = String::from_utf8(data).expect("Data is not valid UTF-8");
-                // deserialize the data to V
-                match serde_json::from_str(&data) {
-                    Ok(value) => Some(value),
-                    Err(_) => None,
-                }
+        let data = match tokio::fs::read(self.file_path(key)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(StorageError::NotFound(key.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+
+        let data = String::from_utf8(data).map_err(|e| StorageError::corrupt(key, e))?;
+        // deserialize the data to V
+        serde_json::from_str(&data).map_err(|e| StorageError::corrupt(key, e))
+    }
+
+    async fn set(&self, key: &str, value: serde_json::Value) -> StorageResult<()> {
+        let _guard = self.locks.lock(key).await;
+        self.write(key, value).await
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        let _guard = self.locks.lock(key).await;
+        self.remove(key).await
+    }
+
+    async fn compare_and_swap(&self, key: &str, expected: Option<serde_json::Value>, new: Option<serde_json::Value>) -> StorageResult<()> {
+        let _guard = self.locks.lock(key).await;
+
+        let current = match self.get(key).await {
+            Ok(value) => Some(value),
+            Err(StorageError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if current != expected {
+            return Err(StorageError::Conflict(key.to_string()));
+        }
+
+        match new {
+            Some(value) => self.write(key, value).await,
+            None if expected.is_some() => self.remove(key).await,
+            None => Ok(()),
+        }
+    }
+
+    // Temp files start with '.', so they never match a key prefix
+    async fn count(&self, prefix: &str) -> StorageResult<u64> {
+        let mut dir = match tokio::fs::read_dir(self.dir()).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut count = 0;
+        while let Some(entry) = dir.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with(prefix) {
+                count += 1;
             }
-            Err(_) => None, 
         }
+        Ok(count)
     }
 
-    async fn set(&self, key: &str, value: serde_json::Value) -> anyhow::Result<()> {
-        return self.write(key, value).await
+    async fn keys(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let mut dir = match tokio::fs::read_dir(self.dir()).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = vec![];
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) {
+                keys.push(name);
+            }
+        }
+        keys.sort();
+        Ok(keys)
     }
+}
 
-    async fn delete(&self, key: &str) -> bool {
-        tokio::fs::remove_file(self.file_path(key)).await.is_ok()
+impl FileStorageClient {
+    async fn remove(&self, key: &str) -> StorageResult<()> {
+        match tokio::fs::remove_file(self.file_path(key)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StorageError::NotFound(key.to_string())),
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
@@ -281,9 +391,9 @@ mod tests {
 
         client.create_storage_dir().await.expect("Failed to create storage directory");
 
-        // No data at first so should be none
+        // No data at first so should be not found
         let result = client.get(key).await;
-        assert!(result.is_none());
+        assert!(matches!(result, Err(StorageError::NotFound(_))));
 
         // write to file test with fs::write
         match tokio::fs::write(client.file_path(key), serde_json::to_string(&value).expect("Failed to serialize value")).await {
@@ -313,8 +423,8 @@ mod tests {
         client.set(key, value.clone()).await.expect("Failed to set value");
 
         match client.get(key).await {
-            Some(v) => assert_eq!(v, value.clone()),
-            None => assert!(false)
+            Ok(v) => assert_eq!(v, value.clone()),
+            Err(e) => panic!("Expected a value: {}", e)
         };
 
         // clean up
@@ -344,16 +454,113 @@ mod tests {
         assert!(check_path_exists(&client.file_path(key)).await);
 
         // delete
-        assert!(client.delete(key).await);
+        assert!(client.delete(key).await.is_ok());
 
         // file shouldn't exist
         assert!(!check_path_exists(&client.file_path(key)).await);
 
-        // delete: should be false
-        assert!(!client.delete(key).await);
+        // delete: nothing left to delete
+        assert!(matches!(client.delete(key).await, Err(StorageError::NotFound(_))));
 
         // clean up
         cleanup_test_files(&dir_path).await;
     }
 
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sync_dir_option() {
+        let client = FileStorageClient::new(Uri::from_static("/tmp"));
+        assert!(!client.sync_dir);
+
+        let client = FileStorageClient::new(Uri::from_static("/tmp?sync_dir=true"));
+        assert!(client.sync_dir);
+        // the query is not part of the directory
+        assert_eq!(client.file_path("test"), "/tmp/test");
+
+        let client = FileStorageClient::new(Uri::from_static("/tmp?sync_dir=false"));
+        assert!(!client.sync_dir);
+    }
+
+    #[tokio::test]
+    async fn test_write_leaves_no_temp_files() {
+        let current_directory = std::env::current_dir().expect("Failed to get current directory");
+        let dir_path = format!("{}/write_leaves_no_temp_files", current_directory.display());
+        let uri = Uri::builder().path_and_query(format!("{}?sync_dir=true", dir_path)).build().unwrap();
+
+        let client = FileStorageClient::new(uri);
+
+        client.write("test", serde_json::json!({"j": "value"})).await.expect("Failed to write file");
+        client.write("test", serde_json::json!({"j": "new value"})).await.expect("Failed to write file");
+
+        let mut names = vec![];
+        let mut dir = tokio::fs::read_dir(&dir_path).await.expect("Failed to read dir");
+        while let Some(entry) = dir.next_entry().await.expect("Failed to read dir") {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        assert_eq!(names, vec!["test".to_string()]);
+        assert_eq!(client.get("test").await.unwrap(), serde_json::json!({"j": "new value"}));
+
+        // clean up
+        cleanup_test_files(&dir_path).await;
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let current_directory = std::env::current_dir().expect("Failed to get current directory");
+        let dir_path = format!("{}/recover", current_directory.display());
+        let uri = Uri::builder().path_and_query(dir_path.clone()).build().unwrap();
+
+        let client = FileStorageClient::new(uri);
+
+        // no directory yet, nothing to recover
+        assert!(client.recover().await.expect("Failed to recover").is_empty());
+
+        let value = serde_json::json!({"j": "value"});
+        client.set("test", value.clone()).await.expect("Failed to set value");
+
+        // a crash after creating the temp file but before the rename
+        let leftover = ".test.1234.tmp";
+        tokio::fs::write(format!("{}/{}", dir_path, leftover), "{\"j\": \"ha").await.expect("Failed to write file");
+
+        // the committed value is untouched
+        assert_eq!(client.get("test").await.unwrap(), value.clone());
+
+        let recovered = client.recover().await.expect("Failed to recover");
+        assert_eq!(recovered, vec![leftover.to_string()]);
+        assert!(!check_path_exists(&format!("{}/{}", dir_path, leftover)).await);
+        assert_eq!(client.get("test").await.unwrap(), value);
+
+        // clean up
+        cleanup_test_files(&dir_path).await;
+    }
+
+    #[tokio::test]
+    async fn test_get_corrupt() {
+        let current_directory = std::env::current_dir().expect("Failed to get current directory");
+        let dir_path = format!("{}/get_corrupt", current_directory.display());
+        let uri = Uri::builder().path_and_query(dir_path.clone()).build().unwrap();
+
+        let client = FileStorageClient::new(uri);
+        client.create_storage_dir().await.expect("Failed to create storage directory");
+
+        // not UTF-8
+        tokio::fs::write(client.file_path("binary"), [0xff, 0xfe, 0x00]).await.expect("Failed to write file");
+        assert!(matches!(client.get("binary").await, Err(StorageError::Corrupt { .. })));
+
+        // cut off mid write
+        tokio::fs::write(client.file_path("partial"), "{\"j\": \"va").await.expect("Failed to write file");
+        assert!(matches!(client.get("partial").await, Err(StorageError::Corrupt { .. })));
+
+        // a corrupt value is never what a compare and swap expects
+        let value = serde_json::json!({"j": "value"});
+        let result = client.compare_and_swap("partial", None, Some(value.clone())).await;
+        assert!(matches!(result, Err(StorageError::Corrupt { .. })));
+
+        // but can be overwritten
+        client.set("partial", value.clone()).await.expect("Failed to set value");
+        assert_eq!(client.get("partial").await.unwrap(), value);
+
+        // clean up
+        cleanup_test_files(&dir_path).await;
+    }
+}
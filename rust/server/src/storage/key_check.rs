@@ -0,0 +1,12 @@
+use serde::{Serialize, Deserialize};
+
+
+// Where a pub_key + hash index entry points, for operators looking into the index
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyCheck {
+    #[serde(rename = "userUUID")]
+    pub user_uuid: String,
+    // whether that user still exists holding the pub_key, as its primary or a device key, and the hash as its hash
+    pub consistent: bool,
+}
@@ -0,0 +1,71 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, Weak}};
+
+use tokio::sync::OwnedMutexGuard;
+
+
+// One async mutex per storage key, created on demand.
+// Entries are only weakly held so keys nobody is waiting on are dropped again
+#[derive(Debug, Default)]
+pub struct KeyLocks {
+    locks: Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Waits for exclusive access to key; released when the guard is dropped
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().expect("Key locks poisoned");
+            locks.retain(|_, lock| lock.strong_count() > 0);
+
+            match locks.get(key).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(key.to_string(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_is_per_key() {
+        let locks = Arc::new(KeyLocks::new());
+
+        let guard = locks.lock("a").await;
+
+        // a different key is not blocked
+        let other = tokio::time::timeout(Duration::from_millis(50), locks.lock("b")).await;
+        assert!(other.is_ok());
+
+        // the same key waits until the guard is dropped
+        let same = tokio::time::timeout(Duration::from_millis(50), locks.lock("a")).await;
+        assert!(same.is_err());
+
+        drop(guard);
+        let same = tokio::time::timeout(Duration::from_millis(50), locks.lock("a")).await;
+        assert!(same.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unused_locks_are_dropped() {
+        let locks = KeyLocks::new();
+
+        drop(locks.lock("a").await);
+        drop(locks.lock("b").await);
+
+        assert!(locks.locks.lock().unwrap().len() <= 1);
+    }
+}
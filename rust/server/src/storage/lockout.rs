@@ -0,0 +1,18 @@
+use serde::{Serialize, Deserialize};
+
+
+// Consecutive hash mismatches for a user, stored under its own key next to the user
+// so a lockout survives restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Lockout {
+    // mismatches since the last match, update or lockout
+    pub failures: u32,
+    // milliseconds; hash checks are refused until then
+    pub locked_until: i64,
+}
+
+impl Lockout {
+    pub fn is_locked(&self, now: i64) -> bool {
+        now < self.locked_until
+    }
+}
@@ -1,13 +1,31 @@
 mod storage_client;
+mod storage_error;
 mod file_storage_client;
+mod postgres_storage_client;
 mod client;
 mod user_client;
 mod user;
+mod user_state;
+mod lockout;
+mod user_stats;
+mod key_check;
+mod audit_log;
 mod pub_key;
+mod key_locks;
+mod replay_cache;
 
 pub use storage_client::*;
+pub use storage_error::*;
 pub use file_storage_client::*;
+pub use postgres_storage_client::*;
 pub use user_client::*;
 pub use client::*;
 pub use user::*;
-pub use pub_key::*;
\ No newline at end of file
+pub use user_state::*;
+pub use lockout::*;
+pub use user_stats::*;
+pub use key_check::*;
+pub use audit_log::*;
+pub use pub_key::*;
+pub use key_locks::*;
+pub use replay_cache::*;
@@ -0,0 +1,261 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::http::Uri;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::sync::OnceCell;
+
+use super::{StorageClient, StorageError, StorageResult, PUB_KEY_STRING, USER_STRING};
+
+
+static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+// Where a storage key lives in the database
+#[derive(Debug, PartialEq, Eq)]
+struct Location<'a> {
+    table: &'static str,
+    key_column: &'static str,
+    value_column: &'static str,
+    id: &'a str,
+}
+
+impl<'a> Location<'a> {
+    fn for_key(key: &'a str) -> Self {
+        match key.split_once(':') {
+            // user:{uuid} -> users table
+            Some((prefix, uuid)) if prefix == USER_STRING => {
+                Location { table: "users", key_column: "uuid", value_column: "data", id: uuid }
+            },
+            // pub_key:{digest} -> pub_keys table
+            Some((prefix, digest)) if prefix == PUB_KEY_STRING => {
+                Location { table: "pub_keys", key_column: "digest", value_column: "data", id: digest }
+            },
+            // everything else -> entries table
+            _ => Location { table: "entries", key_column: "key", value_column: "value", id: key },
+        }
+    }
+
+    fn select(&self) -> String {
+        format!("SELECT {} FROM {} WHERE {} = $1", self.value_column, self.table, self.key_column)
+    }
+
+    fn upsert(&self) -> String {
+        format!(
+            "INSERT INTO {table} ({key}, {value}) VALUES ($1, $2) ON CONFLICT ({key}) DO UPDATE SET {value} = EXCLUDED.{value}",
+            table = self.table, key = self.key_column, value = self.value_column,
+        )
+    }
+
+    fn insert_if_absent(&self) -> String {
+        format!(
+            "INSERT INTO {table} ({key}, {value}) VALUES ($1, $2) ON CONFLICT ({key}) DO NOTHING",
+            table = self.table, key = self.key_column, value = self.value_column,
+        )
+    }
+
+    fn update_if_equal(&self) -> String {
+        format!(
+            "UPDATE {table} SET {value} = $3 WHERE {key} = $1 AND {value} = $2",
+            table = self.table, key = self.key_column, value = self.value_column,
+        )
+    }
+
+    // id with the LIKE wildcards escaped, for matching on a prefix
+    fn escaped_id(&self) -> String {
+        self.id.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+    }
+
+    // Rows whose key starts with id, given as $1 with LIKE wildcards escaped
+    fn count_prefix(&self) -> String {
+        format!("SELECT count(*) FROM {} WHERE {} LIKE $1 || '%'", self.table, self.key_column)
+    }
+
+    fn select_prefix(&self) -> String {
+        format!("SELECT {key} FROM {} WHERE {key} LIKE $1 || '%' ORDER BY {key}", self.table, key = self.key_column)
+    }
+
+    fn delete(&self) -> String {
+        format!("DELETE FROM {} WHERE {} = $1", self.table, self.key_column)
+    }
+
+    fn delete_if_equal(&self) -> String {
+        format!("DELETE FROM {} WHERE {} = $1 AND {} = $2", self.table, self.key_column, self.value_column)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PostgresStorageClient {
+    pool: PgPool,
+    migrated: Arc<OnceCell<()>>,
+}
+
+impl PostgresStorageClient {
+    pub fn new(storage_uri: Uri) -> Self {
+        // Connections are opened on first use, so constructing the client never blocks
+        let pool = PgPoolOptions::new()
+            .connect_lazy(&storage_uri.to_string())
+            .expect("STORAGE_URI must be a valid postgres connection string");
+
+        Self { pool, migrated: Arc::new(OnceCell::new()) }
+    }
+
+    // Runs the embedded migrations once per client
+    pub async fn migrate(&self) -> StorageResult<()> {
+        self.migrated.get_or_try_init(|| async {
+            MIGRATOR.run(&self.pool).await
+        }).await.map_err(std::io::Error::other)?;
+        Ok(())
+    }
+}
+
+// Database failures surface as I/O errors; the database itself never holds a corrupt JSONB value
+fn io_error(e: sqlx::Error) -> StorageError {
+    StorageError::Io(std::io::Error::other(e))
+}
+
+// A statement that changed no rows means the row wasn't as expected
+fn expect_one_row(key: &str, result: sqlx::postgres::PgQueryResult) -> StorageResult<()> {
+    match result.rows_affected() {
+        1 => Ok(()),
+        _ => Err(StorageError::Conflict(key.to_string())),
+    }
+}
+
+#[async_trait]
+impl StorageClient for PostgresStorageClient {
+    async fn get(&self, key: &str) -> StorageResult<serde_json::Value> {
+        self.migrate().await?;
+
+        let location = Location::for_key(key);
+        let value: Option<serde_json::Value> = sqlx::query_scalar(&location.select())
+            .bind(location.id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(io_error)?;
+        value.ok_or_else(|| StorageError::NotFound(key.to_string()))
+    }
+
+    async fn set(&self, key: &str, value: serde_json::Value) -> StorageResult<()> {
+        self.migrate().await?;
+
+        let location = Location::for_key(key);
+        sqlx::query(&location.upsert())
+            .bind(location.id)
+            .bind(value)
+            .execute(&self.pool)
+            .await
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        self.migrate().await?;
+
+        let location = Location::for_key(key);
+        let result = sqlx::query(&location.delete()).bind(location.id).execute(&self.pool).await.map_err(io_error)?;
+        match result.rows_affected() {
+            0 => Err(StorageError::NotFound(key.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    // Each case is a single conditional statement, so the database does the locking
+    async fn compare_and_swap(&self, key: &str, expected: Option<serde_json::Value>, new: Option<serde_json::Value>) -> StorageResult<()> {
+        self.migrate().await?;
+
+        let location = Location::for_key(key);
+        let result = match (expected, new) {
+            (None, Some(new)) => {
+                sqlx::query(&location.insert_if_absent()).bind(location.id).bind(new).execute(&self.pool).await
+            },
+            (Some(expected), Some(new)) => {
+                sqlx::query(&location.update_if_equal()).bind(location.id).bind(expected).bind(new).execute(&self.pool).await
+            },
+            (Some(expected), None) => {
+                sqlx::query(&location.delete_if_equal()).bind(location.id).bind(expected).execute(&self.pool).await
+            },
+            (None, None) => {
+                return match self.get(key).await {
+                    Ok(_) => Err(StorageError::Conflict(key.to_string())),
+                    Err(StorageError::NotFound(_)) => Ok(()),
+                    Err(e) => Err(e),
+                };
+            },
+        };
+        expect_one_row(key, result.map_err(io_error)?)
+    }
+
+    // "user:" and "pub_key:" count their whole table, other prefixes the matching entries
+    async fn count(&self, prefix: &str) -> StorageResult<u64> {
+        self.migrate().await?;
+
+        let location = Location::for_key(prefix);
+        let count: i64 = sqlx::query_scalar(&location.count_prefix())
+            .bind(location.escaped_id())
+            .fetch_one(&self.pool)
+            .await
+            .map_err(io_error)?;
+        Ok(count as u64)
+    }
+
+    async fn keys(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        self.migrate().await?;
+
+        let location = Location::for_key(prefix);
+        let ids: Vec<String> = sqlx::query_scalar(&location.select_prefix())
+            .bind(location.escaped_id())
+            .fetch_all(&self.pool)
+            .await
+            .map_err(io_error)?;
+        // rows of the users and pub_keys tables are keyed without the key's own prefix
+        let key_prefix = &prefix[..prefix.len() - location.id.len()];
+        Ok(ids.into_iter().map(|id| format!("{}{}", key_prefix, id)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::{PubKeyEntry, PubKeys, UserClient}, test_common::postgres_test_uri};
+
+    #[test]
+    fn test_location_for_key() {
+        let table_and_id = |key| {
+            let location = Location::for_key(key);
+            (location.table, location.id)
+        };
+
+        assert_eq!(table_and_id("user:1234"), ("users", "1234"));
+        assert_eq!(table_and_id("pub_key:abcd"), ("pub_keys", "abcd"));
+        assert_eq!(table_and_id("keys"), ("entries", "keys"));
+        assert_eq!(table_and_id("user"), ("entries", "user"));
+        assert_eq!(table_and_id("username:1234"), ("entries", "username:1234"));
+        assert_eq!(table_and_id("test"), ("entries", "test"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs POSTGRES_TEST_URI"]
+    async fn test_pub_key_rows() {
+        let uri = postgres_test_uri();
+        let user_client = UserClient::new(uri.clone());
+        let client = PostgresStorageClient::new(uri);
+
+        let user_uuid = sessionless::Sessionless::generate_uuid().to_string();
+        let key = PubKeys::key("hash", &user_uuid);
+        user_client.put_key(&key, &user_uuid).await.expect("Failed to put key");
+
+        // one row per entry, queryable by user
+        let rows: Vec<serde_json::Value> = sqlx::query_scalar("SELECT data FROM pub_keys WHERE user_uuid = $1")
+            .bind(&user_uuid)
+            .fetch_all(&client.pool)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        let entry: PubKeyEntry = serde_json::from_value(rows[0].clone()).unwrap();
+        assert_eq!(entry, PubKeyEntry { key: key.clone(), user_uuid: user_uuid.clone() });
+
+        assert_eq!(user_client.clone().get_user_uuid(&key).await, Some(user_uuid.clone()));
+        user_client.remove_key(&key, &user_uuid).await.expect("Failed to remove key");
+        assert!(user_client.clone().get_user_uuid(&key).await.is_none());
+    }
+}
@@ -3,22 +3,29 @@ use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 
 
-// Associates a user uuid to a pub_key
+// A single entry of the pub_key + hash index, stored under its own key
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PubKeyEntry {
+    // pub_key + hash, see PubKeys::key
+    pub key: String,
+    pub user_uuid: String,
+}
+
+// Associates a user uuid to a pub_key
+// This is the layout of the legacy single "keys" blob, and a convenient view of the whole index
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PubKeys {
     // {pub_key + hash: user_uuid}
     pub_keys: HashMap<String, String>
 }
 
 impl PubKeys {
-    
-    pub fn default() -> Self {
-        Self { pub_keys: HashMap::new() }
-    }
+
     pub fn key(hash: &str, pub_key: &str) -> String {
         format!("{}{}", hash, pub_key)
     }
 
+    #[cfg(test)]
     pub fn add_user_uuid(&mut self, user_uuid: &str,  key: &str) -> &mut Self {
         self.pub_keys.insert(key.to_string(), user_uuid.to_string());
         self
@@ -28,12 +35,12 @@ impl PubKeys {
         self.pub_keys.get(key)
     }
 
-    pub fn remove_key(&mut self, key: &str) -> Option<String> {
-        self.pub_keys.remove(key)
-    }
-
     pub fn num_keys(&self) -> usize {
         self.pub_keys.len()
     }
+
+    pub fn entries(&self) -> impl Iterator<Item = PubKeyEntry> + '_ {
+        self.pub_keys.iter().map(|(key, user_uuid)| PubKeyEntry { key: key.clone(), user_uuid: user_uuid.clone() })
+    }
 }
 
@@ -0,0 +1,212 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{Client, OptionalExt, StorageClient, StorageError, StorageResult};
+
+
+pub(crate) static REPLAY_STRING: &str = "replay";
+
+// What is kept for a seen (pub_key, signature) pair
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReplayEntry {
+    // unix millis after which the signed request is stale anyway
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    expires_at: HashMap<String, i64>,
+    // insertion order, oldest first
+    order: VecDeque<String>,
+}
+
+// Remembers signatures until their request could no longer pass the timestamp check,
+// so the same signed request is only accepted once.
+// The in-memory set holds at most `capacity` entries; with a storage client the entries
+// are also written as `replay:<digest>` so they survive a restart
+#[derive(Debug)]
+pub struct ReplayCache {
+    capacity: usize,
+    seen: Mutex<Seen>,
+    storage: Option<Client>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, seen: Mutex::new(Seen::default()), storage: None }
+    }
+
+    pub fn persistent(capacity: usize, storage: Client) -> Self {
+        Self { capacity, seen: Mutex::new(Seen::default()), storage: Some(storage) }
+    }
+
+    fn key(pub_key: &str, signature: &str) -> String {
+        let digest = Sha256::new().chain_update(pub_key.as_bytes()).chain_update(signature.as_bytes()).finalize();
+        format!("{}:{}", REPLAY_STRING, hex::encode(digest))
+    }
+
+    // Records the pair and returns true the first time it is seen before expires_at,
+    // false for a replay
+    pub async fn first_use(&self, pub_key: &str, signature: &str, expires_at: i64, now_millis: i64) -> StorageResult<bool> {
+        let key = Self::key(pub_key, signature);
+
+        let expired = {
+            let mut seen = self.seen.lock().expect("Replay cache poisoned");
+            if seen.expires_at.get(&key).is_some_and(|expiry| *expiry >= now_millis) {
+                return Ok(false);
+            }
+            self.insert(&mut seen, &key, expires_at, now_millis)
+        };
+
+        if let Some(storage) = &self.storage {
+            for expired_key in expired {
+                storage.delete(&expired_key).await.optional()?;
+            }
+            if !Self::persist(storage, &key, expires_at, now_millis).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Adds key, evicting expired entries and then the oldest ones to stay within capacity.
+    // Returns the keys that expired
+    fn insert(&self, seen: &mut Seen, key: &str, expires_at: i64, now_millis: i64) -> Vec<String> {
+        let mut expired = vec![];
+        let Seen { expires_at: expiries, order } = seen;
+        order.retain(|seen_key| match expiries.get(seen_key) {
+            Some(expiry) if *expiry < now_millis => {
+                expiries.remove(seen_key);
+                expired.push(seen_key.clone());
+                false
+            },
+            Some(_) => true,
+            None => false,
+        });
+
+        while order.len() >= self.capacity.max(1) {
+            if let Some(oldest) = order.pop_front() {
+                expiries.remove(&oldest);
+            }
+        }
+
+        expiries.insert(key.to_string(), expires_at);
+        order.push_back(key.to_string());
+        expired
+    }
+
+    // Inserts the entry unless an unexpired one is already stored
+    async fn persist(storage: &Client, key: &str, expires_at: i64, now_millis: i64) -> StorageResult<bool> {
+        let entry = serde_json::to_value(ReplayEntry { expires_at }).map_err(std::io::Error::other)?;
+
+        for _ in 0..2 {
+            let current = storage.get(key).await.optional()?;
+            if let Some(current) = &current {
+                // an expired (or unreadable) entry is replaced
+                if serde_json::from_value::<ReplayEntry>(current.clone()).is_ok_and(|stored| stored.expires_at >= now_millis) {
+                    return Ok(false);
+                }
+            }
+
+            match storage.compare_and_swap(key, current, Some(entry.clone())).await {
+                Ok(()) => return Ok(true),
+                // changed in the meantime, look again
+                Err(StorageError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        // lost twice to other writers of the same signature, so it was used
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{cleanup_test_files, postgres_test_uri, storage_uri};
+
+    static NOW: i64 = 1_700_000_000_000;
+
+    #[tokio::test]
+    async fn test_first_use() {
+        let cache = ReplayCache::new(10);
+
+        assert!(cache.first_use("pub_key", "signature", NOW + 1000, NOW).await.unwrap());
+        assert!(!cache.first_use("pub_key", "signature", NOW + 1000, NOW).await.unwrap());
+        assert!(!cache.first_use("pub_key", "signature", NOW + 1000, NOW + 1000).await.unwrap());
+
+        // the same signature from another key is a different request
+        assert!(cache.first_use("other_pub_key", "signature", NOW + 1000, NOW).await.unwrap());
+
+        // once expired the timestamp check rejects it anyway, so it is forgotten
+        assert!(cache.first_use("pub_key", "signature", NOW + 3000, NOW + 1001).await.unwrap());
+        assert!(!cache.first_use("pub_key", "signature", NOW + 3000, NOW + 1002).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let cache = ReplayCache::new(2);
+
+        assert!(cache.first_use("pub_key", "1", NOW + 1000, NOW).await.unwrap());
+        assert!(cache.first_use("pub_key", "2", NOW + 1000, NOW).await.unwrap());
+        assert!(cache.first_use("pub_key", "3", NOW + 1000, NOW).await.unwrap());
+        assert_eq!(cache.seen.lock().unwrap().order.len(), 2);
+
+        // the oldest entry made room
+        assert!(!cache.first_use("pub_key", "3", NOW + 1000, NOW).await.unwrap());
+        assert!(!cache.first_use("pub_key", "2", NOW + 1000, NOW).await.unwrap());
+        assert!(cache.first_use("pub_key", "1", NOW + 1000, NOW).await.unwrap());
+
+        // expired entries go first
+        let cache = ReplayCache::new(2);
+        assert!(cache.first_use("pub_key", "1", NOW + 2000, NOW).await.unwrap());
+        assert!(cache.first_use("pub_key", "2", NOW + 1000, NOW).await.unwrap());
+        assert!(cache.first_use("pub_key", "3", NOW + 2000, NOW + 1500).await.unwrap());
+        assert!(!cache.first_use("pub_key", "1", NOW + 2000, NOW + 1500).await.unwrap());
+    }
+
+    async fn check_persistent(client: Client, prefix: &str) {
+        let pub_key = format!("{}_pub_key", prefix);
+
+        let cache = ReplayCache::persistent(10, client.clone());
+        assert!(cache.first_use(&pub_key, "signature", NOW + 1000, NOW).await.unwrap());
+
+        // a fresh cache, as after a restart, still knows the signature
+        let restarted = ReplayCache::persistent(10, client.clone());
+        assert!(!restarted.first_use(&pub_key, "signature", NOW + 1000, NOW).await.unwrap());
+        assert!(client.get(&ReplayCache::key(&pub_key, "signature")).await.is_ok());
+
+        // even when it fell out of memory
+        let small = ReplayCache::persistent(1, client.clone());
+        assert!(small.first_use(&pub_key, "other", NOW + 1000, NOW).await.unwrap());
+        assert!(!small.first_use(&pub_key, "signature", NOW + 1000, NOW).await.unwrap());
+
+        // an expired stored entry is replaced
+        let restarted = ReplayCache::persistent(10, client.clone());
+        assert!(restarted.first_use(&pub_key, "signature", NOW + 5000, NOW + 2000).await.unwrap());
+        let stored = client.get(&ReplayCache::key(&pub_key, "signature")).await.unwrap();
+        assert_eq!(serde_json::from_value::<ReplayEntry>(stored).unwrap(), ReplayEntry { expires_at: NOW + 5000 });
+
+        // expired entries are removed from storage as the cache evicts them
+        assert!(restarted.first_use(&pub_key, "later", NOW + 9000, NOW + 6000).await.unwrap());
+        assert!(client.get(&ReplayCache::key(&pub_key, "signature")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_persistent_file_storage() {
+        let uri = storage_uri("test_replay_cache_persistent");
+        check_persistent(Client::new(uri.clone()), "file").await;
+
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs POSTGRES_TEST_URI"]
+    async fn test_persistent_postgres() {
+        let uri = postgres_test_uri();
+        let prefix = sessionless::Sessionless::generate_uuid().to_string();
+        check_persistent(Client::new(uri), &prefix).await;
+    }
+}
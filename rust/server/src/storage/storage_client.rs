@@ -1,13 +1,22 @@
 use async_trait::async_trait;
 
+use super::{StorageError, StorageResult};
+
 #[async_trait]
 pub trait StorageClient: Send + Clone {
-    // Get a json value from the storage
-    async fn get(&self, key: &str) -> Option<serde_json::Value>;
+    // Get a json value from the storage; NotFound if there is none
+    async fn get(&self, key: &str) -> StorageResult<serde_json::Value>;
     // Set a json value in the storage; will create new file if it doesnt exist or overwrite otherwise
-    async fn set(&self, key: &str, value: serde_json::Value) -> anyhow::Result<()>;
-    // Delete from the storage; returns true if the value was deleted
-    async fn delete(&self, key: &str) -> bool;
+    async fn set(&self, key: &str, value: serde_json::Value) -> StorageResult<()>;
+    // Delete from the storage; NotFound if there was nothing to delete
+    async fn delete(&self, key: &str) -> StorageResult<()>;
+    // Atomically replace the value at key with new if it currently equals expected,
+    // where None means absent (expected) or delete (new); Conflict if the value differed
+    async fn compare_and_swap(&self, key: &str, expected: Option<serde_json::Value>, new: Option<serde_json::Value>) -> StorageResult<()>;
+    // Number of keys starting with prefix
+    async fn count(&self, prefix: &str) -> StorageResult<u64>;
+    // Keys starting with prefix, in order
+    async fn keys(&self, prefix: &str) -> StorageResult<Vec<String>>;
 }
 
 #[derive(Debug, Clone)]
@@ -15,15 +24,27 @@ pub struct NotImplementedYetClient {}
 
 #[async_trait]
 impl StorageClient for NotImplementedYetClient {
-    async fn get(&self, _key: &str) -> Option<serde_json::Value> {
-        None
+    async fn get(&self, key: &str) -> StorageResult<serde_json::Value> {
+        Err(StorageError::NotFound(key.to_string()))
     }
 
-    async fn set(&self, _key: &str, _value: serde_json::Value) -> anyhow::Result<()> {
+    async fn set(&self, _key: &str, _value: serde_json::Value) -> StorageResult<()> {
         Ok(())
     }
 
-    async fn delete(&self, _key: &str) -> bool {
-        false
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        Err(StorageError::NotFound(key.to_string()))
     }
-}
\ No newline at end of file
+
+    async fn compare_and_swap(&self, _key: &str, _expected: Option<serde_json::Value>, _new: Option<serde_json::Value>) -> StorageResult<()> {
+        Ok(())
+    }
+
+    async fn count(&self, _prefix: &str) -> StorageResult<u64> {
+        Ok(0)
+    }
+
+    async fn keys(&self, _prefix: &str) -> StorageResult<Vec<String>> {
+        Ok(vec![])
+    }
+}
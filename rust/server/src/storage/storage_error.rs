@@ -0,0 +1,50 @@
+use serde::de::DeserializeOwned;
+
+
+// What can go wrong talking to storage. Backends map their own failures onto these,
+// so callers can tell a missing value from a corrupt one from an unreachable store
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Nothing stored at {0}")]
+    NotFound(String),
+    #[error("Corrupt value at {key}: {reason}")]
+    Corrupt { key: String, reason: String },
+    #[error("Storage I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    // a compare and swap found a different value, or gave up under contention
+    #[error("Conflicting write to {0}")]
+    Conflict(String),
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl StorageError {
+    pub fn corrupt(key: &str, reason: impl ToString) -> Self {
+        StorageError::Corrupt { key: key.to_string(), reason: reason.to_string() }
+    }
+}
+
+// Serializes a value to store; our own types always serialize, so a failure is reported as I/O
+pub fn to_value<T: serde::Serialize>(value: &T) -> StorageResult<serde_json::Value> {
+    serde_json::to_value(value).map_err(|e| StorageError::Io(std::io::Error::other(e)))
+}
+
+// Deserializes the value stored at key, reporting a mismatch as corruption
+pub fn from_value<T: DeserializeOwned>(key: &str, value: serde_json::Value) -> StorageResult<T> {
+    serde_json::from_value(value).map_err(|e| StorageError::corrupt(key, e))
+}
+
+pub trait OptionalExt<T> {
+    // Turns NotFound into None, for callers where a missing value is expected
+    fn optional(self) -> StorageResult<Option<T>>;
+}
+
+impl<T> OptionalExt<T> for StorageResult<T> {
+    fn optional(self) -> StorageResult<Option<T>> {
+        match self {
+            Ok(value) => Ok(Some(value)),
+            Err(StorageError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -1,26 +1,174 @@
-use std::str::FromStr;
+use std::collections::BTreeMap;
 
 use serde::{Serialize, Deserialize};
-use sessionless::secp256k1::PublicKey;
 
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct User {
     pub uuid: String,
+    // The primary key, the one the pub_key + hash index points from
     pub pub_key: String,
     pub hash: String,
+    // Further keys authorized for the user, one per device; absent in records
+    // written before users could hold more than one key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub device_keys: Vec<String>,
+    // Hashes the user had before this one, most recent first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<HashRecord>,
+    // Hashes of independent pieces of state, by slot name. Only hash is indexed and has a history
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub slots: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HashRecord {
+    pub hash: String,
+    // when the hash was replaced, in milliseconds
+    pub timestamp: i64,
 }
 
 impl User {
     // Create a new user with an empty uuid
     pub fn new(uuid: Option<String>, pub_key: String, hash: String) -> Self {
         match uuid {
-            Some(uuid) => Self {uuid: uuid, pub_key: pub_key, hash: hash},
-            None => Self {uuid: "".to_string(), pub_key: pub_key, hash: hash}
+            Some(uuid) => Self {uuid, pub_key, hash, device_keys: vec![], history: vec![], slots: BTreeMap::new()},
+            None => Self {uuid: "".to_string(), pub_key, hash, device_keys: vec![], history: vec![], slots: BTreeMap::new()}
         }
     }
 
-    pub fn pub_key(&self) -> anyhow::Result<PublicKey> {
-        PublicKey::from_str(self.pub_key.as_str()).map_err(|_| anyhow::anyhow!("Failed to parse public key"))
+    // The user with new_hash, the current hash going to the front of its history.
+    // A hash appears in the history once, and only the latest history_size are kept
+    pub fn with_hash(&self, new_hash: &str, replaced_at: i64, history_size: usize) -> User {
+        let mut user = self.clone();
+        if user.hash != new_hash {
+            user.history.retain(|record| record.hash != new_hash && record.hash != self.hash);
+            user.history.insert(0, HashRecord { hash: std::mem::replace(&mut user.hash, new_hash.to_string()), timestamp: replaced_at });
+        }
+        user.history.truncate(history_size);
+        user
     }
-}
\ No newline at end of file
+
+    // The hash of slot, or the user's own hash when there is no slot
+    pub fn slot_hash(&self, slot: Option<&str>) -> Option<&str> {
+        match slot {
+            Some(slot) => self.slots.get(slot).map(String::as_str),
+            None => Some(&self.hash),
+        }
+    }
+
+    pub fn had_hash(&self, hash: &str) -> bool {
+        self.history.iter().any(|record| record.hash == hash)
+    }
+
+    // Every authorized key, primary first
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.pub_key.as_str()).chain(self.device_keys.iter().map(String::as_str))
+    }
+
+    pub fn has_key(&self, key: &str) -> bool {
+        self.keys().any(|k| k == key)
+    }
+
+    // The user with key authorized as well
+    pub fn with_key(&self, key: &str) -> User {
+        let mut user = self.clone();
+        if !user.has_key(key) {
+            user.device_keys.push(key.to_string());
+        }
+        user
+    }
+
+    // The user without key, the first device key taking over if key was the primary.
+    // None if key is the only one left
+    pub fn without_key(&self, key: &str) -> Option<User> {
+        let mut user = self.clone();
+        user.device_keys.retain(|k| k != key);
+        if user.pub_key == key {
+            if user.device_keys.is_empty() {
+                return None;
+            }
+            user.pub_key = user.device_keys.remove(0);
+        }
+        Some(user)
+    }
+
+    // The user with key swapped for new_key, which takes over key's place
+    pub fn with_key_replaced(&self, key: &str, new_key: &str) -> User {
+        let mut user = self.clone();
+        user.device_keys.retain(|k| k != new_key);
+        if user.pub_key == key {
+            user.pub_key = new_key.to_string();
+        } else if user.pub_key != new_key {
+            for k in user.device_keys.iter_mut().filter(|k| *k == key) {
+                *k = new_key.to_string();
+            }
+        } else {
+            user.device_keys.retain(|k| k != key);
+        }
+        user
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(pub_key: &str, device_keys: &[&str]) -> User {
+        User { device_keys: device_keys.iter().map(|k| k.to_string()).collect(), ..User::new(Some("1234".to_string()), pub_key.to_string(), "hash".to_string()) }
+    }
+
+    #[test]
+    fn test_keys() {
+        let phone = user("phone", &[]);
+        assert_eq!(phone.keys().collect::<Vec<_>>(), vec!["phone"]);
+
+        let both = phone.with_key("desktop");
+        assert_eq!(both, user("phone", &["desktop"]));
+        assert_eq!(both.with_key("desktop"), both);
+        assert!(both.has_key("desktop") && both.has_key("phone") && !both.has_key("tablet"));
+
+        // the primary goes, the device key takes over
+        assert_eq!(both.without_key("phone"), Some(user("desktop", &[])));
+        assert_eq!(both.without_key("desktop"), Some(phone.clone()));
+        assert_eq!(phone.without_key("phone"), None);
+
+        assert_eq!(both.with_key_replaced("phone", "tablet"), user("tablet", &["desktop"]));
+        assert_eq!(both.with_key_replaced("desktop", "tablet"), user("phone", &["tablet"]));
+        // onto a key the user already holds
+        assert_eq!(both.with_key_replaced("phone", "desktop"), user("desktop", &[]));
+        assert_eq!(both.with_key_replaced("desktop", "phone"), phone);
+    }
+
+    #[test]
+    fn test_history() {
+        let record = |hash: &str, timestamp: i64| HashRecord { hash: hash.to_string(), timestamp };
+
+        let user = user("phone", &[]).with_hash("second", 1, 2);
+        assert_eq!(user.hash, "second");
+        assert_eq!(user.history, vec![record("hash", 1)]);
+        assert!(user.had_hash("hash") && !user.had_hash("second"));
+
+        // only the latest two are kept
+        let user = user.with_hash("third", 2, 2).with_hash("fourth", 3, 2);
+        assert_eq!(user.history, vec![record("third", 3), record("second", 2)]);
+
+        // going back to a hash takes it out of the history
+        let user = user.with_hash("second", 4, 2);
+        assert_eq!(user.hash, "second");
+        assert_eq!(user.history, vec![record("fourth", 4), record("third", 3)]);
+
+        // the same hash again changes nothing
+        assert_eq!(user.with_hash("second", 5, 2), user);
+        assert!(user.with_hash("fifth", 5, 0).history.is_empty());
+    }
+
+    #[test]
+    fn test_records_without_device_keys() {
+        let user: User = serde_json::from_value(serde_json::json!({"uuid": "1234", "pub_key": "phone", "hash": "hash"})).unwrap();
+        assert!(user.device_keys.is_empty() && user.history.is_empty() && user.slots.is_empty());
+        assert_eq!(user.slot_hash(None), Some("hash"));
+        assert_eq!(user.slot_hash(Some("settings")), None);
+        assert_eq!(serde_json::to_value(&user).unwrap(), serde_json::json!({"uuid": "1234", "pub_key": "phone", "hash": "hash"}));
+    }
+}
@@ -1,104 +1,450 @@
+use std::sync::Arc;
+
 use axum::http::Uri;
+use sessionless::Sessionless;
+use sha2::{Digest, Sha256};
+
+use super::{from_value, to_value, Client, KeyLocks, OptionalExt, PubKeyEntry, PubKeys, StorageClient, StorageError, StorageResult, User, UserState, UserStats, Lockout, KeyCheck};
 
-use super::{Client, PubKeys, StorageClient, User};
 
+pub(crate) static USER_STRING: &str = "user";
+pub(crate) static PUB_KEY_STRING: &str = "pub_key";
+pub(crate) static STATE_STRING: &str = "state";
+pub(crate) static LOCKOUT_STRING: &str = "lockout";
+pub(crate) static HEALTH_STRING: &str = "health";
+// Legacy single blob holding the whole pub_key index
+pub(crate) static KEYS_STRING: &str = "keys";
 
-static USER_STRING: &str = "user";
-static KEYS_STRING: &str = "keys";
+// How often a compare and swap is retried before giving up under contention
+static MAX_SWAP_ATTEMPTS: usize = 16;
 
 #[derive(Debug, Clone)]
 pub struct UserClient {
-    pub client: Client
+    pub client: Client,
+    // Serializes mutations of the same user (or index entry) within this process;
+    // compare and swap on the storage keeps other processes honest
+    locks: Arc<KeyLocks>,
 }
 
 impl UserClient {
     pub fn new(storage_uri: Uri) -> Self {
-        Self { client: Client::new(storage_uri) }
+        Self { client: Client::new(storage_uri), locks: Arc::new(KeyLocks::new()) }
     }
 
     fn user_key(uuid: &str) -> String {
         format!("{}:{}", USER_STRING, uuid)
     }
 
+    fn state_key(uuid: &str) -> String {
+        format!("{}:{}", STATE_STRING, uuid)
+    }
+
+    fn lockout_key(uuid: &str) -> String {
+        format!("{}:{}", LOCKOUT_STRING, uuid)
+    }
+
+    // Storage key of a single index entry. The pub_key + hash is digested so that
+    // any client supplied hash makes a fixed length, path safe key
+    pub(crate) fn pub_key_entry_key(key: &str) -> String {
+        format!("{}:{}", PUB_KEY_STRING, hex::encode(Sha256::digest(key.as_bytes())))
+    }
+
+    #[cfg(test)]
     pub async fn get_user_uuid(self, key: &str) -> Option<String> {
-        match self.get_keys().await {
-            Ok(pub_keys) => pub_keys.get_user_uuid(key).cloned(),
-            Err(_) => None
-        }
+        let entry_key = UserClient::pub_key_entry_key(key);
+        let value = self.client.get(&entry_key).await.ok()?;
+        from_value::<PubKeyEntry>(&entry_key, value).ok().map(|entry| entry.user_uuid)
     }
 
-    pub async fn get_user(self, uuid: impl AsRef<str>) -> Option<User> {
-        match self.client.get(UserClient::user_key(uuid.as_ref()).as_str()).await {
-            Some(value) => {
-                match serde_json::from_value(value) {
-                    Ok(user) => Some(user),
-                    Err(_) => None
-                }
-            },
-            None => None
-        }
+    // NotFound if there is no such user, Corrupt if the record isn't a user
+    pub async fn get_user(self, uuid: impl AsRef<str>) -> StorageResult<User> {
+        self.get_user_record(uuid.as_ref()).await.map(|(_, user)| user)
     }
 
     // Will put a new user with the given uuid, pub_key, and hash
     // will return the newly put user
-    pub async fn put_user(&self, uuid: &str, pub_key: &str, hash: &str) -> anyhow::Result<User> {
+    pub async fn put_user(&self, uuid: &str, pub_key: &str, hash: &str) -> StorageResult<User> {
         let user = User::new(Some(uuid.to_string()), pub_key.to_string(), hash.to_string());
-        if let Ok(value) = serde_json::to_value(user.clone()) {
-            match self.client.set(&UserClient::user_key(&user.uuid).as_str(), value).await {
-                Ok(_) => {
-                    return Ok(user.clone());
+        self.client.set(UserClient::user_key(&user.uuid).as_str(), to_value(&user)?).await?;
+        Ok(user)
+    }
+
+    // The stored user along with the raw value it was read from, for compare and swap
+    async fn get_user_record(&self, uuid: &str) -> StorageResult<(serde_json::Value, User)> {
+        let user_key = UserClient::user_key(uuid);
+        let value = self.client.get(&user_key).await?;
+        let user = from_value(&user_key, value.clone())?;
+        Ok((value, user))
+    }
+
+    // Returns the user indexed under pub_key + hash, creating one if there is none, along with
+    // whether this call created it. pub_key may be any of the user's keys, a device key included.
+    // Concurrent creates for the same pub_key + hash all end up with the same user
+    pub async fn create_user(&self, pub_key: &str, hash: &str) -> StorageResult<(User, bool)> {
+        let key = PubKeys::key(hash, pub_key);
+        let entry_key = UserClient::pub_key_entry_key(&key);
+        let _guard = self.locks.lock(&entry_key).await;
+
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            if let Some(value) = self.client.get(&entry_key).await.optional()? {
+                let entry: PubKeyEntry = from_value(&entry_key, value.clone())?;
+                match self.clone().get_user(&entry.user_uuid).await.optional()? {
+                    Some(user) if user.has_key(pub_key) && user.hash == hash => return Ok((user, false)),
+                    // the entry outlived its user (or the user's hash); drop it and try again
+                    _ => {
+                        ignore_conflict(self.client.compare_and_swap(&entry_key, Some(value), None).await)?;
+                        continue;
+                    }
+                }
+            }
+
+            // The user is written before it is indexed, so an index entry never points at nothing
+            let user = self.put_user(&Sessionless::generate_uuid().to_string(), pub_key, hash).await?;
+            let entry = PubKeyEntry { key: key.clone(), user_uuid: user.uuid.clone() };
+            match self.client.compare_and_swap(&entry_key, None, Some(to_value(&entry)?)).await {
+                Ok(()) => return Ok((user, true)),
+                // another create won the index entry; theirs is the user to return
+                Err(StorageError::Conflict(_)) => {
+                    self.client.delete(UserClient::user_key(&user.uuid).as_str()).await.optional()?;
                 },
-                Err(e) => Err(e.into()),
+                Err(e) => return Err(e),
             }
-        } else {
-            Err(anyhow::Error::msg("Failed to serialize user"))
         }
+        Err(StorageError::Conflict(entry_key))
     }
 
-    pub async fn delete_user(self, uuid: &str) -> bool {
-        self.client.delete(UserClient::user_key(uuid).as_str()).await
+    // Sets the user's hash from hash to new_hash and moves the index entries of its keys along with it.
+    // The replaced hash is kept in the user's history, stamped replaced_at, up to history_size of them.
+    // The new entries are claimed before the user is swapped, as in update_user.
+    // NotFound if there is no such user, Conflict if its hash isn't hash (anymore) or another user
+    // holds one of its keys with new_hash
+    pub async fn update_hash(&self, uuid: &str, hash: &str, new_hash: &str, replaced_at: i64, history_size: usize) -> StorageResult<User> {
+        let user_key = UserClient::user_key(uuid);
+        let _guard = self.locks.lock(&user_key).await;
+
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let (value, user) = self.get_user_record(uuid).await?;
+            // checked against the value the swap below expects, so no other update can slip in between
+            if user.hash != hash {
+                return Err(StorageError::Conflict(user_key));
+            }
+
+            let updated = user.with_hash(new_hash, replaced_at, history_size);
+            let added = match user.hash != new_hash {
+                true => user.keys().map(|key| PubKeys::key(new_hash, key)).collect::<Vec<_>>(),
+                false => vec![],
+            };
+            if !self.claim_keys(&added, uuid).await? {
+                continue;
+            }
+
+            match self.client.compare_and_swap(&user_key, Some(value), Some(to_value(&updated)?)).await {
+                Ok(()) => {},
+                Err(StorageError::Conflict(_)) => {
+                    self.remove_keys(&added, uuid).await?;
+                    continue;
+                },
+                Err(e) => {
+                    self.remove_keys(&added, uuid).await?;
+                    return Err(e);
+                },
+            }
+
+            if user.hash != new_hash {
+                self.remove_keys(&user.keys().map(|key| PubKeys::key(&user.hash, key)).collect::<Vec<_>>(), uuid).await?;
+            }
+            return Ok(updated);
+        }
+        Err(StorageError::Conflict(user_key))
     }
 
-    pub async fn save_pub_keys(&self, keys: PubKeys) -> anyhow::Result<()> {
-        if let Ok(value) = serde_json::to_value(keys) {
-            self.client.set(KEYS_STRING, value).await?;
-            Ok(())
-        } else {
-            Err(anyhow::Error::msg("Failed to set keys"))
+    // Replaces the user with change(user), all but its uuid, hash and history, retried if the user
+    // changes meanwhile. The index entries of keys it gains are claimed before the user is swapped,
+    // so two users can't end up under the same pub_key + hash; Conflict if another user holds one
+    pub async fn update_user<E: From<StorageError>>(&self, uuid: &str, change: impl Fn(&User) -> Result<User, E>) -> Result<User, E> {
+        let user_key = UserClient::user_key(uuid);
+        let _guard = self.locks.lock(&user_key).await;
+
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let (value, user) = self.get_user_record(uuid).await?;
+            let changed = User { uuid: user.uuid.clone(), hash: user.hash.clone(), history: user.history.clone(), ..change(&user)? };
+            if changed == user {
+                return Ok(user);
+            }
+
+            let added = changed.keys().filter(|key| !user.has_key(key)).map(|key| PubKeys::key(&user.hash, key)).collect::<Vec<_>>();
+            let removed = user.keys().filter(|key| !changed.has_key(key)).map(|key| PubKeys::key(&user.hash, key)).collect::<Vec<_>>();
+            if !self.claim_keys(&added, uuid).await? {
+                continue;
+            }
+
+            match self.client.compare_and_swap(&user_key, Some(value), Some(to_value(&changed)?)).await {
+                Ok(()) => {},
+                Err(StorageError::Conflict(_)) => {
+                    self.remove_keys(&added, uuid).await?;
+                    continue;
+                },
+                Err(e) => {
+                    self.remove_keys(&added, uuid).await?;
+                    return Err(e.into());
+                },
+            }
+
+            self.remove_keys(&removed, uuid).await?;
+            return Ok(changed);
         }
+        Err(StorageError::Conflict(user_key).into())
     }
 
-    pub async fn get_keys(&self) -> anyhow::Result<PubKeys> {
-        match self.client.get(KEYS_STRING).await {
-            Some(value) => {
-                match serde_json::from_value(value) {
-                    Ok(result) => Ok(result),
-                    Err(_) => Ok(PubKeys::default())
-                }
-            },
-            None => Ok(PubKeys::default())
+    // Claims every one of keys for user_uuid, or none of them: the ones claimed are
+    // released again when another can't be. Ok(false) when the claims should be retried
+    async fn claim_keys(&self, keys: &[String], user_uuid: &str) -> StorageResult<bool> {
+        for (claimed, key) in keys.iter().enumerate() {
+            let result = self.claim_key(key, user_uuid).await;
+            if !matches!(result, Ok(true)) {
+                self.remove_keys(&keys[..claimed], user_uuid).await?;
+                return result;
+            }
         }
+        Ok(true)
     }
 
-    // will add a new key
-    pub async fn update_keys(&self, key: &str, user_uuid: &str) -> anyhow::Result<()> {
-        match self.get_keys().await {
-            Ok(mut pub_keys) => {
-                let pub_keys = pub_keys.add_user_uuid(user_uuid, key);
-                self.save_pub_keys(pub_keys.clone()).await
-            },
-            Err(e) => Err(e)
+    // Points the index entry for key at user_uuid unless another user holds it.
+    // Ok(false) when a stale entry was dropped and the claim should be retried
+    async fn claim_key(&self, key: &str, user_uuid: &str) -> StorageResult<bool> {
+        let entry_key = UserClient::pub_key_entry_key(key);
+        let _guard = self.locks.lock(&entry_key).await;
+
+        let entry = PubKeyEntry { key: key.to_string(), user_uuid: user_uuid.to_string() };
+        match self.client.compare_and_swap(&entry_key, None, Some(to_value(&entry)?)).await {
+            Ok(()) => return Ok(true),
+            Err(StorageError::Conflict(_)) => {},
+            Err(e) => return Err(e),
+        }
+
+        let Some(current) = self.client.get(&entry_key).await.optional()? else {
+            return Ok(false);
+        };
+        let holder: PubKeyEntry = from_value(&entry_key, current.clone())?;
+        if holder.user_uuid == user_uuid {
+            return Ok(true);
+        }
+        match self.clone().get_user(&holder.user_uuid).await.optional()? {
+            Some(held) if held.keys().any(|k| PubKeys::key(&held.hash, k) == key) => Err(StorageError::Conflict(entry_key)),
+            // the entry outlived its user (or the user's key); drop it and try again
+            _ => {
+                ignore_conflict(self.client.compare_and_swap(&entry_key, Some(current), None).await)?;
+                Ok(false)
+            }
         }
     }
 
-    pub async fn remove_key(&self, key: &str) -> anyhow::Result<()> {
-        match self.get_keys().await {
-            Ok(mut pub_keys) => {
-                pub_keys.remove_key(key);
-                self.save_pub_keys(pub_keys.clone()).await
-            },
-            Err(e) => Err(e)
+    // The user's saved state; NotFound if none was saved
+    pub async fn get_state(&self, uuid: &str) -> StorageResult<UserState> {
+        let state_key = UserClient::state_key(uuid);
+        let value = self.client.get(&state_key).await?;
+        from_value(&state_key, value)
+    }
+
+    // Saves state for the user while its hash is still hash, replacing any state saved before.
+    // NotFound if there is no such user, Conflict if the hash changed
+    pub async fn put_state(&self, uuid: &str, hash: &str, state: &str) -> StorageResult<UserState> {
+        let user_key = UserClient::user_key(uuid);
+        let state_key = UserClient::state_key(uuid);
+        let _guard = self.locks.lock(&user_key).await;
+
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let (_, user) = self.get_user_record(uuid).await?;
+            if user.hash != hash {
+                return Err(StorageError::Conflict(user_key));
+            }
+
+            let current = self.client.get(&state_key).await.optional()?;
+            let version = match &current {
+                Some(value) => from_value::<UserState>(&state_key, value.clone())?.version + 1,
+                None => 1,
+            };
+
+            let saved = UserState { hash: hash.to_string(), state: state.to_string(), version };
+            match self.client.compare_and_swap(&state_key, current, Some(to_value(&saved)?)).await {
+                Ok(()) => return Ok(saved),
+                Err(StorageError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
         }
+        Err(StorageError::Conflict(state_key))
+    }
+
+    // Writes, reads back and deletes a throwaway entry, proving storage is reachable and writable.
+    // Runs on its own task, so the entry is deleted even when the caller stops waiting mid-write
+    pub async fn check_storage(&self) -> StorageResult<()> {
+        let client = self.client.clone();
+        let check = tokio::spawn(async move {
+            let key = format!("{}:{}", HEALTH_STRING, Sessionless::generate_uuid());
+            let value = serde_json::json!({ "checked": true });
+
+            let result = match client.set(&key, value.clone()).await {
+                Ok(()) => client.get(&key).await.and_then(|read| match read == value {
+                    true => Ok(()),
+                    false => Err(StorageError::corrupt(&key, "read back a different value")),
+                }),
+                Err(e) => Err(e),
+            };
+            // a failed write may still have left the entry behind
+            let deleted = client.delete(&key).await.optional();
+            result.and(deleted.map(|_| ()))
+        });
+        check.await.map_err(|e| StorageError::Io(std::io::Error::other(e)))?
+    }
+
+    // Every user's uuid, in order
+    pub async fn user_uuids(&self) -> StorageResult<Vec<String>> {
+        let prefix = format!("{}:", USER_STRING);
+        let keys = self.client.keys(&prefix).await?;
+        Ok(keys.into_iter().map(|key| key[prefix.len()..].to_string()).collect())
+    }
+
+    // Every entry of the pub_key + hash index
+    pub async fn key_entries(&self) -> StorageResult<Vec<PubKeyEntry>> {
+        let mut entries = vec![];
+        for entry_key in self.client.keys(&format!("{}:", PUB_KEY_STRING)).await? {
+            // an entry removed since it was listed is no longer part of the index
+            if let Some(value) = self.client.get(&entry_key).await.optional()? {
+                entries.push(from_value(&entry_key, value)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    // Users and pub_key index entries in storage
+    pub async fn stats(&self) -> StorageResult<UserStats> {
+        let users = self.client.count(&format!("{}:", USER_STRING)).await?;
+        let pub_keys = self.client.count(&format!("{}:", PUB_KEY_STRING)).await?;
+        Ok(UserStats { users, pub_keys })
+    }
+
+    // The user the index entry for pub_key + hash points at; NotFound if there is no entry
+    pub async fn check_key(&self, pub_key: &str, hash: &str) -> StorageResult<KeyCheck> {
+        let entry_key = UserClient::pub_key_entry_key(&PubKeys::key(hash, pub_key));
+        let entry: PubKeyEntry = from_value(&entry_key, self.client.get(&entry_key).await?)?;
+        let consistent = match self.clone().get_user(&entry.user_uuid).await.optional()? {
+            Some(user) => user.has_key(pub_key) && user.hash == hash,
+            None => false,
+        };
+        Ok(KeyCheck { user_uuid: entry.user_uuid, consistent })
+    }
+
+    // The user's hash mismatch count; NotFound if there was none since the last reset
+    pub async fn get_lockout(&self, uuid: &str) -> StorageResult<Lockout> {
+        let lockout_key = UserClient::lockout_key(uuid);
+        let value = self.client.get(&lockout_key).await?;
+        from_value(&lockout_key, value)
+    }
+
+    // Counts a hash mismatch for the user. The max_failures-th in a row locks hash checks
+    // out until lockout_millis after now, and the count starts over
+    pub async fn record_failure(&self, uuid: &str, now: i64, max_failures: u32, lockout_millis: i64) -> StorageResult<Lockout> {
+        let lockout_key = UserClient::lockout_key(uuid);
+        let _guard = self.locks.lock(&lockout_key).await;
+
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let current = self.client.get(&lockout_key).await.optional()?;
+            let mut lockout = match &current {
+                Some(value) => from_value::<Lockout>(&lockout_key, value.clone())?,
+                None => Lockout::default(),
+            };
+
+            lockout.failures += 1;
+            if lockout.failures >= max_failures {
+                lockout = Lockout { failures: 0, locked_until: now + lockout_millis };
+            }
+            match self.client.compare_and_swap(&lockout_key, current, Some(to_value(&lockout)?)).await {
+                Ok(()) => return Ok(lockout),
+                Err(StorageError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(StorageError::Conflict(lockout_key))
+    }
+
+    // Forgets the user's mismatches, after a match or an update
+    pub async fn reset_lockout(&self, uuid: &str) -> StorageResult<()> {
+        self.client.delete(&UserClient::lockout_key(uuid)).await.optional()?;
+        Ok(())
+    }
+
+    // Deletes the user and the index entries of its keys; NotFound if there is no such user
+    pub async fn delete_user(self, uuid: &str) -> StorageResult<()> {
+        let user_key = UserClient::user_key(uuid);
+        let _guard = self.locks.lock(&user_key).await;
+
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let (value, user) = self.get_user_record(uuid).await?;
+
+            match self.client.compare_and_swap(&user_key, Some(value), None).await {
+                Ok(()) => {
+                    self.client.delete(&UserClient::state_key(uuid)).await.optional()?;
+                    self.reset_lockout(uuid).await?;
+                    return self.remove_keys(&user.keys().map(|key| PubKeys::key(&user.hash, key)).collect::<Vec<_>>(), uuid).await;
+                },
+                Err(StorageError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(StorageError::Conflict(user_key))
+    }
+
+    // Adds (or overwrites) the index entry for key
+    pub async fn put_key(&self, key: &str, user_uuid: &str) -> StorageResult<()> {
+        let entry = PubKeyEntry { key: key.to_string(), user_uuid: user_uuid.to_string() };
+        self.client.set(UserClient::pub_key_entry_key(key).as_str(), to_value(&entry)?).await
+    }
+
+    // Removes the index entry for key only while it still points at user_uuid;
+    // removing a key that is not in the index is not an error
+    pub async fn remove_key(&self, key: &str, user_uuid: &str) -> StorageResult<()> {
+        let entry_key = UserClient::pub_key_entry_key(key);
+        if let Some(value) = self.client.get(&entry_key).await.optional()? {
+            let entry: PubKeyEntry = from_value(&entry_key, value.clone())?;
+            if entry.user_uuid == user_uuid {
+                ignore_conflict(self.client.compare_and_swap(&entry_key, Some(value), None).await)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the index entries for keys that still point at user_uuid
+    pub async fn remove_keys(&self, keys: &[String], user_uuid: &str) -> StorageResult<()> {
+        for key in keys {
+            self.remove_key(key, user_uuid).await?;
+        }
+        Ok(())
+    }
+
+    // One-time move of the legacy "keys" blob into individual index entries.
+    // Returns the number of entries migrated; the blob is removed once all of them are written
+    pub async fn migrate_legacy_keys(&self) -> StorageResult<usize> {
+        let pub_keys: PubKeys = match self.client.get(KEYS_STRING).await.optional()? {
+            Some(value) => from_value(KEYS_STRING, value)?,
+            None => return Ok(0)
+        };
+
+        let mut migrated = 0;
+        for entry in pub_keys.entries() {
+            self.put_key(&entry.key, &entry.user_uuid).await?;
+            migrated += 1;
+        }
+
+        self.client.delete(KEYS_STRING).await.optional()?;
+        Ok(migrated)
+    }
+}
+
+// Losing a compare and swap race is fine when someone else made the same change
+fn ignore_conflict(result: StorageResult<()>) -> StorageResult<()> {
+    match result {
+        Err(StorageError::Conflict(_)) => Ok(()),
+        result => result,
     }
 }
 
@@ -108,9 +454,10 @@ mod tests {
     use super::*;
     use sessionless::Sessionless;
     use tokio::io::AsyncWriteExt;
-    use crate::test_common::{storage_uri, check_path_exists, cleanup_test_files};
+    use crate::test_common::{storage_uri, check_path_exists, cleanup_test_files, count_users, read_keys};
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_get_user() {
         let uri = storage_uri("get_user");
 
@@ -141,8 +488,8 @@ mod tests {
         assert!(file.write_all(serde_json::to_string(&data).expect("Failed to serialize to string").as_bytes()).await.is_ok());
 
         match user_client.clone().get_user(initial_uuid).await {
-            Some(result) => assert_eq!(result, user.clone()),
-            None => assert!(false)
+            Ok(result) => assert_eq!(result, user.clone()),
+            Err(e) => panic!("Expected a value: {}", e)
         };
 
         // clean up
@@ -151,6 +498,7 @@ mod tests {
 
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants, clippy::to_string_in_format_args)]
     async fn test_put_user() {
         let uri = storage_uri("put_user");
 
@@ -191,6 +539,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_delete_user() {
         let uri = storage_uri("delete_user");
 
@@ -222,36 +571,32 @@ mod tests {
         // confirm the file exists
         assert!(check_path_exists(&file_path).await);
 
-        // delete the user: should be true as the file should be deleted
-        assert!(user_client.clone().delete_user(initial_uuid).await);
+        // delete the user: the file should be deleted
+        assert!(user_client.clone().delete_user(initial_uuid).await.is_ok());
 
         // confirm the file doesn't exist after
         assert!(!check_path_exists(&file_path).await);
 
-        // try to delete the user again: should be false as the file doesn't exist
-        assert!(!user_client.clone().delete_user(initial_uuid).await);
+        // try to delete the user again: not found as the file doesn't exist
+        assert!(matches!(user_client.clone().delete_user(initial_uuid).await, Err(StorageError::NotFound(_))));
 
         // clean up
         cleanup_test_files(&uri.to_string()).await;
     }
 
     #[tokio::test]
-    async fn test_get_keys() {
-        let uri = storage_uri("get_keys");
+    #[allow(clippy::assertions_on_constants)]
+    async fn test_get_user_uuid() {
+        let uri = storage_uri("get_user_uuid");
 
-        let file_path = format!("{}/{}", &uri.to_string(), KEYS_STRING);
         let user_client = UserClient::new(uri.clone());
 
-        // confirm file doesn't exist before
-        assert!(!check_path_exists(&file_path).await);
+        let sessionless = Sessionless::new();
+        let key = PubKeys::key("hash", &sessionless.public_key().to_string());
+        let file_path = format!("{}/{}", &uri.to_string(), UserClient::pub_key_entry_key(&key));
 
-        // Keys are default when the file doesn't exist
-        match user_client.get_keys().await {
-            Ok(result) => {
-                assert_eq!(result, PubKeys::default());
-            },
-            Err(_) => assert!(false)
-        }
+        // nothing in the index yet
+        assert!(user_client.clone().get_user_uuid(&key).await.is_none());
 
         // create directory
         match user_client.clone().client {
@@ -261,14 +606,10 @@ mod tests {
             _ => assert!(false)
         }
 
-        let user_uuid = "test_user_uuid";
-        let pub_key = "test_pub_key";
-
-        let mut pub_keys = PubKeys::default();
-        let pub_keys = pub_keys.add_user_uuid(user_uuid, pub_key);
-        let data = serde_json::to_value(pub_keys.clone()).expect("Failed to serialize");
+        let entry = PubKeyEntry { key: key.clone(), user_uuid: "test_user_uuid".to_string() };
+        let data = serde_json::to_value(entry).expect("Failed to serialize");
 
-        // write pub_keys to file with fs::write
+        // write the entry to file with fs::write
         let mut file = match tokio::fs::File::create_new(file_path).await {
             Ok(file) => file,
             Err(e) => panic!("Failed to write to file: {}", e),
@@ -276,124 +617,407 @@ mod tests {
 
         assert!(file.write_all(serde_json::to_string(&data).expect("Failed to serialize to string").as_bytes()).await.is_ok());
 
-        match user_client.clone().get_keys().await {
-            Ok(result) => {
-                let result_user_uuid = result.get_user_uuid(pub_key);
-                assert!(result_user_uuid.is_some());
-                assert_eq!(user_uuid, result_user_uuid.unwrap().as_str());
-            },
-            Err(_) => assert!(false)
-        };
+        assert_eq!(user_client.clone().get_user_uuid(&key).await, Some("test_user_uuid".to_string()));
 
         // clean up
         cleanup_test_files(&uri.to_string()).await;
     }
 
+    #[test]
+    fn test_pub_key_entry_key() {
+        let key = PubKeys::key("../../etc/passwd", "pub_key");
+        let entry_key = UserClient::pub_key_entry_key(&key);
+
+        assert!(entry_key.starts_with("pub_key:"));
+        assert!(!entry_key.contains('/'));
+        // sha256 hex digest
+        assert_eq!(entry_key.len(), "pub_key:".len() + 64);
+        // stable for the same key
+        assert_eq!(entry_key, UserClient::pub_key_entry_key(&key));
+        assert_ne!(entry_key, UserClient::pub_key_entry_key(&PubKeys::key("other", "pub_key")));
+    }
+
     #[tokio::test]
-    async fn test_save_pub_keys() {
-        let uri = storage_uri("save_pub_keys");
+    async fn test_put_and_remove_key() {
+        let uri = storage_uri("put_and_remove_key");
 
-        let file_path = format!("{}/{}", &uri.to_string(), KEYS_STRING);
         let user_client = UserClient::new(uri.clone());
 
-        // confirm file doesn't exist before
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key();
+        let hash = "hash";
+        let user_uuid = "test_user_uuid";
+
+        let key = PubKeys::key(hash, &pub_key.to_string());
+        let file_path = format!("{}/{}", &uri.to_string(), UserClient::pub_key_entry_key(&key));
+        user_client.put_key(&key, user_uuid).await.expect("Failed to put key");
+
+        // each entry is its own file
+        assert!(check_path_exists(&file_path).await);
+        let data = tokio::fs::read(file_path.clone()).await.expect("Failed to read file");
+        let entry: PubKeyEntry = serde_json::from_slice(data.as_slice()).expect("Failed to deserialize");
+        assert_eq!(entry, PubKeyEntry { key: key.clone(), user_uuid: user_uuid.to_string() });
+
+        // put another user with same pub_key but different hash
+        let diff_key = PubKeys::key("diff_hash", &pub_key.to_string());
+        let diff_uuid = "diff_user_uuid";
+        user_client.put_key(&diff_key, diff_uuid).await.expect("Failed to put key");
+
+        assert_eq!(user_client.clone().get_user_uuid(&key).await, Some(user_uuid.to_string()));
+        assert_eq!(user_client.clone().get_user_uuid(&diff_key).await, Some(diff_uuid.to_string()));
+
+        // an entry is only removed for the user it points at
+        user_client.remove_key(&key, diff_uuid).await.expect("Failed to remove key");
+        assert_eq!(user_client.clone().get_user_uuid(&key).await, Some(user_uuid.to_string()));
+
+        // removing one entry leaves the other alone
+        user_client.remove_key(&key, user_uuid).await.expect("Failed to remove key");
         assert!(!check_path_exists(&file_path).await);
+        assert!(user_client.clone().get_user_uuid(&key).await.is_none());
+        assert_eq!(user_client.clone().get_user_uuid(&diff_key).await, Some(diff_uuid.to_string()));
 
-        // create directory
-        match user_client.clone().client {
-            Client::FileStorageClient { storage_client } => {
-                storage_client.create_storage_dir().await.expect("Failed to create storage directory");
-            },
-            _ => assert!(false)
-        }
+        // removing a missing key is fine
+        assert!(user_client.remove_key(&key, user_uuid).await.is_ok());
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_check_key() {
+        let uri = storage_uri("check_key");
+        let user_client = UserClient::new(uri.clone());
+        let pub_key = Sessionless::new().public_key().to_string();
+
+        let (user, _) = user_client.create_user(&pub_key, "hash").await.expect("Failed to create user");
+        let check = user_client.check_key(&pub_key, "hash").await.expect("Failed to check key");
+        assert_eq!(check, KeyCheck { user_uuid: user.uuid.clone(), consistent: true });
+
+        assert!(matches!(user_client.check_key(&pub_key, "other").await, Err(StorageError::NotFound(_))));
+
+        // an entry left pointing at a user that has moved on, or is gone
+        user_client.put_key(&PubKeys::key("old", &pub_key), &user.uuid).await.expect("Failed to put key");
+        assert!(!user_client.check_key(&pub_key, "old").await.expect("Failed to check key").consistent);
+        user_client.put_key(&PubKeys::key("gone", &pub_key), "missing").await.expect("Failed to put key");
+        let check = user_client.check_key(&pub_key, "gone").await.expect("Failed to check key");
+        assert_eq!(check, KeyCheck { user_uuid: "missing".to_string(), consistent: false });
+
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_keys() {
+        let uri = storage_uri("migrate_legacy_keys");
+
+        let keys_file_path = format!("{}/{}", &uri.to_string(), KEYS_STRING);
+        let user_client = UserClient::new(uri.clone());
+
+        // nothing to migrate
+        assert_eq!(user_client.migrate_legacy_keys().await.expect("Failed to migrate"), 0);
 
         let mut pub_keys = PubKeys::default();
-        let pub_keys = pub_keys.add_user_uuid("test_user_uuid", "test_pub_key");
+        pub_keys
+            .add_user_uuid("uuid_1", &PubKeys::key("hash_1", "pub_key"))
+            .add_user_uuid("uuid_2", &PubKeys::key("hash_2", "pub_key"));
+        user_client.client.set(KEYS_STRING, serde_json::to_value(&pub_keys).unwrap()).await.expect("Failed to write legacy keys");
+        assert!(check_path_exists(&keys_file_path).await);
 
-        match user_client.clone().save_pub_keys(pub_keys.clone()).await {
-            Ok(_) => {
-                assert!(check_path_exists(&file_path).await);
-                // read the file and check the contents
-                match tokio::fs::read(file_path.clone()).await {
-                    Ok(data) => {
-                        let result: PubKeys = serde_json::from_slice(data.as_slice()).expect("Failed to deserialize");
-                        assert_eq!(result, *pub_keys);
-                    },
-                    Err(e) => panic!("Failed to read file: {}", e)
-                }
-            },
-            Err(_) => assert!(false)
+        assert_eq!(user_client.migrate_legacy_keys().await.expect("Failed to migrate"), 2);
+
+        // the blob is gone and every entry is addressable on its own
+        assert!(!check_path_exists(&keys_file_path).await);
+        assert_eq!(user_client.clone().get_user_uuid(&PubKeys::key("hash_1", "pub_key")).await, Some("uuid_1".to_string()));
+        assert_eq!(user_client.clone().get_user_uuid(&PubKeys::key("hash_2", "pub_key")).await, Some("uuid_2".to_string()));
+
+        // running again is a no-op
+        assert_eq!(user_client.migrate_legacy_keys().await.expect("Failed to migrate"), 0);
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_create_user_concurrently() {
+        let uri = storage_uri("create_user_concurrently");
+        let user_client = UserClient::new(uri.clone());
+
+        let sessionless = Sessionless::new();
+        let pub_key = sessionless.public_key().to_string();
+
+        // everyone creating the same pub_key + hash gets the same user
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..32 {
+            let user_client = user_client.clone();
+            let pub_key = pub_key.clone();
+            tasks.spawn(async move { user_client.create_user(&pub_key, "same_hash").await.map(|(user, _)| user) });
+        }
+        let uuids: Vec<String> = tasks.join_all().await.into_iter()
+            .map(|result| result.expect("Failed to create user").uuid)
+            .collect();
+        assert!(uuids.iter().all(|uuid| uuid == &uuids[0]));
+
+        // different hashes each get their own user and index entry
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..32 {
+            let user_client = user_client.clone();
+            let pub_key = pub_key.clone();
+            tasks.spawn(async move { user_client.create_user(&pub_key, &format!("hash_{}", i)).await.map(|(user, _)| user) });
         }
+        for result in tasks.join_all().await {
+            assert!(result.is_ok());
+        }
+
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 33);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("same_hash", &pub_key)), Some(&uuids[0]));
+        // and there is exactly one user per index entry
+        assert_eq!(count_users(&uri.to_string()).await, 33);
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_update_hash_concurrently() {
+        let uri = storage_uri("update_hash_concurrently");
+        let user_client = UserClient::new(uri.clone());
+
+        let pub_key = Sessionless::new().public_key().to_string();
+        let (user, _) = user_client.create_user(&pub_key, "initial_hash").await.expect("Failed to create user");
+
+        // every device updates from the same hash; one wins, the others are told so
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..32 {
+            let user_client = user_client.clone();
+            let uuid = user.uuid.clone();
+            tasks.spawn(async move { user_client.update_hash(&uuid, "initial_hash", &format!("hash_{}", i), i, 8).await });
+        }
+        let results = tasks.join_all().await;
+        let winners: Vec<User> = results.iter().filter_map(|result| result.as_ref().ok().cloned()).collect();
+        assert_eq!(winners.len(), 1);
+        assert!(results.iter().filter(|result| result.is_err()).all(|result| matches!(result, Err(StorageError::Conflict(_)))));
+
+        // the index holds exactly the winner's entry
+        let stored = user_client.clone().get_user(&user.uuid).await.expect("Missing user");
+        assert_eq!(stored, winners[0]);
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key(&stored.hash, &pub_key)), Some(&user.uuid));
+        assert_eq!(stored.history.len(), 1);
+
+        // deleting takes the index entry with it
+        user_client.clone().delete_user(&user.uuid).await.expect("Failed to delete user");
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 0);
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_update_hash_key_taken() {
+        let uri = storage_uri("update_hash_key_taken");
+        let user_client = UserClient::new(uri.clone());
+
+        // one key, two users, told apart by their hashes
+        let pub_key = Sessionless::new().public_key().to_string();
+        let (first, _) = user_client.create_user(&pub_key, "first_hash").await.expect("Failed to create user");
+        let (second, _) = user_client.create_user(&pub_key, "second_hash").await.expect("Failed to create user");
+        assert_ne!(first.uuid, second.uuid);
+
+        // the first can't take the second's hash, which would put both under the same key + hash
+        let result = user_client.update_hash(&first.uuid, "first_hash", "second_hash", 0, 8).await;
+        assert!(matches!(result, Err(StorageError::Conflict(_))));
+
+        let stored = user_client.clone().get_user(&first.uuid).await.expect("Missing user");
+        assert_eq!(stored, first);
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 2);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("first_hash", &pub_key)), Some(&first.uuid));
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("second_hash", &pub_key)), Some(&second.uuid));
+
+        // a hash nobody holds is fine
+        let updated = user_client.update_hash(&first.uuid, "first_hash", "third_hash", 0, 8).await.expect("Failed to update hash");
+        assert_eq!(updated.hash, "third_hash");
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 2);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("third_hash", &pub_key)), Some(&first.uuid));
 
         // clean up
         cleanup_test_files(&uri.to_string()).await;
     }
 
     #[tokio::test]
-    async fn test_update_keys() {
-        let uri = storage_uri("update_keys");
+    async fn test_update_user() {
+        let uri = storage_uri("update_user");
+        let user_client = UserClient::new(uri.clone());
+
+        let old_pub_key = Sessionless::new().public_key().to_string();
+        let new_pub_key = Sessionless::new().public_key().to_string();
+        let (user, _) = user_client.create_user(&old_pub_key, "hash").await.expect("Failed to create user");
+
+        // a device key is indexed as well
+        let added = user_client.update_user(&user.uuid, |user| StorageResult::Ok(user.with_key(&new_pub_key))).await.expect("Failed to add key");
+        assert_eq!(added.keys().collect::<Vec<_>>(), vec![old_pub_key.as_str(), new_pub_key.as_str()]);
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 2);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("hash", &new_pub_key)), Some(&user.uuid));
+
+        // dropping the primary leaves the key taking over indexed
+        let rotated = user_client.update_user(&user.uuid, |user| user.without_key(&old_pub_key).ok_or(StorageError::Conflict("last key".to_string()))).await.expect("Failed to revoke key");
+        assert_eq!(rotated, User::new(Some(user.uuid.clone()), new_pub_key.clone(), "hash".to_string()));
+        assert_eq!(user_client.clone().get_user(&user.uuid).await.unwrap(), rotated);
+
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("hash", &new_pub_key)), Some(&user.uuid));
+        assert!(pub_keys.get_user_uuid(&PubKeys::key("hash", &old_pub_key)).is_none());
+
+        // the change's own error comes back untouched
+        let result = user_client.update_user(&user.uuid, |user| user.without_key(&new_pub_key).ok_or(StorageError::Conflict("last key".to_string()))).await;
+        assert!(matches!(result, Err(StorageError::Conflict(key)) if key == "last key"));
+
+        // swapping onto a pub_key + hash another user holds is refused
+        let (other, _) = user_client.create_user(&old_pub_key, "hash").await.expect("Failed to create user");
+        assert_ne!(other.uuid, user.uuid);
+        let result = user_client.update_user(&other.uuid, |user| StorageResult::Ok(user.with_key_replaced(&old_pub_key, &new_pub_key))).await;
+        assert!(matches!(result, Err(StorageError::Conflict(_))));
+        assert_eq!(user_client.clone().get_user(&other.uuid).await.unwrap().pub_key, old_pub_key);
+        assert_eq!(user_client.clone().get_user_uuid(&PubKeys::key("hash", &new_pub_key)).await, Some(user.uuid.clone()));
+
+        // and so is adding it as a device key, without claiming any of the other keys
+        let device_key = Sessionless::new().public_key().to_string();
+        let result = user_client.update_user(&other.uuid, |user| StorageResult::Ok(user.with_key(&device_key).with_key(&new_pub_key))).await;
+        assert!(matches!(result, Err(StorageError::Conflict(_))));
+        assert_eq!(user_client.clone().get_user(&other.uuid).await.unwrap().device_keys, Vec::<String>::new());
+        assert_eq!(user_client.clone().get_user_uuid(&PubKeys::key("hash", &device_key)).await, None);
+
+        // no such user
+        let result = user_client.update_user("missing", |user| StorageResult::Ok(user.clone())).await;
+        assert!(matches!(result, Err(StorageError::NotFound(_))));
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
 
-        let file_path = format!("{}/{}", &uri.to_string(), KEYS_STRING);
+    #[tokio::test]
+    async fn test_device_keys_indexed() {
+        let uri = storage_uri("user_device_keys_indexed");
         let user_client = UserClient::new(uri.clone());
 
-        // confirm file doesn't exist before
-        assert!(!check_path_exists(&file_path).await);
+        let pub_key = Sessionless::new().public_key().to_string();
+        let device_key = Sessionless::new().public_key().to_string();
+        let (user, _) = user_client.create_user(&pub_key, "hash").await.expect("Failed to create user");
+        user_client.update_user(&user.uuid, |user| StorageResult::Ok(user.with_key(&device_key))).await.expect("Failed to add key");
+
+        // creating from the device key finds the same user
+        let (found, created) = user_client.create_user(&device_key, "hash").await.expect("Failed to create user");
+        assert!(!created);
+        assert_eq!(found.uuid, user.uuid);
+        assert!(user_client.check_key(&device_key, "hash").await.unwrap().consistent);
+
+        // the device key's entry moves with the hash
+        user_client.update_hash(&user.uuid, "hash", "new_hash", 0, 10).await.expect("Failed to update hash");
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 2);
+        assert_eq!(pub_keys.get_user_uuid(&PubKeys::key("new_hash", &device_key)), Some(&user.uuid));
+
+        // revoking it takes its entry away
+        user_client.update_user(&user.uuid, |user| user.without_key(&device_key).ok_or(StorageError::Conflict("last key".to_string()))).await.expect("Failed to revoke key");
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 1);
+        assert!(pub_keys.get_user_uuid(&PubKeys::key("new_hash", &device_key)).is_none());
+
+        // and deleting the user takes all of them
+        user_client.update_user(&user.uuid, |user| StorageResult::Ok(user.with_key(&device_key))).await.expect("Failed to add key");
+        user_client.clone().delete_user(&user.uuid).await.expect("Failed to delete user");
+        let pub_keys = read_keys(&uri.to_string()).await.expect("Failed to read keys");
+        assert_eq!(pub_keys.num_keys(), 0);
 
-        // create directory
-        match user_client.clone().client {
-            Client::FileStorageClient { storage_client } => {
-                storage_client.create_storage_dir().await.expect("Failed to create storage directory");
-            },
-            _ => assert!(false)
-        }
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
 
-        let sessionless = Sessionless::new();
-        let pub_key = sessionless.public_key();
-        let hash = "hash";
-        let user_uuid = "test_user_uuid";
+    #[tokio::test]
+    async fn test_state() {
+        let uri = storage_uri("user_state");
+        let user_client = UserClient::new(uri.clone());
 
-        let key = PubKeys::key(hash, &pub_key.to_string());
-        match user_client.clone().update_keys(&key, user_uuid).await {
-            Ok(_) => {
-                assert!(check_path_exists(&file_path).await);
-                // read the file and check the contents
-                match tokio::fs::read(file_path.clone()).await {
-                    Ok(data) => {
-                        let result: PubKeys = serde_json::from_slice(data.as_slice()).expect("Failed to deserialize");
-                        let result_user_uuid = result.get_user_uuid(&key.to_string());
-                        assert!(result_user_uuid.is_some());
-                        assert_eq!(user_uuid, result_user_uuid.unwrap().as_str());
-                    },
-                    Err(e) => panic!("Failed to read file: {}", e)
-                }
-            },
-            Err(_) => assert!(false)
-        }
+        let (user, _) = user_client.create_user("pub_key", "hash").await.expect("Failed to create user");
+        assert!(matches!(user_client.get_state(&user.uuid).await, Err(StorageError::NotFound(_))));
 
-        // put another user with same pub_key but different hash
-        let diff_hash = "diff_hash";
-        let diff_key = PubKeys::key(diff_hash, &pub_key.to_string());
-        let diff_uuid = "diff_user_uuid";
-        match user_client.clone().update_keys(&diff_key, diff_uuid).await {
-            Ok(_) => {
-                assert!(check_path_exists(&file_path).await);
-                // read the file and check the contents
-                match tokio::fs::read(file_path.clone()).await {
-                    Ok(data) => {
-                        let result: PubKeys = serde_json::from_slice(data.as_slice()).expect("Failed to deserialize");
-                        let result_user_uuid = result.get_user_uuid(&diff_key.to_string());
-                        assert!(result_user_uuid.is_some());
-                        assert_eq!(diff_uuid, result_user_uuid.unwrap().as_str());
-                    },
-                    Err(e) => panic!("Failed to read file: {}", e)
-                }
-            },
-            Err(_) => assert!(false)
-        }
+        let first = user_client.put_state(&user.uuid, "hash", "first").await.expect("Failed to put state");
+        assert_eq!(first, UserState { hash: "hash".to_string(), state: "first".to_string(), version: 1 });
+        let second = user_client.put_state(&user.uuid, "hash", "second").await.expect("Failed to put state");
+        assert_eq!(second.version, 2);
+        assert_eq!(user_client.get_state(&user.uuid).await.unwrap(), second);
+
+        // only against the current hash
+        assert!(matches!(user_client.put_state(&user.uuid, "old", "third").await, Err(StorageError::Conflict(_))));
+        assert!(matches!(user_client.put_state("missing", "hash", "third").await, Err(StorageError::NotFound(_))));
 
-        // TODO add other test cases
+        // the state goes with the user
+        user_client.clone().delete_user(&user.uuid).await.expect("Failed to delete user");
+        assert!(matches!(user_client.get_state(&user.uuid).await, Err(StorageError::NotFound(_))));
 
         // clean up
         cleanup_test_files(&uri.to_string()).await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let uri = storage_uri("user_lockout");
+        let user_client = UserClient::new(uri.clone());
+
+        let (user, _) = user_client.create_user("pub_key", "hash").await.expect("Failed to create user");
+        assert!(matches!(user_client.get_lockout(&user.uuid).await, Err(StorageError::NotFound(_))));
+
+        let first = user_client.record_failure(&user.uuid, 1000, 3, 500).await.expect("Failed to record failure");
+        assert_eq!(first, Lockout { failures: 1, locked_until: 0 });
+        user_client.record_failure(&user.uuid, 1000, 3, 500).await.expect("Failed to record failure");
+        let third = user_client.record_failure(&user.uuid, 1000, 3, 500).await.expect("Failed to record failure");
+        assert_eq!(third, Lockout { failures: 0, locked_until: 1500 });
+        assert!(third.is_locked(1499));
+        assert!(!third.is_locked(1500));
+        assert_eq!(user_client.get_lockout(&user.uuid).await.unwrap(), third);
+
+        user_client.reset_lockout(&user.uuid).await.expect("Failed to reset lockout");
+        assert!(matches!(user_client.get_lockout(&user.uuid).await, Err(StorageError::NotFound(_))));
+        // nothing to reset
+        user_client.reset_lockout(&user.uuid).await.expect("Failed to reset lockout");
+
+        // the count goes with the user
+        user_client.record_failure(&user.uuid, 1000, 3, 500).await.expect("Failed to record failure");
+        user_client.clone().delete_user(&user.uuid).await.expect("Failed to delete user");
+        assert!(matches!(user_client.get_lockout(&user.uuid).await, Err(StorageError::NotFound(_))));
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_check_storage() {
+        let uri = storage_uri("user_check_storage");
+        let user_client = UserClient::new(uri.clone());
+        let health_prefix = format!("{}:", HEALTH_STRING);
+
+        user_client.check_storage().await.expect("Failed to check storage");
+        assert!(user_client.client.keys(&health_prefix).await.unwrap().is_empty());
+
+        // giving up before the round trip is done still cleans up after it
+        let check = tokio::time::timeout(std::time::Duration::ZERO, user_client.check_storage()).await;
+        assert!(check.is_err());
+        let mut cleaned_up = false;
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            if user_client.client.keys(&health_prefix).await.unwrap().is_empty() {
+                cleaned_up = true;
+                break;
+            }
+        }
+        assert!(cleaned_up);
+
+        // clean up
+        cleanup_test_files(&uri.to_string()).await;
+    }
+}
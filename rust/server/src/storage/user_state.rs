@@ -0,0 +1,13 @@
+use serde::{Serialize, Deserialize};
+
+
+// A state payload the client encrypts before sending, stored under its own key next to the user.
+// The server never looks inside state
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserState {
+    // the user's hash when the state was saved
+    pub hash: String,
+    pub state: String,
+    // 1 for the first state saved, one more for every save after it
+    pub version: u64,
+}
@@ -0,0 +1,11 @@
+use serde::{Serialize, Deserialize};
+
+
+// How much is in storage, for metrics and operators
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStats {
+    pub users: u64,
+    // entries in the pub_key index, one per key (and hash) of every user
+    pub pub_keys: u64,
+}
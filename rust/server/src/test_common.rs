@@ -1,15 +1,19 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use axum::{http::Uri, routing::{delete, get, post, put}, Router};
+use axum::{http::Uri, Router};
 use axum_test::TestServer;
 use tokio::io::AsyncWriteExt;
 
-use crate::{config::AppState, handlers, storage::{PubKeys, User, UserClient}};
+use crate::{config::{AppState, Clock, SystemClock}, middleware::{RateLimiter, RateLimits}, service::{LockoutPolicy, UserService}, storage::{PubKeyEntry, PubKeys, ReplayCache, User, UserClient}};
 
 pub static USER_CREATE_PATH: &str = "/user/create";
 pub static USER_UPDATE_HASH_PATH: &str = "/user/update-hash";
 pub static USER_DELETE_PATH: &str = "/user/delete";
-pub static USER_GET_PATH: &str = "/user/{uuid}";
+pub static USER_ROTATE_KEY_PATH: &str = "/user/rotate-key";
+pub static USER_ADD_KEY_PATH: &str = "/user/add-key";
+pub static USER_REVOKE_KEY_PATH: &str = "/user/revoke-key";
+pub static USER_STATE_PATH: &str = "/user/state";
+pub static USER_ROLLBACK_PATH: &str = "/user/rollback";
 
 pub fn storage_uri(test_name: &str) -> Uri {
     let current_directory = std::env::current_dir().expect("Failed to get current directory"); 
@@ -17,25 +21,100 @@ pub fn storage_uri(test_name: &str) -> Uri {
     Uri::builder().path_and_query(storage_uri.clone()).build().unwrap()
 }
 
-fn test_router(storage_uri: Uri) -> Router {
-    let test_user_client = UserClient::new(storage_uri.clone());
+// Postgres backed tests are ignored unless asked for, and then need POSTGRES_TEST_URI to point at a database
+pub fn postgres_test_uri() -> Uri {
+    let uri = std::env::var("POSTGRES_TEST_URI").expect("POSTGRES_TEST_URI must point at a database");
+    Uri::try_from(uri).expect("POSTGRES_TEST_URI must be a valid URI")
+}
 
-    let test_app_state = Arc::new(AppState {
-        user_client: test_user_client,
-    });
+pub static ALLOWED_TIME_DIFFERENCE: Duration = Duration::from_secs(600);
+pub static MAX_STATE_SIZE: usize = 1024;
+pub static HASH_HISTORY_SIZE: usize = 10;
 
-    Router::new()
-        .route(&USER_CREATE_PATH, post(handlers::create_user_handler))
-        .route(&USER_GET_PATH, get(handlers::get_user_handler))
-        .route(&USER_UPDATE_HASH_PATH, put(handlers::update_hash_handler))
-        .route(&USER_DELETE_PATH, delete(handlers::delete_user_handler))
-        .with_state(test_app_state)
+pub fn test_user_service(storage_uri: Uri, clock: Arc<dyn Clock>) -> UserService {
+    UserService::new(UserClient::new(storage_uri), ReplayCache::new(1000), clock, ALLOWED_TIME_DIFFERENCE, MAX_STATE_SIZE, HASH_HISTORY_SIZE, LockoutPolicy::default())
 }
 
-pub fn setup_test_server(storage_uri: Uri) -> TestServer {
-    let router = test_router(storage_uri);
+// A test server over the server's own router, configured like ServerConfig would;
+// anything not set is off, with the system clock and default rate limits
+pub struct TestServerBuilder {
+    storage_uri: Uri,
+    clock: Arc<dyn Clock>,
+    fount_pub_key: Option<String>,
+    operator_pub_keys: Vec<String>,
+    rate_limits: RateLimits,
+    http_transport: bool,
+}
+
+impl TestServerBuilder {
+    pub fn new(storage_uri: Uri) -> Self {
+        TestServerBuilder {
+            storage_uri,
+            clock: Arc::new(SystemClock),
+            fount_pub_key: None,
+            operator_pub_keys: vec![],
+            rate_limits: RateLimits::default(),
+            http_transport: false,
+        }
+    }
+
+    // Time only moves when clock is told to
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Accepts spells signed by the resolver with fount_pub_key
+    pub fn fount_pub_key(mut self, fount_pub_key: &str) -> Self {
+        self.fount_pub_key = Some(fount_pub_key.to_string());
+        self
+    }
+
+    // Accepts admin requests signed with operator_pub_key
+    pub fn operator_pub_key(mut self, operator_pub_key: &str) -> Self {
+        self.operator_pub_keys.push(operator_pub_key.to_string());
+        self
+    }
+
+    pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    // Serves over a real socket, so requests are handled in parallel on the runtime's workers
+    pub fn http_transport(mut self) -> Self {
+        self.http_transport = true;
+        self
+    }
+
+    pub fn app_state(&self) -> AppState {
+        AppState {
+            user_service: test_user_service(self.storage_uri.clone(), self.clock.clone()),
+            fount_pub_key: self.fount_pub_key.clone(),
+            operator_pub_keys: self.operator_pub_keys.clone(),
+            rate_limiter: Arc::new(RateLimiter::new(self.rate_limits.clone(), self.clock.clone())),
+        }
+    }
+
+    pub fn build(self) -> TestServer {
+        self.serve(crate::setup_router(self.app_state()))
+    }
+
+    // Only /metrics, as served on METRICS_ADDRESS
+    pub fn build_metrics(self) -> TestServer {
+        self.serve(crate::setup_metrics_router(self.app_state()))
+    }
 
-    TestServer::new(router).unwrap()
+    fn serve(&self, router: Router) -> TestServer {
+        if self.http_transport {
+            return TestServer::builder().http_transport().build(router).unwrap();
+        }
+        TestServer::new(router).unwrap()
+    }
+}
+
+pub fn setup_test_server(storage_uri: Uri) -> TestServer {
+    TestServerBuilder::new(storage_uri).build()
 }
 
 pub async fn write_user(dir_path: &str, uuid: &str, pub_key: &str, hash: &str) -> bool {
@@ -62,29 +141,55 @@ pub async fn read_user(dir_path: &str, uuid: &str) -> anyhow::Result<User> {
     Ok(user)
 }
 
-pub async fn write_keys(dir_path: &str, pub_keys: &PubKeys) -> bool {
-    let data = serde_json::to_value(pub_keys).unwrap();
+pub fn key_entry_path(dir_path: &str, key: &str) -> String {
+    format!("{}/{}", &dir_path, UserClient::pub_key_entry_key(key))
+}
 
-    let file_path = format!("{}/keys", &dir_path);
-    let mut file = match tokio::fs::File::create_new(file_path).await {
-        Ok(f) => f,
-        Err(e) => {
-            panic!("Failed to create file {}", e);
+// Writes every entry of pub_keys to its own index file
+pub async fn write_keys(dir_path: &str, pub_keys: &PubKeys) -> bool {
+    for entry in pub_keys.entries() {
+        let data = serde_json::to_value(&entry).unwrap();
+
+        let mut file = match tokio::fs::File::create_new(key_entry_path(dir_path, &entry.key)).await {
+            Ok(f) => f,
+            Err(e) => {
+                panic!("Failed to create file {}", e);
+            }
+        };
+
+        if file.write_all(data.to_string().as_bytes()).await.is_err() {
+            return false;
         }
-    };
-
-    file.write_all(data.to_string().as_bytes()).await.is_ok()
+    }
+    true
 }
 
+// Collects every index file in dir_path
 pub async fn read_keys(dir_path: &str) -> anyhow::Result<PubKeys> {
-
-    let file_path = format!("{}/keys", &dir_path);
-    let data = tokio::fs::read_to_string(file_path).await?;
-
-    let pub_keys: PubKeys = serde_json::from_str(&data)?;
+    let mut pub_keys = PubKeys::default();
+
+    let mut dir = tokio::fs::read_dir(dir_path).await?;
+    while let Some(file) = dir.next_entry().await? {
+        if file.file_name().to_string_lossy().starts_with("pub_key:") {
+            let data = tokio::fs::read_to_string(file.path()).await?;
+            let entry: PubKeyEntry = serde_json::from_str(&data)?;
+            pub_keys.add_user_uuid(&entry.user_uuid, &entry.key);
+        }
+    }
     Ok(pub_keys)
 }
 
+pub async fn count_users(dir_path: &str) -> usize {
+    let mut count = 0;
+    let mut dir = tokio::fs::read_dir(dir_path).await.expect("Failed to read dir");
+    while let Some(file) = dir.next_entry().await.expect("Failed to read dir") {
+        if file.file_name().to_string_lossy().starts_with("user:") {
+            count += 1;
+        }
+    }
+    count
+}
+
 pub async fn check_path_exists(path: &str) -> bool {
     tokio::fs::metadata(path).await.is_ok()
 }